@@ -0,0 +1,18 @@
+-- Your SQL goes here
+
+CREATE TABLE script_templates (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    content TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    UNIQUE (name, version)
+    );
+
+CREATE TABLE project_templates (
+    project_id TEXT NOT NULL,
+    format TEXT NOT NULL,
+    template_name TEXT NOT NULL,
+    template_version INTEGER,
+    PRIMARY KEY (project_id, format)
+    )
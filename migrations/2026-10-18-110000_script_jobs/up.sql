@@ -0,0 +1,14 @@
+-- Your SQL goes here
+
+CREATE TABLE script_jobs (
+    job_id TEXT PRIMARY KEY NOT NULL,
+    user_id TEXT NOT NULL,
+    project_id TEXT NOT NULL,
+    bridgehead_ids TEXT NOT NULL,
+    format TEXT NOT NULL,
+    status TEXT NOT NULL,
+    tables TEXT NOT NULL,
+    error TEXT,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+    )
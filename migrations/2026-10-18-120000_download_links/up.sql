@@ -0,0 +1,9 @@
+-- Your SQL goes here
+
+CREATE TABLE download_links (
+    id TEXT PRIMARY KEY NOT NULL,
+    job_id TEXT NOT NULL REFERENCES script_jobs(job_id) ON DELETE CASCADE,
+    expires_at BIGINT NOT NULL,
+    used_at TEXT,
+    created_at TEXT NOT NULL
+    )
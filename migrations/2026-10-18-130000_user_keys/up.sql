@@ -0,0 +1,7 @@
+-- Your SQL goes here
+
+CREATE TABLE user_keys (
+    user_id TEXT PRIMARY KEY NOT NULL,
+    public_key TEXT NOT NULL,
+    created_at TEXT NOT NULL
+    )
@@ -0,0 +1,24 @@
+-- Your SQL goes here
+
+CREATE TABLE outbox (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    operation TEXT NOT NULL,
+    bk TEXT NOT NULL,
+    user_id TEXT,
+    project_id TEXT,
+    token_name TEXT,
+    status TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    next_attempt_at BIGINT NOT NULL,
+    last_error TEXT,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+    );
+
+CREATE INDEX outbox_status_next_attempt ON outbox (status, next_attempt_at);
+
+-- Revocations that were still retried in memory are picked up by the outbox worker
+INSERT INTO outbox (operation, bk, user_id, project_id, token_name, status, attempts, next_attempt_at, created_at, updated_at)
+SELECT 'DELETE_TOKEN', bk, user_id, project_id, token_name, 'PENDING', 0, 0, token_created_at, token_created_at
+FROM tokens
+WHERE token_status = 'REVOKING';
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+
+CREATE TABLE reconciliation_runs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    started_at TEXT NOT NULL,
+    finished_at TEXT NOT NULL,
+    checked INTEGER NOT NULL,
+    in_sync INTEGER NOT NULL,
+    drifts TEXT NOT NULL
+    )
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+
+-- This migration is irreversible: refreshed tokens were stored as UPDATED and statuses unknown
+-- to the enums as reported by the sites, neither can be told apart from the normalized values
+-- anymore. Reverting keeps the normalized statuses, which older versions read as well.
+SELECT 1
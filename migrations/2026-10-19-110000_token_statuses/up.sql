@@ -0,0 +1,9 @@
+-- Your SQL goes here
+
+UPDATE tokens SET token_status = 'CREATED' WHERE token_status = 'UPDATED';
+
+UPDATE tokens SET token_status = 'ERROR'
+    WHERE token_status NOT IN ('CREATED', 'EXPIRED', 'NOT_FOUND', 'ERROR', 'REVOKING', 'REVOKED');
+
+UPDATE tokens SET project_status = 'ERROR'
+    WHERE project_status NOT IN ('CREATED', 'WITH_DATA', 'NOT_FOUND', 'ERROR')
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+
+ALTER TABLE tokens ADD COLUMN scopes TEXT NOT NULL DEFAULT '[]';
+ALTER TABLE tokens ADD COLUMN expires_at TEXT;
+
+ALTER TABLE outbox ADD COLUMN permissions TEXT;
@@ -0,0 +1,12 @@
+-- Your SQL goes here
+
+CREATE TABLE site_notifications (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    task_id TEXT NOT NULL,
+    bk TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    token_name TEXT,
+    project_id TEXT,
+    tokens_updated INTEGER NOT NULL,
+    received_at TEXT NOT NULL
+    )
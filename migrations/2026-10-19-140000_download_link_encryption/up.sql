@@ -0,0 +1,3 @@
+-- Your SQL goes here
+
+ALTER TABLE download_links ADD COLUMN encrypted BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+
+ALTER TABLE script_jobs ADD COLUMN task_settings TEXT;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+
+DELETE FROM site_notifications
+    WHERE id NOT IN (SELECT MIN(id) FROM site_notifications GROUP BY task_id);
+
+CREATE UNIQUE INDEX site_notifications_task_id ON site_notifications (task_id);
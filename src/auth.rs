@@ -0,0 +1,38 @@
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, StatusCode},
+};
+use subtle::ConstantTimeEq;
+use tracing::warn;
+
+use crate::config::CONFIG;
+
+/// Extractor guarding the admin endpoints with the configured `ADMIN_API_KEY`.
+pub struct Admin;
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Admin
+where
+    S: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(api_key) = &CONFIG.admin_api_key else {
+            warn!("Admin endpoint called but no admin api key is configured");
+            return Err(StatusCode::FORBIDDEN);
+        };
+
+        let bearer = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        match bearer {
+            Some(key) if bool::from(key.as_bytes().ct_eq(api_key.as_bytes())) => Ok(Self),
+            _ => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+}
@@ -0,0 +1,431 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+use once_cell::sync::Lazy;
+use tokio::sync::Notify;
+use tracing::warn;
+
+use crate::config::CONFIG;
+use crate::enums::{OpalRequestType, SiteFeature};
+use crate::models::BridgeheadCapabilities;
+use crate::opal::{SiteCapabilities, SiteValue, LEGACY_REQUEST_TYPES, PROTOCOL_VERSION};
+
+/// Time a bridgehead that did not answer the capability query is not asked again.
+const UNAVAILABLE_TTL: Duration = Duration::from_secs(300);
+
+struct CachedCapabilities {
+    /// `None` if the bridgehead did not answer
+    capabilities: Option<BridgeheadCapabilities>,
+    expires: Instant,
+}
+
+impl CachedCapabilities {
+    fn is_fresh(&self) -> bool {
+        Instant::now() < self.expires
+    }
+}
+
+fn cache_ttl() -> Duration {
+    Duration::from_secs(CONFIG.capabilities_cache_ttl)
+}
+
+/// Bridgeheads that did not answer are asked again with the next request after five minutes at
+/// the latest.
+fn unavailable_ttl(cache_ttl: Duration) -> Duration {
+    cache_ttl.min(UNAVAILABLE_TTL)
+}
+
+static CAPABILITIES_CACHE: Lazy<Mutex<HashMap<String, CachedCapabilities>>> =
+    Lazy::new(Default::default);
+
+/// Bridgeheads whose capabilities are being fetched.
+static FETCHING: Lazy<Mutex<HashSet<String>>> = Lazy::new(Default::default);
+static FETCHED: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Result of the last capability query of the bridgehead within `CAPABILITIES_CACHE_TTL`,
+/// `Some(None)` if the bridgehead did not answer it recently.
+pub fn cached(bk: &str) -> Option<Option<BridgeheadCapabilities>> {
+    let mut cache = CAPABILITIES_CACHE.lock().unwrap();
+    match cache.get(bk) {
+        Some(cached) if cached.is_fresh() => Some(cached.capabilities.clone()),
+        Some(_) => {
+            cache.remove(bk);
+            None
+        }
+        None => None,
+    }
+}
+
+/// Capabilities of the bridgehead if they were fetched within `CAPABILITIES_CACHE_TTL`.
+pub fn cached_capabilities(bk: &str) -> Option<BridgeheadCapabilities> {
+    cached(bk).flatten()
+}
+
+/// Whether the bridgehead has to be asked for its capabilities, i.e. it did not answer recently
+/// and is not being asked already.
+pub fn needs_fetch(bk: &str) -> bool {
+    cached(bk).is_none() && !FETCHING.lock().unwrap().contains(bk)
+}
+
+/// Bridgeheads a capability query is sent to, released when dropped.
+pub struct FetchClaim(Vec<String>);
+
+impl FetchClaim {
+    /// Claims the bridgeheads not being asked already, the others are returned to wait for.
+    pub fn claim(bridgeheads: Vec<String>) -> (Self, Vec<String>) {
+        let mut fetching = FETCHING.lock().unwrap();
+        let (claimed, in_flight) = bridgeheads
+            .into_iter()
+            .partition(|bk| fetching.insert(bk.clone()));
+        (FetchClaim(claimed), in_flight)
+    }
+
+    pub fn bridgeheads(&self) -> &[String] {
+        &self.0
+    }
+}
+
+impl Drop for FetchClaim {
+    fn drop(&mut self) {
+        let mut fetching = FETCHING.lock().unwrap();
+        for bk in &self.0 {
+            fetching.remove(bk);
+        }
+        FETCHED.notify_waiters();
+    }
+}
+
+/// Waits until the capability queries other requests sent to the bridgeheads finished.
+pub async fn wait_for_fetches(bridgeheads: &[String]) {
+    loop {
+        let fetched = FETCHED.notified();
+        if !bridgeheads
+            .iter()
+            .any(|bk| FETCHING.lock().unwrap().contains(bk))
+        {
+            return;
+        }
+        fetched.await;
+    }
+}
+
+/// Caches the capabilities the agent of the bridgehead reported.
+pub fn store_capabilities(bk: &str, reported: SiteCapabilities) -> BridgeheadCapabilities {
+    if reported.protocol_version < PROTOCOL_VERSION {
+        warn!(
+            "BK {bk} speaks protocol version {}, this token manager speaks {PROTOCOL_VERSION}",
+            reported.protocol_version
+        );
+    }
+    store(reported_capabilities(bk, reported))
+}
+
+fn reported_capabilities(bk: &str, reported: SiteCapabilities) -> BridgeheadCapabilities {
+    let mut request_types = Vec::new();
+    let mut unknown_request_types = Vec::new();
+    for request_type in reported.request_types {
+        match request_type {
+            SiteValue::Known(request_type) => request_types.push(request_type),
+            SiteValue::Unknown(request_type) => unknown_request_types.push(request_type),
+        }
+    }
+    let mut features = Vec::new();
+    let mut unknown_features = Vec::new();
+    for feature in reported.features {
+        match feature {
+            SiteValue::Known(feature) => features.push(feature),
+            SiteValue::Unknown(feature) => unknown_features.push(feature),
+        }
+    }
+    BridgeheadCapabilities {
+        bk: bk.to_string(),
+        protocol_version: reported.protocol_version,
+        request_types,
+        unknown_request_types,
+        features,
+        unknown_features,
+        fetched_at: Local::now().format("%d-%m-%Y %H:%M:%S").to_string(),
+    }
+}
+
+/// Caches the bridgehead as running an agent that predates capability queries.
+pub fn store_legacy(bk: &str) -> BridgeheadCapabilities {
+    warn!("BK {bk} does not answer capability queries, its agent predates protocol version 2");
+    store(legacy_capabilities(bk))
+}
+
+fn legacy_capabilities(bk: &str) -> BridgeheadCapabilities {
+    BridgeheadCapabilities {
+        bk: bk.to_string(),
+        protocol_version: 1,
+        request_types: LEGACY_REQUEST_TYPES.to_vec(),
+        unknown_request_types: Vec::new(),
+        features: Vec::new(),
+        unknown_features: Vec::new(),
+        fetched_at: Local::now().format("%d-%m-%Y %H:%M:%S").to_string(),
+    }
+}
+
+/// Caches that the bridgehead did not answer the capability query, it is asked again after
+/// `UNAVAILABLE_TTL`.
+pub fn store_unavailable(bk: &str) {
+    insert(bk, None, unavailable_ttl(cache_ttl()));
+}
+
+fn store(capabilities: BridgeheadCapabilities) -> BridgeheadCapabilities {
+    insert(&capabilities.bk, Some(capabilities.clone()), cache_ttl());
+    capabilities
+}
+
+fn insert(bk: &str, capabilities: Option<BridgeheadCapabilities>, ttl: Duration) {
+    CAPABILITIES_CACHE.lock().unwrap().insert(
+        bk.to_string(),
+        CachedCapabilities {
+            capabilities,
+            expires: Instant::now() + ttl,
+        },
+    );
+}
+
+/// Splits the bridgeheads into those a task of the request type is sent to and the capabilities
+/// of those known not to support it. Bridgeheads whose capabilities are not cached are assumed
+/// to support it.
+pub fn partition_supported(
+    bridgeheads: &[String],
+    request_type: OpalRequestType,
+) -> (Vec<String>, Vec<BridgeheadCapabilities>) {
+    let mut supported = Vec::new();
+    let mut unsupported = Vec::new();
+    for bk in bridgeheads {
+        match cached_capabilities(bk) {
+            Some(capabilities) if !capabilities.supports(request_type) => {
+                unsupported.push(capabilities)
+            }
+            _ => supported.push(bk.clone()),
+        }
+    }
+    (supported, unsupported)
+}
+
+/// Features of `required` each bridgehead lacks, bridgeheads lacking none are left out.
+pub fn missing_features(
+    capabilities: &[BridgeheadCapabilities],
+    required: &[SiteFeature],
+) -> Vec<(String, Vec<SiteFeature>)> {
+    capabilities
+        .iter()
+        .filter_map(|capabilities| {
+            let missing: Vec<_> = required
+                .iter()
+                .copied()
+                .filter(|feature| !capabilities.has_feature(*feature))
+                .collect();
+            (!missing.is_empty()).then(|| (capabilities.bk.clone(), missing))
+        })
+        .collect()
+}
+
+/// Hint why the response of the bridgehead could not be read, based on its cached capabilities.
+pub fn incompatibility_hint(bk: &str, request_type: OpalRequestType) -> String {
+    match cached_capabilities(bk) {
+        Some(capabilities) if !capabilities.supports(request_type) => format!(
+            "the agent does not support {request_type} requests (protocol version {})",
+            capabilities.protocol_version
+        ),
+        Some(capabilities) if capabilities.protocol_version != PROTOCOL_VERSION => format!(
+            "the agent speaks protocol version {}, this token manager {PROTOCOL_VERSION}",
+            capabilities.protocol_version
+        ),
+        Some(_) => "the agent speaks the same protocol version".to_string(),
+        None => "the capabilities of the agent are unknown".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn capabilities(bk: &str, protocol_version: u32) -> BridgeheadCapabilities {
+        BridgeheadCapabilities {
+            protocol_version,
+            request_types: vec![
+                OpalRequestType::CREATE,
+                OpalRequestType::CAPABILITIES,
+                OpalRequestType::TOKENS,
+            ],
+            features: vec![SiteFeature::TokenScopes],
+            ..legacy_capabilities(bk)
+        }
+    }
+
+    fn bridgeheads(bks: &[&str]) -> Vec<String> {
+        bks.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn capabilities_are_cached_until_their_ttl() {
+        insert("ttl-fresh", Some(capabilities("ttl-fresh", 2)), HOUR);
+        insert(
+            "ttl-expired",
+            Some(capabilities("ttl-expired", 2)),
+            Duration::ZERO,
+        );
+
+        assert_eq!(
+            cached_capabilities("ttl-fresh").map(|c| c.protocol_version),
+            Some(2)
+        );
+        assert!(cached("ttl-expired").is_none());
+        assert!(!CAPABILITIES_CACHE
+            .lock()
+            .unwrap()
+            .contains_key("ttl-expired"));
+        assert!(needs_fetch("ttl-expired"));
+    }
+
+    #[test]
+    fn unavailable_bridgeheads_are_not_asked_again_for_a_while() {
+        assert_eq!(unavailable_ttl(HOUR), UNAVAILABLE_TTL);
+        assert_eq!(
+            unavailable_ttl(Duration::from_secs(60)),
+            Duration::from_secs(60)
+        );
+
+        insert("unavailable", None, unavailable_ttl(HOUR));
+        assert!(matches!(cached("unavailable"), Some(None)));
+        assert!(cached_capabilities("unavailable").is_none());
+        assert!(!needs_fetch("unavailable"));
+    }
+
+    #[tokio::test]
+    async fn bridgeheads_are_only_asked_once_at_a_time() {
+        let (first, in_flight) = FetchClaim::claim(bridgeheads(&["claim-a", "claim-b"]));
+        assert_eq!(first.bridgeheads(), ["claim-a", "claim-b"]);
+        assert!(in_flight.is_empty());
+        assert!(!needs_fetch("claim-b"));
+
+        let (second, in_flight) = FetchClaim::claim(bridgeheads(&["claim-b", "claim-c"]));
+        assert_eq!(second.bridgeheads(), ["claim-c"]);
+        assert_eq!(in_flight, ["claim-b"]);
+
+        let waiting = wait_for_fetches(&in_flight);
+        tokio::pin!(waiting);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), &mut waiting)
+                .await
+                .is_err()
+        );
+        drop(first);
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .expect("Released claims wake the waiting requests");
+        assert!(needs_fetch("claim-a"));
+        drop(second);
+    }
+
+    #[test]
+    fn agents_without_capabilities_fall_back_to_protocol_version_1() {
+        let legacy = legacy_capabilities("legacy");
+
+        assert_eq!(legacy.protocol_version, 1);
+        assert!(legacy.supports(OpalRequestType::CREATE));
+        assert!(legacy.supports(OpalRequestType::SCRIPT));
+        assert!(!legacy.supports(OpalRequestType::CAPABILITIES));
+        assert!(!legacy.supports(OpalRequestType::TOKENS));
+        assert!(legacy.features.is_empty());
+    }
+
+    #[test]
+    fn unknown_reported_values_are_kept_apart() {
+        let reported = reported_capabilities(
+            "reported",
+            SiteCapabilities {
+                protocol_version: 3,
+                request_types: vec![
+                    SiteValue::Known(OpalRequestType::CREATE),
+                    SiteValue::Unknown("RENAME".to_string()),
+                ],
+                features: vec![
+                    SiteValue::Known(SiteFeature::TokenExpiry),
+                    SiteValue::Unknown("TOKEN_LABELS".to_string()),
+                ],
+            },
+        );
+
+        assert_eq!(reported.protocol_version, 3);
+        assert_eq!(reported.request_types, [OpalRequestType::CREATE]);
+        assert_eq!(reported.unknown_request_types, ["RENAME"]);
+        assert_eq!(reported.features, [SiteFeature::TokenExpiry]);
+        assert_eq!(reported.unknown_features, ["TOKEN_LABELS"]);
+    }
+
+    #[test]
+    fn tasks_skip_bridgeheads_known_not_to_support_them() {
+        insert(
+            "skip-legacy",
+            Some(legacy_capabilities("skip-legacy")),
+            HOUR,
+        );
+        insert("skip-current", Some(capabilities("skip-current", 2)), HOUR);
+        insert("skip-unavailable", None, HOUR);
+        let bks = bridgeheads(&[
+            "skip-legacy",
+            "skip-current",
+            "skip-unavailable",
+            "skip-uncached",
+        ]);
+
+        let (supported, unsupported) = partition_supported(&bks, OpalRequestType::TOKENS);
+        assert_eq!(
+            supported,
+            ["skip-current", "skip-unavailable", "skip-uncached"]
+        );
+        assert_eq!(unsupported.len(), 1);
+        assert_eq!(unsupported[0].bk, "skip-legacy");
+
+        let (supported, unsupported) = partition_supported(&bks, OpalRequestType::CREATE);
+        assert_eq!(supported.len(), 4);
+        assert!(unsupported.is_empty());
+
+        let (supported, _) =
+            partition_supported(&bridgeheads(&["skip-legacy"]), OpalRequestType::TOKENS);
+        assert!(supported.is_empty());
+    }
+
+    #[test]
+    fn missing_features_are_listed_per_bridgehead() {
+        let bridgeheads = [
+            legacy_capabilities("features-legacy"),
+            capabilities("features-current", 2),
+        ];
+
+        assert_eq!(
+            missing_features(&bridgeheads, &[SiteFeature::TokenScopes]),
+            [(
+                "features-legacy".to_string(),
+                vec![SiteFeature::TokenScopes]
+            )]
+        );
+        assert_eq!(
+            missing_features(
+                &bridgeheads,
+                &[SiteFeature::TokenScopes, SiteFeature::TokenExpiry]
+            ),
+            [
+                (
+                    "features-legacy".to_string(),
+                    vec![SiteFeature::TokenScopes, SiteFeature::TokenExpiry]
+                ),
+                (
+                    "features-current".to_string(),
+                    vec![SiteFeature::TokenExpiry]
+                ),
+            ]
+        );
+        assert!(missing_features(&bridgeheads, &[]).is_empty());
+    }
+}
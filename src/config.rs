@@ -4,6 +4,9 @@ use once_cell::sync::Lazy;
 use reqwest::Url;
 use std::{convert::Infallible, net::SocketAddr};
 
+use crate::enums::DriftKind;
+use crate::tasks::{parse_task_settings, TaskSettingsConfig};
+
 pub(crate) static CONFIG: Lazy<Config> = Lazy::new(Config::parse);
 
 #[derive(Debug, Parser)]
@@ -35,8 +38,82 @@ pub struct Config {
     #[clap(long, env)]
     pub auth_script_template_path: String,
 
+    /// Template of the Python script, a built-in template for the `datashield` client is used if unset
+    #[clap(long, env)]
+    pub auth_python_script_template_path: Option<String>,
+
     #[clap(long, env, default_value = "info")]
     pub rust_log: String,
+
+    /// Api key required as bearer token for the admin endpoints. Admin endpoints are disabled if unset
+    #[clap(long, env)]
+    pub admin_api_key: Option<String>,
+
+    /// JSON file listing the `bk`, `name`, `opal_url` and optional `profile` of the bridgeheads.
+    /// Unlisted bridgeheads are named after the second segment of their AppId
+    #[clap(long, env)]
+    pub site_registry_path: Option<String>,
+
+    /// Seconds the table lists fetched from the bridgeheads are cached
+    #[clap(long, env, default_value = "300")]
+    pub tables_cache_ttl: u64,
+
+    /// Seconds the protocol version and request types reported by the bridgeheads are cached
+    #[clap(long, env, default_value = "3600")]
+    pub capabilities_cache_ttl: u64,
+
+    /// Secret used to sign script download links; a random secret is used if unset, which
+    /// invalidates the links on restart
+    #[clap(long, env)]
+    pub download_link_secret: Option<String>,
+
+    /// Seconds a script download link is valid
+    #[clap(long, env, default_value = "300")]
+    pub download_link_ttl: u64,
+
+    /// Public base url of this service the download links are built with, e.g. `https://example.com`
+    #[clap(long, env)]
+    pub public_url: Option<String>,
+
+    /// Seconds finished script jobs and their download links are kept
+    #[clap(long, env, default_value = "86400")]
+    pub script_job_retention: u64,
+
+    /// Seconds script generation waits for the tokens it requested from bridgeheads without one
+    #[clap(long, env, default_value = "30")]
+    pub script_provision_timeout: u64,
+
+    /// Beam task settings per request type as JSON, overriding the default `ttl` of 60s, the
+    /// `discard` failure strategy and Beam's default `wait_time` (30s for SCRIPT and
+    /// CAPABILITIES), e.g.
+    /// `{"SCRIPT": {"wait_time": "45s", "failure_strategy": {"retry": {"backoff_millisecs": 1000, "max_tries": 3}}}}`
+    #[clap(long, env, default_value = "{}", value_parser = parse_task_settings)]
+    pub beam_task_settings: TaskSettingsConfig,
+
+    /// Seconds between scheduled reconciliations of the active tokens with the sites, disabled if unset
+    #[clap(long, env)]
+    pub reconcile_interval: Option<u64>,
+
+    /// Drift kinds the reconciler repairs by re-creating the stored token at the site, e.g.
+    /// `MISSING_AT_SITE,EXPIRED`; other drift is only reported, `UNKNOWN_AT_SITE` and
+    /// `UNDETERMINED` are never repaired
+    #[clap(long, env, value_delimiter = ',')]
+    pub reconcile_repair: Vec<DriftKind>,
+
+    /// Beam AppIds allowed to request tokens, token statuses and scripts with Beam tasks addressed
+    /// to `BEAM_ID`, e.g. `orchestrator.proxy.broker`; inbound tasks are not polled if unset
+    #[clap(long, env, value_delimiter = ',', value_parser=|id: &str| Ok::<_, Infallible>(AppId::new_unchecked(id)))]
+    pub inbound_allowed_apps: Vec<AppId>,
+
+    /// Accept notifications of the bridgeheads about tokens and projects deleted, disabled or
+    /// expired directly at their site, sent as Beam tasks addressed to `BEAM_ID`; only apps in the
+    /// site registry or with stored tokens are accepted as bridgeheads
+    #[clap(long, env)]
+    pub site_notifications: bool,
+
+    /// Maximum number of attempts to deliver a webhook notification
+    #[clap(long, env, default_value = "5")]
+    pub webhook_max_attempts: u32,
 }
 
 pub static BEAM_CLIENT: Lazy<BeamClient> = Lazy::new(|| {
@@ -5,29 +5,63 @@ use axum::{
     http::{request::Parts, StatusCode},
     Json,
 };
+use chrono::Local;
 use diesel::prelude::*;
 use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
 use diesel::result::Error;
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use serde_json::json;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
 use tracing::{error, info, warn};
 
 use crate::config::CONFIG;
-use crate::enums::{OpalProjectStatus, OpalTokenStatus};
+use crate::enums::{
+    OpalProjectStatus, OpalTokenStatus, OutboxOperation, OutboxStatus, ScriptFormat,
+    ScriptJobStatus, WebhookDeliveryStatus,
+};
 use crate::handlers::{
     check_project_status_request, check_token_status_request, fetch_project_tables_names_request,
 };
 use crate::models::{
-    NewToken, ProjectQueryParams, TokenManager, TokenParams, TokenStatus, TokensQueryParams,
+    BridgeheadRevocationProgress, DownloadLink, NewOutboxEntry, NewReconciliationRun,
+    NewScriptTemplate, NewSiteNotification, NewToken, NewWebhook, NewWebhookDelivery, OutboxEntry,
+    ProjectQueryParams, ProjectTemplate, ReconciliationRun, RevocationProgress, ScriptCredentials,
+    ScriptJob, ScriptTemplate, SiteCredentials, SiteNotification, TokenManager, TokenParams,
+    TokenStatus, TokenStatusResponse, TokenUpdate, TokensQueryParams, UserKey, UserQueryParams,
+    UserStatusResponse, UserToken, Webhook, WebhookDelivery,
 };
 use crate::schema::tokens;
 use crate::schema::tokens::dsl::*;
-use crate::utils::{decrypt_data, fetch_tables_prefix, generate_r_script};
+use crate::schema::{
+    download_links, outbox, project_templates, reconciliation_runs, script_jobs, script_templates,
+    site_notifications, user_keys, webhook_deliveries, webhooks,
+};
+use crate::sites;
+use crate::templates::{cached_template, invalidate_template_cache, render_script};
+use crate::utils::{
+    credentials_csv, decrypt_data, default_script_template, encrypt_for_public_key,
+    group_tables_by_prefix,
+};
 
 const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
-pub fn setup_db() -> anyhow::Result<Pool<ConnectionManager<SqliteConnection>>> {
+/// Token statuses of rows that must no longer be handed out or re-created at a site.
+const REVOKED_STATUSES: [OpalTokenStatus; 2] =
+    [OpalTokenStatus::REVOKING, OpalTokenStatus::REVOKED];
+
+/// Number of most recent outbox entries returned to admins.
+const OUTBOX_LOG_LIMIT: i64 = 1000;
+
+/// Number of most recent deliveries returned per webhook.
+const WEBHOOK_DELIVERY_LOG_LIMIT: i64 = 100;
+
+/// Number of most recent site notifications returned to admins.
+const SITE_NOTIFICATION_LOG_LIMIT: i64 = 1000;
+
+pub type DbPool = Pool<ConnectionManager<SqliteConnection>>;
+
+pub fn setup_db() -> anyhow::Result<DbPool> {
     let pool = Pool::new(ConnectionManager::<SqliteConnection>::new(
         &CONFIG.token_manager_db_path,
     ))?;
@@ -38,6 +72,20 @@ pub fn setup_db() -> anyhow::Result<Pool<ConnectionManager<SqliteConnection>>> {
     Ok(pool)
 }
 
+/// Migrated in-memory database, a single connection so all users see the same database.
+#[cfg(test)]
+pub fn test_pool() -> DbPool {
+    let pool = Pool::builder()
+        .max_size(1)
+        .build(ConnectionManager::<SqliteConnection>::new(":memory:"))
+        .unwrap();
+    pool.get()
+        .unwrap()
+        .run_pending_migrations(MIGRATIONS)
+        .unwrap();
+    pool
+}
+
 pub struct Db(PooledConnection<ConnectionManager<SqliteConnection>>);
 
 #[async_trait]
@@ -58,6 +106,10 @@ where
 }
 
 impl Db {
+    pub fn from_pool(pool: &DbPool) -> anyhow::Result<Self> {
+        Ok(Self(pool.get()?))
+    }
+
     pub fn save_token_db(&mut self, new_token: NewToken) {
         match diesel::insert_into(tokens::table)
             .values(&new_token)
@@ -76,7 +128,7 @@ impl Db {
         }
     }
 
-    pub fn update_token_db(&mut self, token_update: NewToken) {
+    pub fn update_token_db(&mut self, token_update: TokenUpdate) {
         let maybe_last_id = tokens
             .filter(
                 user_id
@@ -94,7 +146,7 @@ impl Db {
             match diesel::update(target)
                 .set((
                     token.eq(&token_update.token),
-                    token_status.eq("UPDATED"),
+                    token_status.eq(OpalTokenStatus::CREATED),
                     token_created_at.eq(&token_update.token_created_at),
                 ))
                 .execute(&mut self.0)
@@ -110,13 +162,17 @@ impl Db {
         }
     }
 
+    /// Sets the status of the active tokens of the user and project at the bridgehead, revoked
+    /// tokens keep their status.
     pub fn update_token_status_db(&mut self, token_update: TokenStatus) {
-        let target = tokens.filter(
-            user_id
-                .eq(&token_update.user_id)
-                .and(project_id.eq(&token_update.project_id))
-                .and(bk.eq(&token_update.bk)),
-        );
+        let target = tokens
+            .filter(
+                user_id
+                    .eq(&token_update.user_id)
+                    .and(project_id.eq(&token_update.project_id))
+                    .and(bk.eq(&token_update.bk)),
+            )
+            .filter(token_status.ne_all(REVOKED_STATUSES));
 
         match diesel::update(target)
             .set((token_status.eq(token_update.token_status),))
@@ -134,38 +190,863 @@ impl Db {
         }
     }
 
-    pub fn delete_project_db(&mut self, project_params: &ProjectQueryParams) {
-        let target = tokens.filter(project_id.eq(&project_params.project_id));
+    /// Marks the tokens at the bridgehead (or at every bridgehead) as revoking and abandons their
+    /// pending creations.
+    pub fn mark_tokens_revoking_db(&mut self, bridgehead: Option<&str>) -> Result<usize, Error> {
+        let revocable = token_status.ne(OpalTokenStatus::REVOKED);
+        let marked = self.0.transaction(|conn| {
+            let marked = match bridgehead {
+                Some(bridgehead) => diesel::update(tokens.filter(revocable.and(bk.eq(bridgehead))))
+                    .set(token_status.eq(OpalTokenStatus::REVOKING))
+                    .execute(conn)?,
+                None => diesel::update(tokens.filter(revocable))
+                    .set(token_status.eq(OpalTokenStatus::REVOKING))
+                    .execute(conn)?,
+            };
+            abandon_token_creations(conn, bridgehead, None, None, None)?;
+            Ok::<_, Error>(marked)
+        })?;
+        warn!(
+            "Marked {} tokens for revocation in BK: {}",
+            marked,
+            bridgehead.unwrap_or("all")
+        );
+        Ok(marked)
+    }
+
+    pub fn set_token_status_by_id_db(&mut self, token_id: i32, status: OpalTokenStatus) {
+        match diesel::update(tokens.filter(id.eq(token_id)))
+            .set(token_status.eq(status))
+            .execute(&mut self.0)
+        {
+            Ok(_) => {
+                info!("Token {} status set to {}", token_id, status);
+            }
+            Err(error) => {
+                warn!("Error updating token status: {}", error);
+            }
+        }
+    }
+
+    pub fn get_tokens_by_status(
+        &mut self,
+        status: OpalTokenStatus,
+    ) -> Result<Vec<TokenManager>, Error> {
+        tokens
+            .filter(token_status.eq(status))
+            .select(TokenManager::as_select())
+            .load::<TokenManager>(&mut self.0)
+    }
+
+    pub fn get_revocation_progress(&mut self) -> Result<Json<RevocationProgress>, Error> {
+        let rows = tokens
+            .filter(token_status.eq_any(REVOKED_STATUSES))
+            .select((bk, token_status))
+            .load::<(String, OpalTokenStatus)>(&mut self.0)?;
+
+        let mut progress: HashMap<String, (usize, usize)> = HashMap::new();
+        for (bridgehead, status) in rows {
+            let (pending, revoked) = progress.entry(bridgehead).or_default();
+            if status == OpalTokenStatus::REVOKED {
+                *revoked += 1;
+            } else {
+                *pending += 1;
+            }
+        }
+
+        let bridgeheads = progress
+            .into_iter()
+            .map(
+                |(bridgehead, (pending, revoked))| BridgeheadRevocationProgress {
+                    bk: bridgehead,
+                    pending,
+                    revoked,
+                },
+            )
+            .collect();
+
+        Ok(Json(RevocationProgress { bridgeheads }))
+    }
+
+    /// Latest active token per user, project and bridgehead.
+    pub fn get_active_tokens(&mut self) -> Result<Vec<TokenManager>, Error> {
+        let records = tokens
+            .filter(token_status.ne_all(REVOKED_STATUSES))
+            .order(id.desc())
+            .select(TokenManager::as_select())
+            .load::<TokenManager>(&mut self.0)?;
+
+        let mut seen = HashSet::new();
+        Ok(records
+            .into_iter()
+            .filter(|record| {
+                seen.insert((
+                    record.user_id.clone(),
+                    record.project_id.clone(),
+                    record.bk.clone(),
+                ))
+            })
+            .collect())
+    }
+
+    /// Returns the latest non-revoked token of every user and project at the given bridgehead.
+    pub fn get_active_bridgehead_tokens(
+        &mut self,
+        bridgehead: &str,
+    ) -> Result<Vec<TokenManager>, Error> {
+        let records = tokens
+            .filter(bk.eq(bridgehead))
+            .filter(token_status.ne_all(REVOKED_STATUSES))
+            .order(id.desc())
+            .select(TokenManager::as_select())
+            .load::<TokenManager>(&mut self.0)?;
+
+        let mut seen = HashSet::new();
+        Ok(records
+            .into_iter()
+            .filter(|record| seen.insert((record.user_id.clone(), record.project_id.clone())))
+            .collect())
+    }
+
+    /// Names of the tokens of the project at the bridgehead whose deletion was not confirmed.
+    pub fn get_token_names_at_bridgehead(
+        &mut self,
+        project: &str,
+        bridgehead: &str,
+    ) -> Result<HashSet<String>, Error> {
+        let names = tokens
+            .filter(project_id.eq(project))
+            .filter(bk.eq(bridgehead))
+            .filter(token_status.ne(OpalTokenStatus::REVOKED))
+            .select(token_name)
+            .load::<String>(&mut self.0)?;
+        Ok(names.into_iter().collect())
+    }
+
+    /// Tokens of the user that were not revoked yet.
+    pub fn get_user_tokens(&mut self, user: &str) -> Result<Vec<TokenManager>, Error> {
+        tokens
+            .filter(user_id.eq(user))
+            .filter(token_status.ne(OpalTokenStatus::REVOKED))
+            .order(id.desc())
+            .select(TokenManager::as_select())
+            .load::<TokenManager>(&mut self.0)
+    }
+
+    pub fn save_webhook_db(&mut self, new_webhook: NewWebhook) -> Result<Webhook, Error> {
+        self.0.transaction(|conn| {
+            diesel::insert_into(webhooks::table)
+                .values(&new_webhook)
+                .execute(conn)?;
+            webhooks::table
+                .order(webhooks::id.desc())
+                .select(Webhook::as_select())
+                .first(conn)
+        })
+    }
+
+    pub fn get_webhooks(&mut self) -> Result<Vec<Webhook>, Error> {
+        webhooks::table
+            .order(webhooks::id.asc())
+            .select(Webhook::as_select())
+            .load(&mut self.0)
+    }
+
+    pub fn delete_webhook_db(&mut self, webhook_id: i32) -> Result<bool, Error> {
+        self.0.transaction(|conn| {
+            diesel::delete(
+                webhook_deliveries::table.filter(webhook_deliveries::webhook_id.eq(webhook_id)),
+            )
+            .execute(conn)?;
+            let deleted = diesel::delete(webhooks::table.filter(webhooks::id.eq(webhook_id)))
+                .execute(conn)?;
+            Ok(deleted > 0)
+        })
+    }
+
+    pub fn save_webhook_delivery_db(&mut self, delivery: NewWebhookDelivery) -> Result<i32, Error> {
+        self.0.transaction(|conn| {
+            diesel::insert_into(webhook_deliveries::table)
+                .values(&delivery)
+                .execute(conn)?;
+            webhook_deliveries::table
+                .order(webhook_deliveries::id.desc())
+                .select(webhook_deliveries::id)
+                .first(conn)
+        })
+    }
+
+    pub fn update_webhook_delivery_db(
+        &mut self,
+        delivery_id: i32,
+        delivery_status: WebhookDeliveryStatus,
+        delivery_attempts: i32,
+        delivery_response_code: Option<i32>,
+        delivery_error: Option<&str>,
+        updated: &str,
+    ) {
+        match diesel::update(
+            webhook_deliveries::table.filter(webhook_deliveries::id.eq(delivery_id)),
+        )
+        .set((
+            webhook_deliveries::status.eq(delivery_status),
+            webhook_deliveries::attempts.eq(delivery_attempts),
+            webhook_deliveries::response_code.eq(delivery_response_code),
+            webhook_deliveries::last_error.eq(delivery_error),
+            webhook_deliveries::updated_at.eq(updated),
+        ))
+        .execute(&mut self.0)
+        {
+            Ok(_) => {}
+            Err(error) => {
+                warn!("Error updating webhook delivery {}: {}", delivery_id, error);
+            }
+        }
+    }
+
+    /// Queues the operation unless the same operation is already pending for the site. Tokens
+    /// that are deleted are marked as revoking and their pending creations are abandoned.
+    pub fn save_outbox_entry_db(&mut self, entry: NewOutboxEntry) -> Result<bool, Error> {
+        self.0.transaction(|conn| {
+            let deleted = match (entry.operation, entry.token_name, entry.project_id) {
+                (OutboxOperation::DeleteToken, Some(name), _) => {
+                    Some(tokens.filter(token_name.eq(name)).into_boxed())
+                }
+                (OutboxOperation::DeleteProject, _, Some(project)) => {
+                    Some(tokens.filter(project_id.eq(project)).into_boxed())
+                }
+                _ => None,
+            };
+            if let Some(deleted) = deleted {
+                let ids: Vec<i32> = deleted
+                    .filter(bk.eq(entry.bk))
+                    .filter(token_status.ne(OpalTokenStatus::REVOKED))
+                    .select(id)
+                    .load(conn)?;
+                diesel::update(tokens.filter(id.eq_any(&ids)))
+                    .set(token_status.eq(OpalTokenStatus::REVOKING))
+                    .execute(conn)?;
+                abandon_token_creations(
+                    conn,
+                    Some(entry.bk),
+                    None,
+                    entry.project_id,
+                    entry.token_name,
+                )?;
+            }
+
+            let pending = outbox::table
+                .filter(outbox::operation.eq(entry.operation))
+                .filter(outbox::bk.eq(entry.bk))
+                .filter(outbox::project_id.is(entry.project_id))
+                .filter(outbox::token_name.is(entry.token_name))
+                .filter(outbox::status.eq(OutboxStatus::PENDING))
+                .select(outbox::id)
+                .first::<i32>(conn)
+                .optional()?;
+            if pending.is_some() {
+                return Ok(false);
+            }
+            diesel::insert_into(outbox::table)
+                .values(&entry)
+                .execute(conn)?;
+            Ok(true)
+        })
+    }
+
+    pub fn get_due_outbox_entries(&mut self, now: i64) -> Result<Vec<OutboxEntry>, Error> {
+        outbox::table
+            .filter(outbox::status.eq(OutboxStatus::PENDING))
+            .filter(outbox::next_attempt_at.le(now))
+            .order(outbox::next_attempt_at.asc())
+            .select(OutboxEntry::as_select())
+            .load(&mut self.0)
+    }
+
+    pub fn get_next_outbox_attempt(&mut self) -> Result<Option<i64>, Error> {
+        outbox::table
+            .filter(outbox::status.eq(OutboxStatus::PENDING))
+            .select(diesel::dsl::min(outbox::next_attempt_at))
+            .first(&mut self.0)
+    }
+
+    pub fn get_outbox_entries(
+        &mut self,
+        entry_status: Option<OutboxStatus>,
+    ) -> Result<Vec<OutboxEntry>, Error> {
+        let mut query = outbox::table
+            .order(outbox::id.desc())
+            .limit(OUTBOX_LOG_LIMIT)
+            .select(OutboxEntry::as_select())
+            .into_boxed();
+        if let Some(entry_status) = entry_status {
+            query = query.filter(outbox::status.eq(entry_status));
+        }
+        query.load(&mut self.0)
+    }
+
+    pub fn update_outbox_entry_db(
+        &mut self,
+        entry_id: i32,
+        entry_status: OutboxStatus,
+        entry_attempts: i32,
+        next_attempt: i64,
+        entry_error: Option<&str>,
+        updated: &str,
+    ) {
+        // Entries abandoned in the meantime stay abandoned
+        match diesel::update(
+            outbox::table
+                .find(entry_id)
+                .filter(outbox::status.eq(OutboxStatus::PENDING)),
+        )
+        .set((
+            outbox::status.eq(entry_status),
+            outbox::attempts.eq(entry_attempts),
+            outbox::next_attempt_at.eq(next_attempt),
+            outbox::last_error.eq(entry_error),
+            outbox::updated_at.eq(updated),
+        ))
+        .execute(&mut self.0)
+        {
+            Ok(_) => {}
+            Err(error) => {
+                warn!("Error updating outbox entry {}: {}", entry_id, error);
+            }
+        }
+    }
+
+    /// Gives up on a pending entry, the site is not contacted about it anymore.
+    pub fn abandon_outbox_entry_db(&mut self, entry_id: i32, updated: &str) -> Result<bool, Error> {
+        let abandoned = diesel::update(
+            outbox::table
+                .find(entry_id)
+                .filter(outbox::status.eq(OutboxStatus::PENDING)),
+        )
+        .set((
+            outbox::status.eq(OutboxStatus::ABANDONED),
+            outbox::updated_at.eq(updated),
+        ))
+        .execute(&mut self.0)?;
+        Ok(abandoned > 0)
+    }
+
+    /// Marks the tokens of the user as revoking and abandons their pending creations, before
+    /// they are deleted at the sites.
+    pub fn mark_user_tokens_revoking_db(&mut self, user: &str) -> Result<usize, Error> {
+        self.0.transaction(|conn| {
+            let marked = diesel::update(
+                tokens
+                    .filter(user_id.eq(user))
+                    .filter(token_status.ne(OpalTokenStatus::REVOKED)),
+            )
+            .set(token_status.eq(OpalTokenStatus::REVOKING))
+            .execute(conn)?;
+            abandon_token_creations(conn, None, Some(user), None, None)?;
+            Ok(marked)
+        })
+    }
+
+    /// Stores the token created for the outbox entry unless the entry was abandoned in the
+    /// meantime, e.g. because the token was deleted or revoked. Returns whether it was stored.
+    pub fn save_outbox_token_db(
+        &mut self,
+        entry_id: i32,
+        new_token: NewToken,
+    ) -> Result<bool, Error> {
+        self.0.transaction(|conn| {
+            let pending = outbox::table
+                .find(entry_id)
+                .filter(outbox::status.eq(OutboxStatus::PENDING))
+                .select(outbox::id)
+                .first::<i32>(conn)
+                .optional()?;
+            if pending.is_none() {
+                return Ok(false);
+            }
+            diesel::insert_into(tokens::table)
+                .values(&new_token)
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+            Ok(true)
+        })
+    }
 
-        match diesel::delete(target).execute(&mut self.0) {
+    /// Sets the status of the token at the bridgehead, pending creations of revoked tokens are
+    /// abandoned.
+    pub fn set_token_status_at_bridgehead_db(
+        &mut self,
+        token_name_id: &str,
+        bridgehead: &str,
+        status: OpalTokenStatus,
+    ) {
+        match self.0.transaction(|conn| {
+            let updated =
+                diesel::update(tokens.filter(token_name.eq(token_name_id).and(bk.eq(bridgehead))))
+                    .set(token_status.eq(status))
+                    .execute(conn)?;
+            if REVOKED_STATUSES.contains(&status) {
+                abandon_token_creations(conn, Some(bridgehead), None, None, Some(token_name_id))?;
+            }
+            Ok::<_, Error>(updated)
+        }) {
             Ok(_) => {
                 info!(
-                    "Project and Token deleted from DB for project: {} in BK: {}",
-                    &project_params.project_id, &project_params.bk
+                    "Token {} in BK {} status set to {}",
+                    token_name_id, bridgehead, status
                 );
             }
             Err(error) => {
-                warn!("Error deleting token: {}", error);
+                warn!("Error updating token status: {}", error);
             }
         }
     }
 
-    pub fn delete_token_db(&mut self, token_name_id: String, token_params: &TokensQueryParams) {
-        let target = tokens.filter(token_name.eq(&token_name_id));
-
-        match diesel::delete(target).execute(&mut self.0) {
+    /// Sets the status of the tokens of the project at the bridgehead, pending creations of
+    /// revoked tokens are abandoned.
+    pub fn set_project_tokens_status_db(
+        &mut self,
+        project: &str,
+        bridgehead: &str,
+        status: OpalTokenStatus,
+    ) {
+        match self.0.transaction(|conn| {
+            let updated =
+                diesel::update(tokens.filter(project_id.eq(project).and(bk.eq(bridgehead))))
+                    .set(token_status.eq(status))
+                    .execute(conn)?;
+            if REVOKED_STATUSES.contains(&status) {
+                abandon_token_creations(conn, Some(bridgehead), None, Some(project), None)?;
+            }
+            Ok::<_, Error>(updated)
+        }) {
             Ok(_) => {
                 info!(
-                    "Token deleted from DB for user: {} in BK: {}",
-                    token_params.user_id, token_params.bk
+                    "Tokens of project {} in BK {} status set to {}",
+                    project, bridgehead, status
                 );
             }
             Err(error) => {
-                warn!("Error deleting token: {}", error);
+                warn!("Error updating token status: {}", error);
+            }
+        }
+    }
+
+    /// Applies the change the bridgehead reported in a task once: sets the status of its active
+    /// tokens with the given name and of the given project and records the notification with the
+    /// number of tokens updated. Returns the id of the notification and the tokens whose status
+    /// changed, or `None` if the task was applied before.
+    pub fn apply_site_notification_db(
+        &mut self,
+        mut notification: NewSiteNotification,
+        status: OpalTokenStatus,
+    ) -> Result<Option<(i32, Vec<TokenManager>)>, Error> {
+        self.0.transaction(|conn| {
+            let applied = site_notifications::table
+                .filter(site_notifications::task_id.eq(notification.task_id))
+                .select(site_notifications::id)
+                .first::<i32>(conn)
+                .optional()?;
+            if applied.is_some() {
+                return Ok(None);
+            }
+
+            let mut query = tokens
+                .filter(bk.eq(notification.bk))
+                .filter(token_status.ne_all(REVOKED_STATUSES))
+                .filter(token_status.ne(status))
+                .select(TokenManager::as_select())
+                .into_boxed();
+            if let Some(token_name_id) = notification.token_name {
+                query = query.filter(token_name.eq(token_name_id));
+            }
+            if let Some(project) = notification.project_id {
+                query = query.filter(project_id.eq(project));
+            }
+            let records = query.load::<TokenManager>(conn)?;
+
+            let ids: Vec<i32> = records.iter().map(|record| record.id).collect();
+            diesel::update(tokens.filter(id.eq_any(&ids)))
+                .set(token_status.eq(status))
+                .execute(conn)?;
+
+            notification.tokens_updated = records.len() as i32;
+            diesel::insert_into(site_notifications::table)
+                .values(&notification)
+                .execute(conn)?;
+            let notification_id = site_notifications::table
+                .order(site_notifications::id.desc())
+                .select(site_notifications::id)
+                .first(conn)?;
+            Ok(Some((notification_id, records)))
+        })
+    }
+
+    /// Whether tokens were ever stored for the bridgehead.
+    pub fn is_known_bridgehead(&mut self, bridgehead: &str) -> Result<bool, Error> {
+        diesel::select(diesel::dsl::exists(tokens.filter(bk.eq(bridgehead))))
+            .get_result(&mut self.0)
+    }
+
+    pub fn get_site_notifications(
+        &mut self,
+        bridgehead: Option<&str>,
+    ) -> Result<Vec<SiteNotification>, Error> {
+        let mut query = site_notifications::table
+            .order(site_notifications::id.desc())
+            .limit(SITE_NOTIFICATION_LOG_LIMIT)
+            .select(SiteNotification::as_select())
+            .into_boxed();
+        if let Some(bridgehead) = bridgehead {
+            query = query.filter(site_notifications::bk.eq(bridgehead));
+        }
+        query.load(&mut self.0)
+    }
+
+    pub fn get_webhook_deliveries(
+        &mut self,
+        webhook_id: i32,
+    ) -> Result<Vec<WebhookDelivery>, Error> {
+        webhook_deliveries::table
+            .filter(webhook_deliveries::webhook_id.eq(webhook_id))
+            .order(webhook_deliveries::id.desc())
+            .limit(WEBHOOK_DELIVERY_LOG_LIMIT)
+            .select(WebhookDelivery::as_select())
+            .load(&mut self.0)
+    }
+
+    pub fn get_pending_webhook_deliveries(&mut self) -> Result<Vec<WebhookDelivery>, Error> {
+        webhook_deliveries::table
+            .filter(webhook_deliveries::status.eq(WebhookDeliveryStatus::PENDING))
+            .order(webhook_deliveries::id.asc())
+            .select(WebhookDelivery::as_select())
+            .load(&mut self.0)
+    }
+
+    pub fn save_script_job_db(&mut self, job: &ScriptJob) -> Result<(), Error> {
+        diesel::insert_into(script_jobs::table)
+            .values(job)
+            .execute(&mut self.0)?;
+        Ok(())
+    }
+
+    pub fn update_script_job_tables_db(
+        &mut self,
+        job_id: &str,
+        tables: &str,
+        updated_at: &str,
+    ) -> Result<(), Error> {
+        diesel::update(script_jobs::table.find(job_id))
+            .set((
+                script_jobs::tables.eq(tables),
+                script_jobs::updated_at.eq(updated_at),
+            ))
+            .execute(&mut self.0)?;
+        Ok(())
+    }
+
+    pub fn finish_script_job_db(
+        &mut self,
+        job_id: &str,
+        status: ScriptJobStatus,
+        error: Option<&str>,
+        updated_at: &str,
+    ) -> Result<(), Error> {
+        diesel::update(script_jobs::table.find(job_id))
+            .set((
+                script_jobs::status.eq(status),
+                script_jobs::error.eq(error),
+                script_jobs::updated_at.eq(updated_at),
+            ))
+            .execute(&mut self.0)?;
+        Ok(())
+    }
+
+    /// Fails the jobs still pending, their results can no longer be collected.
+    pub fn fail_pending_script_jobs_db(
+        &mut self,
+        error: &str,
+        updated_at: &str,
+    ) -> Result<usize, Error> {
+        diesel::update(script_jobs::table.filter(script_jobs::status.eq(ScriptJobStatus::PENDING)))
+            .set((
+                script_jobs::status.eq(ScriptJobStatus::FAILED),
+                script_jobs::error.eq(error),
+                script_jobs::updated_at.eq(updated_at),
+            ))
+            .execute(&mut self.0)
+    }
+
+    /// Ids and update times of the jobs that are no longer pending.
+    pub fn get_finished_script_jobs(&mut self) -> Result<Vec<(String, String)>, Error> {
+        script_jobs::table
+            .filter(script_jobs::status.ne(ScriptJobStatus::PENDING))
+            .select((script_jobs::job_id, script_jobs::updated_at))
+            .load(&mut self.0)
+    }
+
+    /// Deletes the jobs together with their download links.
+    pub fn delete_script_jobs_db(&mut self, job_ids: &[String]) -> Result<usize, Error> {
+        self.0.transaction(|conn| {
+            diesel::delete(download_links::table.filter(download_links::job_id.eq_any(job_ids)))
+                .execute(conn)?;
+            diesel::delete(script_jobs::table.filter(script_jobs::job_id.eq_any(job_ids)))
+                .execute(conn)
+        })
+    }
+
+    pub fn get_script_job(&mut self, job_id: &str) -> Result<Option<ScriptJob>, Error> {
+        script_jobs::table
+            .find(job_id)
+            .select(ScriptJob::as_select())
+            .first(&mut self.0)
+            .optional()
+    }
+
+    pub fn save_reconciliation_run_db(&mut self, run: NewReconciliationRun) -> Result<i32, Error> {
+        self.0.transaction(|conn| {
+            diesel::insert_into(reconciliation_runs::table)
+                .values(&run)
+                .execute(conn)?;
+            reconciliation_runs::table
+                .order(reconciliation_runs::id.desc())
+                .select(reconciliation_runs::id)
+                .first(conn)
+        })
+    }
+
+    pub fn get_latest_reconciliation_run(&mut self) -> Result<Option<ReconciliationRun>, Error> {
+        reconciliation_runs::table
+            .order(reconciliation_runs::id.desc())
+            .select(ReconciliationRun::as_select())
+            .first(&mut self.0)
+            .optional()
+    }
+
+    pub fn save_download_link_db(&mut self, link: &DownloadLink) -> Result<(), Error> {
+        diesel::insert_into(download_links::table)
+            .values(link)
+            .execute(&mut self.0)?;
+        Ok(())
+    }
+
+    pub fn get_download_link(&mut self, link_id: &str) -> Result<Option<DownloadLink>, Error> {
+        download_links::table
+            .find(link_id)
+            .select(DownloadLink::as_select())
+            .first(&mut self.0)
+            .optional()
+    }
+
+    /// Marks the link as used; `false` if it was used before.
+    pub fn claim_download_link_db(&mut self, link_id: &str, used_at: &str) -> Result<bool, Error> {
+        let claimed = diesel::update(
+            download_links::table
+                .find(link_id)
+                .filter(download_links::used_at.is_null()),
+        )
+        .set(download_links::used_at.eq(used_at))
+        .execute(&mut self.0)?;
+        Ok(claimed > 0)
+    }
+
+    pub fn save_user_key_db(&mut self, user_key: &UserKey) -> Result<(), Error> {
+        diesel::replace_into(user_keys::table)
+            .values(user_key)
+            .execute(&mut self.0)?;
+        Ok(())
+    }
+
+    pub fn get_user_key(&mut self, user: &str) -> Result<Option<UserKey>, Error> {
+        user_keys::table
+            .find(user)
+            .select(UserKey::as_select())
+            .first(&mut self.0)
+            .optional()
+    }
+
+    pub fn delete_user_key_db(&mut self, user: &str) -> Result<bool, Error> {
+        let deleted = diesel::delete(user_keys::table.find(user)).execute(&mut self.0)?;
+        Ok(deleted > 0)
+    }
+
+    /// Encrypts the script to the public key registered for the user.
+    pub fn encrypt_script_for_user(
+        &mut self,
+        user: &str,
+        script: &str,
+    ) -> Result<String, (StatusCode, String)> {
+        let user_key = match self.get_user_key(user) {
+            Ok(Some(user_key)) => user_key,
+            Ok(None) => {
+                return Err((
+                    StatusCode::PRECONDITION_FAILED,
+                    format!("No public key registered for user {user}"),
+                ))
+            }
+            Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+        };
+        encrypt_for_public_key(&user_key.public_key, script).map_err(|e| {
+            error!("Failed to encrypt script for user {user}: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to encrypt script".to_string(),
+            )
+        })
+    }
+
+    /// Stores the content as the next version of the named template.
+    pub fn save_script_template_db(
+        &mut self,
+        template_name: &str,
+        content: &str,
+        created_at: &str,
+    ) -> Result<ScriptTemplate, Error> {
+        let template = self.0.transaction(|conn| {
+            let latest_version = script_templates::table
+                .filter(script_templates::name.eq(template_name))
+                .select(diesel::dsl::max(script_templates::version))
+                .first::<Option<i32>>(conn)?;
+            diesel::insert_into(script_templates::table)
+                .values(NewScriptTemplate {
+                    name: template_name,
+                    version: latest_version.unwrap_or(0) + 1,
+                    content,
+                    created_at,
+                })
+                .execute(conn)?;
+            script_templates::table
+                .order(script_templates::id.desc())
+                .select(ScriptTemplate::as_select())
+                .first(conn)
+        })?;
+        // Projects following the latest version of the template get the new one
+        invalidate_template_cache();
+        Ok(template)
+    }
+
+    pub fn get_script_templates(
+        &mut self,
+        template_name: Option<&str>,
+    ) -> Result<Vec<ScriptTemplate>, Error> {
+        let mut query = script_templates::table
+            .select(ScriptTemplate::as_select())
+            .order((
+                script_templates::name.asc(),
+                script_templates::version.desc(),
+            ))
+            .into_boxed();
+        if let Some(template_name) = template_name {
+            query = query.filter(script_templates::name.eq(template_name));
+        }
+        query.load(&mut self.0)
+    }
+
+    /// The given version of the template or its latest version.
+    pub fn get_script_template(
+        &mut self,
+        template_name: &str,
+        version: Option<i32>,
+    ) -> Result<Option<ScriptTemplate>, Error> {
+        let mut query = script_templates::table
+            .filter(script_templates::name.eq(template_name))
+            .select(ScriptTemplate::as_select())
+            .order(script_templates::version.desc())
+            .into_boxed();
+        if let Some(version) = version {
+            query = query.filter(script_templates::version.eq(version));
+        }
+        query.first(&mut self.0).optional()
+    }
+
+    pub fn set_project_template_db(
+        &mut self,
+        project_template: &ProjectTemplate,
+    ) -> Result<(), Error> {
+        diesel::replace_into(project_templates::table)
+            .values(project_template)
+            .execute(&mut self.0)?;
+        invalidate_template_cache();
+        Ok(())
+    }
+
+    pub fn delete_project_template_db(
+        &mut self,
+        project: &str,
+        format: ScriptFormat,
+    ) -> Result<bool, Error> {
+        let deleted = diesel::delete(
+            project_templates::table
+                .filter(project_templates::project_id.eq(project))
+                .filter(project_templates::format.eq(format)),
+        )
+        .execute(&mut self.0)?;
+        if deleted > 0 {
+            invalidate_template_cache();
+        }
+        Ok(deleted > 0)
+    }
+
+    pub fn get_project_templates(&mut self) -> Result<Vec<ProjectTemplate>, Error> {
+        project_templates::table
+            .order((
+                project_templates::project_id.asc(),
+                project_templates::format.asc(),
+            ))
+            .select(ProjectTemplate::as_select())
+            .load(&mut self.0)
+    }
+
+    /// Template the script of the project is rendered from: the template selected for the
+    /// project or the configured default. Templates are cached until they are changed.
+    pub fn load_script_template(
+        &mut self,
+        project: &str,
+        format: ScriptFormat,
+    ) -> anyhow::Result<Arc<str>> {
+        let template = cached_template(project, format, || {
+            let project_template = project_templates::table
+                .filter(project_templates::project_id.eq(project))
+                .filter(project_templates::format.eq(format))
+                .select(ProjectTemplate::as_select())
+                .first(&mut self.0)
+                .optional()?;
+            let Some(project_template) = project_template else {
+                return Ok(None);
+            };
+            match self.get_script_template(
+                &project_template.template_name,
+                project_template.template_version,
+            )? {
+                Some(template) => Ok(Some(template.content)),
+                None => anyhow::bail!(
+                    "Template {} of project {} does not exist",
+                    project_template.template_name,
+                    project
+                ),
             }
+        })?;
+        match template {
+            Some(template) => Ok(template),
+            None => Ok(default_script_template(format)?.into()),
         }
     }
 
+    /// Status of the latest token of the user and project at the bridgehead.
+    pub fn get_latest_token_status(
+        &mut self,
+        token_params: &TokensQueryParams,
+    ) -> Result<Option<OpalTokenStatus>, Error> {
+        tokens
+            .filter(user_id.eq(&token_params.user_id))
+            .filter(project_id.eq(&token_params.project_id))
+            .filter(bk.eq(&token_params.bk))
+            .order(id.desc())
+            .select(token_status)
+            .first::<OpalTokenStatus>(&mut self.0)
+            .optional()
+    }
+
     pub fn get_token_name(
         &mut self,
         token_params: &TokensQueryParams,
@@ -174,6 +1055,7 @@ impl Db {
             .filter(user_id.eq(token_params.user_id.clone()))
             .filter(project_id.eq(token_params.project_id.clone()))
             .filter(bk.eq(token_params.bk.clone()))
+            .filter(token_status.ne_all(REVOKED_STATUSES))
             .order(id.desc())
             .select(token_name)
             .first::<String>(&mut self.0)
@@ -190,17 +1072,43 @@ impl Db {
             .filter(user_id.eq(user))
             .filter(project_id.eq(project))
             .filter(bk.eq(bridgehead))
+            .filter(token_status.ne_all(REVOKED_STATUSES))
             .order(id.desc())
             .select(token)
             .first::<String>(&mut self.0)
             .optional()
     }
 
+    /// Bridgeheads of the request the user has no usable token for.
+    pub fn bridgeheads_without_token(
+        &mut self,
+        params: &TokenParams,
+    ) -> Result<Vec<String>, Error> {
+        let with_token: HashSet<String> = tokens
+            .filter(user_id.eq(&params.user_id))
+            .filter(project_id.eq(&params.project_id))
+            .filter(bk.eq_any(&params.bridgehead_ids))
+            .filter(token_status.ne_all(REVOKED_STATUSES))
+            .select(bk)
+            .load::<String>(&mut self.0)?
+            .into_iter()
+            .collect();
+
+        Ok(params
+            .bridgehead_ids
+            .iter()
+            .filter(|bridgehead| !with_token.contains(*bridgehead))
+            .cloned()
+            .collect())
+    }
+
     pub fn is_token_available(&mut self, params: &TokenParams) -> Result<bool, Error> {
         let result = tokens
             .filter(user_id.eq(&params.user_id))
             .filter(project_id.eq(&params.project_id))
             .filter(bk.eq_any(&params.bridgehead_ids))
+            .filter(token_status.ne_all(REVOKED_STATUSES))
+            .select(TokenManager::as_select())
             .first::<TokenManager>(&mut self.0)
             .optional();
 
@@ -233,38 +1141,86 @@ impl Db {
         }
     }
 
+    pub async fn check_user_offboarding_status(
+        &mut self,
+        params: UserQueryParams,
+    ) -> Result<Json<UserStatusResponse>, (StatusCode, String)> {
+        let records = self
+            .get_user_tokens(&params.user_id)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+        let outstanding = records
+            .into_iter()
+            .map(|record| {
+                Ok(UserToken {
+                    permissions: record.permissions()?,
+                    bk: record.bk,
+                    project_id: record.project_id,
+                    token_status: record.token_status,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+
+        info!(
+            "User {} has {} outstanding tokens",
+            params.user_id,
+            outstanding.len()
+        );
+
+        Ok(Json(UserStatusResponse {
+            user_id: params.user_id,
+            outstanding,
+        }))
+    }
+
     pub async fn check_token_status(
         &mut self,
         params: TokensQueryParams,
-    ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
-        let mut token_status_json = json!({
-            "project_id": params.project_id.clone(),
-            "bk": params.bk.clone(),
-            "user_id": params.user_id.clone(),
-            "token_created_at": "",
-            "project_status": OpalTokenStatus::NOTFOUND,
-            "token_status": OpalProjectStatus::NOTFOUND,
-        });
+    ) -> Result<Json<TokenStatusResponse>, (StatusCode, String)> {
+        let mut token_status_response = TokenStatusResponse {
+            project_id: params.project_id.clone(),
+            bk: params.bk.clone(),
+            user_id: params.user_id.clone(),
+            token_created_at: String::new(),
+            project_status: OpalProjectStatus::NOTFOUND,
+            token_status: OpalTokenStatus::NOTFOUND,
+            permissions: Default::default(),
+        };
 
         if let Ok(json_response) = check_project_status_request(ProjectQueryParams {
             bk: params.bk.clone(),
             project_id: params.project_id.clone(),
         })
-            .await
+        .await
         {
-            token_status_json["project_status"] = json_response.0["project_status"].clone();
+            token_status_response.project_status = json_response.0.project_status;
         } else {
             error!("Error retrieving project status");
         }
 
+        // Revoked tokens are reported as such and never re-sent to the site
+        match self.get_latest_token_status(&params) {
+            Ok(Some(status)) if REVOKED_STATUSES.contains(&status) => {
+                token_status_response.token_status = status;
+                info!(
+                    "Token of user {} in BK {} is {status}, not checking it at the site",
+                    params.user_id, params.bk
+                );
+                return Ok(Json(token_status_response));
+            }
+            Ok(_) => {}
+            Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+        }
+
         let token_name_response = match self.get_token_name(&params) {
             Ok(Some(name)) => name,
             Ok(None) => {
                 info!(
                     "Received status response for token. User ID: {}, BK: {}, Response: {}",
-                    params.user_id, params.bk, token_status_json["token_status"]
+                    params.user_id, params.bk, token_status_response.token_status
                 );
-                return Ok(Json(token_status_json));
+                return Ok(Json(token_status_response));
             }
             Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
         };
@@ -273,6 +1229,8 @@ impl Db {
             .filter(user_id.eq(&params.user_id))
             .filter(bk.eq(&params.bk))
             .filter(project_id.eq(&params.project_id))
+            .filter(token_status.ne_all(REVOKED_STATUSES))
+            .order(id.desc())
             .select(TokenManager::as_select())
             .load::<TokenManager>(&mut self.0)
         {
@@ -280,9 +1238,9 @@ impl Db {
             Ok(_) => {
                 info!(
                     "Received status response for token. User ID: {}, BK: {}, Response: {}",
-                    params.user_id, params.bk, token_status_json["token_status"]
+                    params.user_id, params.bk, token_status_response.token_status
                 );
-                return Ok(Json(token_status_json));
+                return Ok(Json(token_status_response));
             }
             Err(err) => {
                 error!("Error calling DB: {}", err);
@@ -291,24 +1249,33 @@ impl Db {
         };
 
         let record = &records[0];
-        token_status_json["token_created_at"] = json!(record.token_created_at);
+        let permissions = record.permissions().map_err(|e| {
+            error!("{e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, e)
+        })?;
+        token_status_response.token_created_at = record.token_created_at.clone();
+        token_status_response.permissions = permissions.clone();
         let token_value = json!(record.token).as_str().unwrap_or_default().to_string();
 
-        if let Ok(json_response) = check_token_status_request(
+        if let Ok(status) = check_token_status_request(
             params.user_id.clone(),
             params.bk.clone(),
             params.project_id.clone(),
             token_name_response.clone(),
             token_value.clone(),
+            permissions,
         )
-            .await
+        .await
         {
-            token_status_json["token_status"] = json_response.0["token_status"].clone();
+            token_status_response.token_status = status;
 
             let new_token_status = TokenStatus {
                 project_id: &params.project_id.clone(),
                 bk: &params.bk.clone(),
-                token_status: OpalTokenStatus::CREATED.as_str(),
+                token_status: match status {
+                    OpalTokenStatus::EXPIRED => OpalTokenStatus::EXPIRED,
+                    _ => OpalTokenStatus::CREATED,
+                },
                 user_id: &params.user_id.clone(),
             };
             self.update_token_status_db(new_token_status);
@@ -318,17 +1285,28 @@ impl Db {
 
         info!(
             "Received status response for token. User ID: {}, BK: {}, Response: {}",
-            params.user_id, params.bk, token_status_json["token_status"]
+            params.user_id, params.bk, token_status_response.token_status
         );
 
-        Ok(Json(token_status_json))
+        Ok(Json(token_status_response))
     }
 
-    pub async fn generate_user_script(&mut self, query: TokenParams) -> Result<String, String> {
+    pub async fn generate_user_script(
+        &mut self,
+        query: TokenParams,
+        format: ScriptFormat,
+    ) -> Result<String, String> {
         let tables_per_bridgehead_result = fetch_project_tables_names_request(query.clone()).await;
         match tables_per_bridgehead_result {
-            Ok(tables_per_bridgehead) => 
-                self.generate_user_script_using_tables(query, tables_per_bridgehead).await,
+            Ok(tables_per_bridgehead) => {
+                match self.generate_user_script_from_tables(&query, format, tables_per_bridgehead) {
+                    Ok(script) => Ok(script), // Return the script if successful
+                    Err(e) => {
+                        error!("Failed to render user script: {e:#}");
+                        Err("# Failed to generate user script due to template error.".to_string())
+                    }
+                }
+            }
             Err(e) => {
                 error!("Error in fetch_project_tables_names_request: {:?}", e);
                 Err("Error obtaining table names.".into())
@@ -336,14 +1314,49 @@ impl Db {
         }
     }
 
-    async fn generate_user_script_using_tables(&mut self, query: TokenParams, bridgehead_tables: HashMap<String, HashSet<String>>) -> Result<String, String> {
-        let mut script_lines = Vec::new();
-        script_lines.push("\"SiteName\",\"URL\",\"ProjectName\",\"Token\"".to_string());
+    /// Renders the script from tables that were already fetched from the bridgeheads.
+    pub fn generate_user_script_from_tables(
+        &mut self,
+        query: &TokenParams,
+        format: ScriptFormat,
+        bridgehead_tables: HashMap<String, HashSet<String>>,
+    ) -> anyhow::Result<String> {
+        let credentials = self.collect_script_credentials(query, bridgehead_tables);
+        self.render_user_script(format, &credentials)
+    }
+
+    fn render_user_script(
+        &mut self,
+        format: ScriptFormat,
+        credentials: &ScriptCredentials,
+    ) -> anyhow::Result<String> {
+        match format {
+            ScriptFormat::R | ScriptFormat::Python => {
+                let template = self.load_script_template(&credentials.project_id, format)?;
+                Ok(render_script(&template, credentials)?)
+            }
+            ScriptFormat::Csv => Ok(credentials_csv(credentials, false)),
+            ScriptFormat::Json => Ok(serde_json::to_string_pretty(credentials)?),
+        }
+    }
+
+    fn collect_script_credentials(
+        &mut self,
+        query: &TokenParams,
+        bridgehead_tables: HashMap<String, HashSet<String>>,
+    ) -> ScriptCredentials {
+        let mut credentials = ScriptCredentials {
+            user_id: query.user_id.clone(),
+            project_id: query.project_id.clone(),
+            generated_at: Local::now().format("%d-%m-%Y %H:%M:%S").to_string(),
+            ..Default::default()
+        };
         for bridgehead in &query.bridgehead_ids {
             let records_result = tokens
                 .filter(project_id.eq(&query.project_id))
                 .filter(user_id.eq(&query.user_id))
                 .filter(bk.eq(bridgehead))
+                .filter(token_status.ne_all(REVOKED_STATUSES))
                 .order(id.desc())
                 .select(TokenManager::as_select())
                 .first::<TokenManager>(&mut self.0);
@@ -354,31 +1367,201 @@ impl Db {
                         record.token.clone(),
                         &record.token_name.clone().as_bytes()[..16],
                     );
-                    let site_name = record.bk.split('.').nth(1).expect("Valid app id");
-                    let tables_prefix = fetch_tables_prefix(&bridgehead_tables, bridgehead, &query.project_id);
-                    // TODO: Maybe in the future, it makes sense to pass record.bk instead of site_name as URL
-                    // e.g. "https://token-manager.dktk-test.broker.ccp-it.dktk.dkfz.de/opal/" instead of "https://dktk-test/opal/"
-                    // It looks more like an absolute beam path. In more complex beam contexts, it would avoid ambiguity.
-                    script_lines.push(format!("\"{}\",\"https://{}/opal/\",\"{}\",\"{}\"",
-                                              site_name, site_name, tables_prefix, token_decrypt));
+                    let site = sites::registry().site(&record.bk);
+                    let grouped_tables =
+                        group_tables_by_prefix(&bridgehead_tables, bridgehead, &query.project_id);
+                    let (prefixes, tables_available) = match grouped_tables {
+                        Some(prefixes) => (prefixes, true),
+                        None => {
+                            warn!(
+                                "Tables of project {} not available for Bridgehead {}",
+                                query.project_id, bridgehead
+                            );
+                            credentials.tables_unavailable.push(bridgehead.clone());
+                            (
+                                BTreeMap::from([(query.project_id.clone(), Vec::new())]),
+                                false,
+                            )
+                        }
+                    };
+                    // Login names have to be unique, so sites with several prefixes get a login per prefix
+                    let several_prefixes = prefixes.len() > 1;
+                    for (prefix, prefix_tables) in prefixes {
+                        let site_name = if several_prefixes {
+                            format!("{}_{}", site.name, prefix)
+                        } else {
+                            site.name.clone()
+                        };
+                        credentials.sites.push(SiteCredentials {
+                            bk: record.bk.clone(),
+                            site_name,
+                            url: site.opal_url.clone(),
+                            profile: site.profile.clone(),
+                            project_name: prefix,
+                            token: token_decrypt.clone(),
+                            tables: prefix_tables,
+                            tables_available,
+                        });
+                    }
                 }
                 Err(_) => {
                     info!("Token not available for Bridgehead {}", bridgehead);
-                    script_lines.push(format!(
-                        "\n # Token not available for bridgehead '{}'",
-                        bridgehead
-                    ));
+                    credentials.missing.push(bridgehead.clone());
                 }
             }
         }
-        if !script_lines.is_empty() {
-            match generate_r_script(script_lines.join("\n")) {
-                Ok(script) => Ok(script), // Return the script if successful
-                Err(_) => Err("# Failed to generate user script due to template error.".to_string()),
-            }
-        } else {
-            Ok("No records found for the given project and user.".into())
+        credentials
+    }
+}
+
+/// Abandons the pending creations of the tokens matching the given bridgehead, user, project and
+/// token name, the tokens were deleted or revoked before the sites confirmed them.
+fn abandon_token_creations(
+    conn: &mut SqliteConnection,
+    bridgehead: Option<&str>,
+    user: Option<&str>,
+    project: Option<&str>,
+    name: Option<&str>,
+) -> Result<usize, Error> {
+    let mut query = outbox::table
+        .filter(outbox::operation.eq(OutboxOperation::CreateToken))
+        .filter(outbox::status.eq(OutboxStatus::PENDING))
+        .select(outbox::id)
+        .into_boxed();
+    if let Some(bridgehead) = bridgehead {
+        query = query.filter(outbox::bk.eq(bridgehead));
+    }
+    if let Some(user) = user {
+        query = query.filter(outbox::user_id.eq(user));
+    }
+    if let Some(project) = project {
+        query = query.filter(outbox::project_id.eq(project));
+    }
+    if let Some(name) = name {
+        query = query.filter(outbox::token_name.eq(name));
+    }
+    let ids: Vec<i32> = query.load(conn)?;
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let now = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
+    let abandoned = diesel::update(outbox::table.filter(outbox::id.eq_any(&ids)))
+        .set((
+            outbox::status.eq(OutboxStatus::ABANDONED),
+            outbox::last_error.eq("Token was deleted before its creation was confirmed"),
+            outbox::updated_at.eq(&now),
+        ))
+        .execute(conn)?;
+    info!("Abandoned {abandoned} pending token creations");
+    Ok(abandoned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_token<'a>(name: &'a str, status: OpalTokenStatus) -> NewToken<'a> {
+        NewToken {
+            token_name: name,
+            token: "secret",
+            project_id: "p",
+            project_status: OpalProjectStatus::CREATED,
+            bk: "bk",
+            token_status: status,
+            user_id: "u",
+            token_created_at: "",
+            scopes: "[]",
+            expires_at: None,
+        }
+    }
+
+    fn query() -> TokensQueryParams {
+        TokensQueryParams {
+            user_id: "u".to_string(),
+            project_id: "p".to_string(),
+            bk: "bk".to_string(),
+        }
+    }
+
+    fn status_update(status: OpalTokenStatus) -> TokenStatus<'static> {
+        TokenStatus {
+            project_id: "p",
+            bk: "bk",
+            token_status: status,
+            user_id: "u",
         }
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn status_update_keeps_revoked_tokens_revoked() {
+        let mut db = Db::from_pool(&test_pool()).unwrap();
+        db.save_token_db(new_token("t", OpalTokenStatus::CREATED));
+        db.mark_user_tokens_revoking_db("u").unwrap();
+
+        db.update_token_status_db(status_update(OpalTokenStatus::CREATED));
+        let status = db.get_latest_token_status(&query()).unwrap();
+        assert_eq!(status, Some(OpalTokenStatus::REVOKING));
+        assert_eq!(db.get_token_name(&query()).unwrap(), None);
+    }
+
+    #[test]
+    fn status_update_changes_active_tokens() {
+        let mut db = Db::from_pool(&test_pool()).unwrap();
+        db.save_token_db(new_token("old", OpalTokenStatus::REVOKED));
+        db.save_token_db(new_token("new", OpalTokenStatus::CREATED));
+
+        db.update_token_status_db(status_update(OpalTokenStatus::EXPIRED));
+        let status = db.get_latest_token_status(&query()).unwrap();
+        assert_eq!(status, Some(OpalTokenStatus::EXPIRED));
+        let revoked = db.get_tokens_by_status(OpalTokenStatus::REVOKED).unwrap();
+        assert_eq!(revoked.len(), 1);
+    }
+
+    fn assign_template(db: &mut Db, project: &str, version: Option<i32>) {
+        db.set_project_template_db(&ProjectTemplate {
+            project_id: project.to_string(),
+            format: ScriptFormat::R,
+            template_name: "template".to_string(),
+            template_version: version,
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn template_updates_replace_the_cached_template() {
+        let mut db = Db::from_pool(&test_pool()).unwrap();
+        db.save_script_template_db("template", "first", "").unwrap();
+        assign_template(&mut db, "cache-update", None);
+        let template = db
+            .load_script_template("cache-update", ScriptFormat::R)
+            .unwrap();
+        assert_eq!(&*template, "first");
+
+        db.save_script_template_db("template", "second", "")
+            .unwrap();
+        let template = db
+            .load_script_template("cache-update", ScriptFormat::R)
+            .unwrap();
+        assert_eq!(&*template, "second");
+    }
+
+    #[test]
+    fn template_assignments_replace_the_cached_template() {
+        let mut db = Db::from_pool(&test_pool()).unwrap();
+        db.save_script_template_db("template", "first", "").unwrap();
+        db.save_script_template_db("template", "second", "")
+            .unwrap();
+        assign_template(&mut db, "cache-assignment", None);
+        let template = db
+            .load_script_template("cache-assignment", ScriptFormat::R)
+            .unwrap();
+        assert_eq!(&*template, "second");
+
+        assign_template(&mut db, "cache-assignment", Some(1));
+        let template = db
+            .load_script_template("cache-assignment", ScriptFormat::R)
+            .unwrap();
+        assert_eq!(&*template, "first");
+    }
+}
@@ -0,0 +1,92 @@
+use once_cell::sync::Lazy;
+use reqwest::Url;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::config::CONFIG;
+use crate::utils::{hmac_sha256_hex, verify_hmac_sha256_hex};
+
+static LINK_SECRET: Lazy<String> = Lazy::new(|| match &CONFIG.download_link_secret {
+    Some(secret) => secret.clone(),
+    None => {
+        warn!("No DOWNLOAD_LINK_SECRET configured, download links are invalidated on restart");
+        format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+    }
+});
+
+fn signed_payload(link_id: &str, expires: i64) -> String {
+    format!("{link_id}.{expires}")
+}
+
+pub fn sign_link(link_id: &str, expires: i64) -> String {
+    hmac_sha256_hex(&LINK_SECRET, &signed_payload(link_id, expires))
+}
+
+pub fn verify_link(link_id: &str, expires: i64, signature: &str) -> bool {
+    verify_hmac_sha256_hex(&LINK_SECRET, &signed_payload(link_id, expires), signature)
+}
+
+/// Url of the link, absolute if `PUBLIC_URL` is configured.
+pub fn link_url(link_id: &str, expires: i64) -> String {
+    let base = CONFIG
+        .public_url
+        .as_deref()
+        .unwrap_or_default()
+        .trim_end_matches('/');
+    format!(
+        "{base}/api/downloads/{link_id}?expires={expires}&signature={}",
+        sign_link(link_id, expires)
+    )
+}
+
+/// Payload of an events link, the lengths keep the filters from being shifted into each other.
+fn events_payload(job_id: &str, user_id: &str, expires: i64) -> String {
+    format!(
+        "events:{}:{job_id}:{}:{user_id}:{expires}",
+        job_id.len(),
+        user_id.len()
+    )
+}
+
+/// Signs a link to the event stream that is bound to the job and user filter.
+pub fn sign_events_link(job_id: Option<&str>, user_id: Option<&str>, expires: i64) -> String {
+    let payload = events_payload(
+        job_id.unwrap_or_default(),
+        user_id.unwrap_or_default(),
+        expires,
+    );
+    hmac_sha256_hex(&LINK_SECRET, &payload)
+}
+
+pub fn verify_events_link(
+    job_id: Option<&str>,
+    user_id: Option<&str>,
+    expires: i64,
+    signature: &str,
+) -> bool {
+    let payload = events_payload(
+        job_id.unwrap_or_default(),
+        user_id.unwrap_or_default(),
+        expires,
+    );
+    verify_hmac_sha256_hex(&LINK_SECRET, &payload, signature)
+}
+
+/// Url of the event stream for clients like `EventSource` that cannot send the admin api key.
+pub fn events_link_url(job_id: Option<&str>, user_id: Option<&str>, expires: i64) -> String {
+    let base = CONFIG
+        .public_url
+        .as_deref()
+        .unwrap_or_default()
+        .trim_end_matches('/');
+    let signature = sign_events_link(job_id, user_id, expires);
+    let expires = expires.to_string();
+    let mut query = Url::parse("http://localhost").expect("Static url is valid");
+    query
+        .query_pairs_mut()
+        .extend_pairs(job_id.map(|id| ("job_id", id)))
+        .extend_pairs(user_id.map(|id| ("user_id", id)))
+        .append_pair("expires", &expires)
+        .append_pair("signature", &signature);
+    format!("{base}/api/events?{}", query.query().unwrap_or_default())
+}
@@ -1,20 +1,16 @@
+use diesel::backend::Backend;
+use diesel::deserialize::{self, FromSql, FromSqlRow};
+use diesel::expression::AsExpression;
+use diesel::serialize::{self, IsNull, Output, ToSql};
+use diesel::sql_types::Text;
+use diesel::sqlite::Sqlite;
 use serde::{Deserialize, Serialize};
 use std::fmt;
-
-#[derive(Debug, Deserialize)]
-#[serde(untagged)]
-pub enum OpalResponse<T> {
-    Err {
-        status_code: i32,
-        error_message: String,
-    },
-    Ok {
-        response: T,
-    },
-}
+use std::str::FromStr;
+use utoipa::ToSchema;
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize, ToSchema)]
 pub enum OpalRequestType {
     #[serde(rename = "CREATE")]
     CREATE,
@@ -26,10 +22,17 @@ pub enum OpalRequestType {
     STATUS,
     #[serde(rename = "SCRIPT")]
     SCRIPT,
+    #[serde(rename = "CAPABILITIES")]
+    CAPABILITIES,
+    #[serde(rename = "TOKENS")]
+    TOKENS,
 }
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ToSchema, AsExpression, FromSqlRow,
+)]
+#[diesel(sql_type = Text)]
 pub enum OpalProjectStatus {
     #[serde(rename = "CREATED")]
     CREATED,
@@ -38,11 +41,14 @@ pub enum OpalProjectStatus {
     #[serde(rename = "NOT_FOUND")]
     NOTFOUND,
     #[serde(rename = "ERROR")]
-    ERROR
+    ERROR,
 }
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ToSchema, AsExpression, FromSqlRow,
+)]
+#[diesel(sql_type = Text)]
 pub enum OpalTokenStatus {
     #[serde(rename = "CREATED")]
     CREATED,
@@ -51,31 +57,110 @@ pub enum OpalTokenStatus {
     #[serde(rename = "NOT_FOUND")]
     NOTFOUND,
     #[serde(rename = "ERROR")]
-    ERROR
+    ERROR,
+    #[serde(rename = "REVOKING")]
+    REVOKING,
+    #[serde(rename = "REVOKED")]
+    REVOKED,
 }
 
 impl OpalProjectStatus {
-    pub fn as_str(&self) -> &'static str {
+    pub const fn as_str(&self) -> &'static str {
         match self {
             OpalProjectStatus::CREATED => "CREATED",
             OpalProjectStatus::WITHDATA => "WITH_DATA",
             OpalProjectStatus::NOTFOUND => "NOT_FOUND",
-            OpalProjectStatus::ERROR => "ERROR"
+            OpalProjectStatus::ERROR => "ERROR",
         }
     }
 }
 
 impl OpalTokenStatus {
-    pub fn as_str(&self) -> &'static str {
+    pub const fn as_str(&self) -> &'static str {
         match self {
             OpalTokenStatus::CREATED => "CREATED",
             OpalTokenStatus::EXPIRED => "EXPIRED",
             OpalTokenStatus::NOTFOUND => "NOT_FOUND",
-            OpalTokenStatus::ERROR => "ERROR"
+            OpalTokenStatus::ERROR => "ERROR",
+            OpalTokenStatus::REVOKING => "REVOKING",
+            OpalTokenStatus::REVOKED => "REVOKED",
+        }
+    }
+}
+
+impl FromStr for OpalProjectStatus {
+    type Err = String;
+
+    fn from_str(status: &str) -> Result<Self, Self::Err> {
+        match status {
+            "CREATED" => Ok(OpalProjectStatus::CREATED),
+            "WITH_DATA" => Ok(OpalProjectStatus::WITHDATA),
+            "NOT_FOUND" => Ok(OpalProjectStatus::NOTFOUND),
+            "ERROR" => Ok(OpalProjectStatus::ERROR),
+            _ => Err(format!("Unknown project status {status}")),
         }
     }
 }
 
+impl FromStr for OpalTokenStatus {
+    type Err = String;
+
+    fn from_str(status: &str) -> Result<Self, Self::Err> {
+        match status {
+            "CREATED" => Ok(OpalTokenStatus::CREATED),
+            "EXPIRED" => Ok(OpalTokenStatus::EXPIRED),
+            "NOT_FOUND" => Ok(OpalTokenStatus::NOTFOUND),
+            "ERROR" => Ok(OpalTokenStatus::ERROR),
+            "REVOKING" => Ok(OpalTokenStatus::REVOKING),
+            "REVOKED" => Ok(OpalTokenStatus::REVOKED),
+            _ => Err(format!("Unknown token status {status}")),
+        }
+    }
+}
+
+impl fmt::Display for OpalProjectStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Display for OpalTokenStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Stores the enum as its `as_str` text and parses it back with `FromStr`.
+macro_rules! text_sql_enum {
+    ($($enum:ty),+ $(,)?) => {
+        $(
+            impl FromSql<Text, Sqlite> for $enum {
+                fn from_sql(bytes: <Sqlite as Backend>::RawValue<'_>) -> deserialize::Result<Self> {
+                    let text = <String as FromSql<Text, Sqlite>>::from_sql(bytes)?;
+                    Ok(text.parse()?)
+                }
+            }
+
+            impl ToSql<Text, Sqlite> for $enum {
+                fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Sqlite>) -> serialize::Result {
+                    out.set_value(self.as_str());
+                    Ok(IsNull::No)
+                }
+            }
+        )+
+    };
+}
+
+text_sql_enum!(
+    OpalProjectStatus,
+    OpalTokenStatus,
+    WebhookDeliveryStatus,
+    OutboxOperation,
+    OutboxStatus,
+    ScriptFormat,
+    ScriptJobStatus,
+);
+
 impl fmt::Display for OpalRequestType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let text = match self {
@@ -84,7 +169,382 @@ impl fmt::Display for OpalRequestType {
             OpalRequestType::UPDATE => "UPDATE",
             OpalRequestType::STATUS => "STATUS",
             OpalRequestType::SCRIPT => "SCRIPT",
+            OpalRequestType::CAPABILITIES => "CAPABILITIES",
+            OpalRequestType::TOKENS => "TOKENS",
         };
         write!(f, "{}", text)
     }
 }
+
+/// Restriction of what a token grants at the site.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+pub enum TokenScope {
+    /// Only usable through DataSHIELD
+    #[serde(rename = "DATASHIELD")]
+    Datashield,
+    /// No write access to the project
+    #[serde(rename = "READ_ONLY")]
+    ReadOnly,
+    /// Only the listed tables of the project
+    #[serde(rename = "TABLES")]
+    Tables(Vec<String>),
+}
+
+/// Optional feature of the Opal agent of a site, reported in its capabilities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+pub enum SiteFeature {
+    /// Tokens can be created with scopes
+    #[serde(rename = "TOKEN_SCOPES")]
+    TokenScopes,
+    /// Tokens can be created with an expiry date
+    #[serde(rename = "TOKEN_EXPIRY")]
+    TokenExpiry,
+}
+
+impl fmt::Display for SiteFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            SiteFeature::TokenScopes => "TOKEN_SCOPES",
+            SiteFeature::TokenExpiry => "TOKEN_EXPIRY",
+        };
+        f.write_str(text)
+    }
+}
+
+/// Change of a token or project made directly at a site and pushed by its bridgehead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+pub enum SiteChange {
+    #[serde(rename = "TOKEN_DELETED")]
+    TokenDeleted,
+    #[serde(rename = "TOKEN_DISABLED")]
+    TokenDisabled,
+    #[serde(rename = "TOKEN_EXPIRED")]
+    TokenExpired,
+    #[serde(rename = "PROJECT_DELETED")]
+    ProjectDeleted,
+}
+
+impl SiteChange {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            SiteChange::TokenDeleted => "TOKEN_DELETED",
+            SiteChange::TokenDisabled => "TOKEN_DISABLED",
+            SiteChange::TokenExpired => "TOKEN_EXPIRED",
+            SiteChange::ProjectDeleted => "PROJECT_DELETED",
+        }
+    }
+
+    /// Local status of the tokens affected by the change, disabled tokens are not handed out
+    /// anymore just like deleted ones.
+    pub const fn token_status(&self) -> OpalTokenStatus {
+        match self {
+            SiteChange::TokenExpired => OpalTokenStatus::EXPIRED,
+            SiteChange::TokenDeleted | SiteChange::TokenDisabled | SiteChange::ProjectDeleted => {
+                OpalTokenStatus::REVOKED
+            }
+        }
+    }
+
+    pub const fn event_kind(&self) -> TokenEventKind {
+        match self {
+            SiteChange::TokenExpired => TokenEventKind::Expired,
+            SiteChange::TokenDeleted | SiteChange::TokenDisabled | SiteChange::ProjectDeleted => {
+                TokenEventKind::Deleted
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+pub enum TokenEventKind {
+    #[serde(rename = "token.created")]
+    Created,
+    #[serde(rename = "token.refreshed")]
+    Refreshed,
+    #[serde(rename = "token.expired")]
+    Expired,
+    #[serde(rename = "token.failed")]
+    Failed,
+    #[serde(rename = "token.deleted")]
+    Deleted,
+}
+
+impl TokenEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenEventKind::Created => "token.created",
+            TokenEventKind::Refreshed => "token.refreshed",
+            TokenEventKind::Expired => "token.expired",
+            TokenEventKind::Failed => "token.failed",
+            TokenEventKind::Deleted => "token.deleted",
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ToSchema, AsExpression, FromSqlRow,
+)]
+#[diesel(sql_type = Text)]
+pub enum WebhookDeliveryStatus {
+    #[serde(rename = "PENDING")]
+    PENDING,
+    #[serde(rename = "DELIVERED")]
+    DELIVERED,
+    #[serde(rename = "FAILED")]
+    FAILED,
+}
+
+impl WebhookDeliveryStatus {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            WebhookDeliveryStatus::PENDING => "PENDING",
+            WebhookDeliveryStatus::DELIVERED => "DELIVERED",
+            WebhookDeliveryStatus::FAILED => "FAILED",
+        }
+    }
+}
+
+impl FromStr for WebhookDeliveryStatus {
+    type Err = String;
+
+    fn from_str(status: &str) -> Result<Self, Self::Err> {
+        match status {
+            "PENDING" => Ok(WebhookDeliveryStatus::PENDING),
+            "DELIVERED" => Ok(WebhookDeliveryStatus::DELIVERED),
+            "FAILED" => Ok(WebhookDeliveryStatus::FAILED),
+            _ => Err(format!("Unknown webhook delivery status {status}")),
+        }
+    }
+}
+
+/// Operation at a site kept in the outbox until the site confirmed it.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ToSchema, AsExpression, FromSqlRow,
+)]
+#[diesel(sql_type = Text)]
+pub enum OutboxOperation {
+    #[serde(rename = "CREATE_TOKEN")]
+    CreateToken,
+    #[serde(rename = "DELETE_TOKEN")]
+    DeleteToken,
+    #[serde(rename = "DELETE_PROJECT")]
+    DeleteProject,
+}
+
+impl OutboxOperation {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            OutboxOperation::CreateToken => "CREATE_TOKEN",
+            OutboxOperation::DeleteToken => "DELETE_TOKEN",
+            OutboxOperation::DeleteProject => "DELETE_PROJECT",
+        }
+    }
+}
+
+impl FromStr for OutboxOperation {
+    type Err = String;
+
+    fn from_str(operation: &str) -> Result<Self, Self::Err> {
+        match operation {
+            "CREATE_TOKEN" => Ok(OutboxOperation::CreateToken),
+            "DELETE_TOKEN" => Ok(OutboxOperation::DeleteToken),
+            "DELETE_PROJECT" => Ok(OutboxOperation::DeleteProject),
+            _ => Err(format!("Unknown outbox operation {operation}")),
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ToSchema, AsExpression, FromSqlRow,
+)]
+#[diesel(sql_type = Text)]
+pub enum OutboxStatus {
+    #[serde(rename = "PENDING")]
+    PENDING,
+    #[serde(rename = "CONFIRMED")]
+    CONFIRMED,
+    #[serde(rename = "ABANDONED")]
+    ABANDONED,
+}
+
+impl OutboxStatus {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            OutboxStatus::PENDING => "PENDING",
+            OutboxStatus::CONFIRMED => "CONFIRMED",
+            OutboxStatus::ABANDONED => "ABANDONED",
+        }
+    }
+}
+
+impl FromStr for OutboxStatus {
+    type Err = String;
+
+    fn from_str(status: &str) -> Result<Self, Self::Err> {
+        match status {
+            "PENDING" => Ok(OutboxStatus::PENDING),
+            "CONFIRMED" => Ok(OutboxStatus::CONFIRMED),
+            "ABANDONED" => Ok(OutboxStatus::ABANDONED),
+            _ => Err(format!("Unknown outbox status {status}")),
+        }
+    }
+}
+
+/// Difference between a locally active token and its state at the site.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+pub enum DriftKind {
+    /// The site does not know the token
+    #[serde(rename = "MISSING_AT_SITE")]
+    MissingAtSite,
+    /// The site reports the token as expired
+    #[serde(rename = "EXPIRED")]
+    Expired,
+    /// The site has a token of the project that is not stored locally, only checked at sites
+    /// supporting TOKENS requests and never repaired automatically
+    #[serde(rename = "UNKNOWN_AT_SITE")]
+    UnknownAtSite,
+    /// The state at the site could not be determined, e.g. it did not respond
+    #[serde(rename = "UNDETERMINED")]
+    Undetermined,
+}
+
+impl FromStr for DriftKind {
+    type Err = String;
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        match kind {
+            "MISSING_AT_SITE" => Ok(DriftKind::MissingAtSite),
+            "EXPIRED" => Ok(DriftKind::Expired),
+            "UNKNOWN_AT_SITE" => Ok(DriftKind::UnknownAtSite),
+            "UNDETERMINED" => Ok(DriftKind::Undetermined),
+            _ => Err(format!("Unknown drift kind {kind}")),
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, ToSchema)]
+pub enum ProgressStatus {
+    #[serde(rename = "PENDING")]
+    PENDING,
+    #[serde(rename = "SUCCEEDED")]
+    SUCCEEDED,
+    #[serde(rename = "FAILED")]
+    FAILED,
+}
+
+/// File format of the generated credentials for the DataSHIELD clients.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    Deserialize,
+    Serialize,
+    ToSchema,
+    AsExpression,
+    FromSqlRow,
+)]
+#[diesel(sql_type = Text)]
+#[serde(rename_all = "lowercase")]
+pub enum ScriptFormat {
+    /// R script using the template at `AUTH_SCRIPT_TEMPLATE_PATH`
+    #[default]
+    R,
+    /// Python script for the `datashield` client
+    Python,
+    /// Only the credentials as CSV
+    Csv,
+    /// Only the credentials as JSON
+    Json,
+}
+
+impl FromStr for ScriptFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "r" => Ok(ScriptFormat::R),
+            "python" => Ok(ScriptFormat::Python),
+            "csv" => Ok(ScriptFormat::Csv),
+            "json" => Ok(ScriptFormat::Json),
+            _ => Err(format!("Unknown script format {format}")),
+        }
+    }
+}
+
+impl ScriptFormat {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            ScriptFormat::R => "r",
+            ScriptFormat::Python => "python",
+            ScriptFormat::Csv => "csv",
+            ScriptFormat::Json => "json",
+        }
+    }
+
+    pub const fn file_extension(&self) -> &'static str {
+        match self {
+            ScriptFormat::R => "R",
+            ScriptFormat::Python => "py",
+            ScriptFormat::Csv => "csv",
+            ScriptFormat::Json => "json",
+        }
+    }
+
+    /// Whether the format is rendered from a template or exports the credentials as they are
+    pub const fn is_templated(&self) -> bool {
+        matches!(self, ScriptFormat::R | ScriptFormat::Python)
+    }
+
+    pub const fn content_type(&self) -> &'static str {
+        match self {
+            ScriptFormat::R => "text/plain; charset=utf-8",
+            ScriptFormat::Python => "text/x-python; charset=utf-8",
+            ScriptFormat::Csv => "text/csv; charset=utf-8",
+            ScriptFormat::Json => "application/json",
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ToSchema, AsExpression, FromSqlRow,
+)]
+#[diesel(sql_type = Text)]
+pub enum ScriptJobStatus {
+    #[serde(rename = "PENDING")]
+    PENDING,
+    #[serde(rename = "COMPLETED")]
+    COMPLETED,
+    #[serde(rename = "FAILED")]
+    FAILED,
+}
+
+impl ScriptJobStatus {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            ScriptJobStatus::PENDING => "PENDING",
+            ScriptJobStatus::COMPLETED => "COMPLETED",
+            ScriptJobStatus::FAILED => "FAILED",
+        }
+    }
+}
+
+impl FromStr for ScriptJobStatus {
+    type Err = String;
+
+    fn from_str(status: &str) -> Result<Self, Self::Err> {
+        match status {
+            "PENDING" => Ok(ScriptJobStatus::PENDING),
+            "COMPLETED" => Ok(ScriptJobStatus::COMPLETED),
+            "FAILED" => Ok(ScriptJobStatus::FAILED),
+            _ => Err(format!("Unknown script job status {status}")),
+        }
+    }
+}
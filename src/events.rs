@@ -0,0 +1,133 @@
+use std::convert::Infallible;
+use std::time::Duration;
+
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_util::{stream, Stream};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, warn};
+use utoipa::ToSchema;
+
+use crate::enums::{OpalRequestType, ProgressStatus};
+use crate::models::EventsQueryParams;
+use crate::opal::OpalTaskRequest;
+use crate::tasks::TaskSettings;
+
+/// Number of events buffered per subscriber before slow subscribers start to miss events.
+const CHANNEL_CAPACITY: usize = 1024;
+
+static EVENTS: Lazy<broadcast::Sender<ProgressEvent>> =
+    Lazy::new(|| broadcast::channel(CHANNEL_CAPACITY).0);
+
+/// Progress of a token operation at a single bridgehead.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct ProgressEvent {
+    /// Id of the Beam task of the operation
+    pub job_id: String,
+    pub operation: OpalRequestType,
+    pub user_id: Option<String>,
+    pub project_id: Option<String>,
+    pub bk: String,
+    pub status: ProgressStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Effective Beam settings of the task
+    pub settings: TaskSettings,
+    pub occurred_at: String,
+}
+
+/// Publishes the progress of one Beam task to the subscribers of `/api/events`.
+pub struct JobProgress {
+    job_id: String,
+    operation: OpalRequestType,
+    user_id: Option<String>,
+    project_id: Option<String>,
+    settings: TaskSettings,
+}
+
+impl JobProgress {
+    /// Creates the progress of the task and publishes it as pending for every addressed bridgehead.
+    pub fn start<R>(
+        task: &OpalTaskRequest<R>,
+        operation: OpalRequestType,
+        user_id: Option<&str>,
+        project_id: Option<&str>,
+    ) -> Self {
+        let progress = Self {
+            job_id: task.id.to_string(),
+            operation,
+            user_id: user_id.map(ToString::to_string),
+            project_id: project_id.map(ToString::to_string),
+            settings: task.settings().clone(),
+        };
+        for bk in &task.to {
+            progress.publish(bk.as_ref(), ProgressStatus::PENDING, None);
+        }
+        progress
+    }
+
+    pub fn succeeded(&self, bk: &str) {
+        self.publish(bk, ProgressStatus::SUCCEEDED, None);
+    }
+
+    pub fn failed(&self, bk: &str, message: impl Into<String>) {
+        self.publish(bk, ProgressStatus::FAILED, Some(message.into()));
+    }
+
+    fn publish(&self, bk: &str, status: ProgressStatus, message: Option<String>) {
+        // Sending only fails if nobody is subscribed
+        let _ = EVENTS.send(ProgressEvent {
+            job_id: self.job_id.clone(),
+            operation: self.operation,
+            user_id: self.user_id.clone(),
+            project_id: self.project_id.clone(),
+            bk: bk.to_string(),
+            status,
+            message,
+            settings: self.settings.clone(),
+            occurred_at: chrono::Utc::now().to_rfc3339(),
+        });
+    }
+}
+
+impl EventsQueryParams {
+    fn matches(&self, event: &ProgressEvent) -> bool {
+        self.job_id.iter().all(|id| *id == event.job_id)
+            && self
+                .user_id
+                .iter()
+                .all(|id| Some(id) == event.user_id.as_ref())
+    }
+}
+
+/// Streams the progress events matching the filter as Server-Sent Events.
+pub fn progress_stream(
+    filter: EventsQueryParams,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let receiver = EVENTS.subscribe();
+    let events = stream::unfold((receiver, filter), |(mut receiver, filter)| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) if filter.matches(&event) => {
+                    let sse_event = Event::default()
+                        .event("progress")
+                        .json_data(&event)
+                        .unwrap_or_else(|e| {
+                            warn!("Failed to serialize progress event: {e}");
+                            Event::default().comment("serialization error")
+                        });
+                    return Some((Ok(sse_event), (receiver, filter)));
+                }
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    debug!("Event subscriber lagged behind, skipped {skipped} events");
+                    continue;
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    Sse::new(events).keep_alive(KeepAlive::new().interval(Duration::from_secs(15)))
+}
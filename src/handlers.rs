@@ -1,90 +1,244 @@
 use std::collections::{HashMap, HashSet};
 use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
 
 use crate::config::BEAM_CLIENT;
 use crate::config::CONFIG;
-use crate::db::Db;
-use crate::enums::{OpalProjectStatus, OpalRequestType, OpalResponse, OpalTokenStatus};
-use crate::models::{NewToken, OpalRequest, ProjectQueryParams, TokenParams, TokensQueryParams};
+use crate::db::{Db, DbPool};
+use crate::enums::{
+    OpalProjectStatus, OpalRequestType, OpalTokenStatus, OutboxOperation, ScriptFormat,
+    ScriptJobStatus, SiteChange, TokenEventKind,
+};
+use crate::events::JobProgress;
+use crate::models::{
+    BridgeheadParams, BridgeheadRevocationReport, CapabilitiesResponse, NewSiteNotification,
+    NewToken, OffboardingReport, OutboxEntry, OutstandingToken, ProjectQueryParams,
+    ProjectStatusResponse, ProjectTablesResponse, ReprovisionReport, ReprovisionedToken,
+    RevocationParams, ScriptJob, TokenEvent, TokenManager, TokenParams, TokenPermissions,
+    TokenUpdate, TokensQueryParams, UserQueryParams,
+};
+use crate::opal::{
+    CapabilitiesRequest, CreateTokenRequest, DeleteProjectRequest, DeleteTokenRequest,
+    OpalResponse, OpalTask, OpalTaskRequest, ProjectStatusRequest, ProjectTablesRequest,
+    ProjectTokensRequest, SiteCapabilities, SiteValue, TokenStatusRequest, UpdateTokenRequest,
+};
+use crate::tasks::{results_path, TaskSettings};
 use crate::utils::{decrypt_data, encrypt_data};
+use crate::{capabilities, outbox, tables, webhooks};
 use anyhow::Result;
 use async_sse::Event;
 use axum::http::StatusCode;
 use axum::{http::HeaderValue, Json};
 use base64::{engine::general_purpose::STANDARD, Engine};
 use beam_lib::{AppId, MsgId, TaskRequest, TaskResult};
-use chrono::Local;
+use chrono::{Local, NaiveDateTime};
+use futures_util::future::join_all;
 use futures_util::stream::TryStreamExt;
 use futures_util::StreamExt;
+use once_cell::sync::Lazy;
 use reqwest::{header, Method};
-use serde_json::json;
+use serde::de::IgnoredAny;
+use tokio::time::MissedTickBehavior;
 use tracing::warn;
 use tracing::{debug, info};
 use uuid::Uuid;
 
+const REPROVISION_CONCURRENCY: usize = 8;
+/// Number of DELETE tasks sent to a bridgehead at a time when revoking several of its tokens.
+const REVOCATION_CONCURRENCY: usize = 8;
+/// Time between the deletions of expired script jobs.
+const SCRIPT_JOB_CLEANUP_INTERVAL: Duration = Duration::from_secs(3600);
+
 pub async fn send_token_registration_request(
     mut db: Db,
     token_params: TokenParams,
-) -> Result<(), anyhow::Error> {
+) -> Result<Option<String>, anyhow::Error> {
     if db.is_token_available(&token_params)? {
-        return Ok(());
+        return Ok(None);
     }
 
     let token_name = Uuid::new_v4().to_string();
-    let task = create_and_send_task_request(
-        OpalRequestType::CREATE,
-        Some(token_name.clone()),
-        Some(token_params.project_id.clone().to_string()),
-        Some(token_params.bridgehead_ids.clone()),
-        None,
-    )
-    .await?;
+    let request = CreateTokenRequest {
+        name: token_name.clone(),
+        project: token_params.project_id.clone(),
+        token: None,
+        permissions: token_params.permissions.clone(),
+    };
+    let task = create_and_send_task_request(request, token_params.bridgehead_ids.clone()).await?;
 
     debug!("Created token task {task:#?}");
-    tokio::task::spawn(save_tokens_from_beam(db, task, token_params, token_name));
-    Ok(())
+    let progress = JobProgress::start(
+        &task,
+        OpalRequestType::CREATE,
+        Some(&token_params.user_id),
+        Some(&token_params.project_id),
+    );
+    let job_id = task.id.to_string();
+    tokio::task::spawn(save_tokens_from_beam(
+        db,
+        task,
+        token_params,
+        token_name,
+        progress,
+    ));
+    Ok(Some(job_id))
 }
 
-pub async fn send_token_from_db(token_params: TokenParams, token_name: String, token: String) {
-    let task = create_and_send_task_request(
+/// Requests tokens from the bridgeheads the user has none for and waits up to
+/// `script_provision_timeout` for them, so they can be part of the script. Tokens arriving
+/// later are still stored. Returns the bridgeheads tokens were requested from. The permissions
+/// have to be checked with `validate_token_permissions` first.
+pub async fn provision_missing_tokens(
+    pool: &DbPool,
+    token_params: &TokenParams,
+) -> Result<Vec<String>> {
+    let mut db = Db::from_pool(pool)?;
+    let missing = db.bridgeheads_without_token(token_params)?;
+    // Bridgeheads a token is already being provisioned at are not asked again
+    let (claim, missing) = ProvisioningClaim::claim(token_params, missing);
+    if missing.is_empty() {
+        return Ok(missing);
+    }
+
+    let token_name = Uuid::new_v4().to_string();
+    let request = CreateTokenRequest {
+        name: token_name.clone(),
+        project: token_params.project_id.clone(),
+        token: None,
+        permissions: token_params.permissions.clone(),
+    };
+    let task = create_and_send_task_request(request, missing.clone()).await?;
+    info!(
+        "Provisioning tokens of user {} for project {} on {missing:?}",
+        token_params.user_id, token_params.project_id
+    );
+
+    let progress = JobProgress::start(
+        &task,
         OpalRequestType::CREATE,
-        Some(token_name.clone()),
-        Some(token_params.project_id.clone().to_string()),
-        Some(token_params.bridgehead_ids.clone()),
-        Some(token.clone()),
-    )
-    .await;
+        Some(&token_params.user_id),
+        Some(&token_params.project_id),
+    );
+    let params = TokenParams {
+        bridgehead_ids: missing.clone(),
+        ..token_params.clone()
+    };
+    let provisioning = tokio::task::spawn(async move {
+        if let Err(e) = save_tokens_from_beam(db, task, params, token_name, progress).await {
+            warn!("Failed to store the provisioned tokens: {e}");
+        }
+        drop(claim);
+    });
+    let timeout = Duration::from_secs(CONFIG.script_provision_timeout);
+    if tokio::time::timeout(timeout, provisioning).await.is_err() {
+        warn!(
+            "Not all bridgeheads of {missing:?} sent a token within {}s, generating the script without them",
+            timeout.as_secs()
+        );
+    }
+    Ok(missing)
+}
+
+/// Bridgeheads tokens are being provisioned at, per user and project.
+static PROVISIONING: Lazy<Mutex<HashSet<(String, String, String)>>> = Lazy::new(Default::default);
+
+/// Bridgeheads claimed for provisioning, released when dropped.
+struct ProvisioningClaim(Vec<(String, String, String)>);
+
+impl ProvisioningClaim {
+    /// Claims the bridgeheads no token is being provisioned at for the user and project yet and
+    /// returns them.
+    fn claim(token_params: &TokenParams, bridgeheads: Vec<String>) -> (Self, Vec<String>) {
+        let mut provisioning = PROVISIONING.lock().unwrap();
+        let mut claimed = Vec::new();
+        let mut unclaimed = Vec::new();
+        for bridgehead in bridgeheads {
+            let key = (
+                token_params.user_id.clone(),
+                token_params.project_id.clone(),
+                bridgehead.clone(),
+            );
+            if provisioning.insert(key.clone()) {
+                claimed.push(key);
+                unclaimed.push(bridgehead);
+            } else {
+                debug!("Token for {key:?} is already being provisioned");
+            }
+        }
+        (Self(claimed), unclaimed)
+    }
+}
+
+impl Drop for ProvisioningClaim {
+    fn drop(&mut self) {
+        let mut provisioning = PROVISIONING.lock().unwrap();
+        for key in &self.0 {
+            provisioning.remove(key);
+        }
+    }
+}
+
+pub async fn send_token_from_db(token_params: TokenParams, token_name: String, token: String) {
+    let request = CreateTokenRequest {
+        name: token_name,
+        project: token_params.project_id,
+        token: Some(token),
+        permissions: token_params.permissions,
+    };
+    let task = create_and_send_task_request(request, token_params.bridgehead_ids).await;
     debug!("Create token in Opal from DB task: {:?}", task);
 }
 
 pub async fn remove_project_and_tokens_request(
     mut db: Db,
     token_params: &ProjectQueryParams,
-) -> Result<OpalResponse<String>, anyhow::Error> {
-    let task = create_and_send_task_request(
-        OpalRequestType::DELETE,
-        None,
-        Some(token_params.project_id.clone()),
-        Some(vec![token_params.bk.clone()]),
-        None,
-    )
-    .await?;
+) -> Result<OpalResponse<IgnoredAny>, anyhow::Error> {
+    let request = DeleteProjectRequest {
+        project: token_params.project_id.clone(),
+    };
+    let task = create_and_send_task_request(request, vec![token_params.bk.clone()]).await?;
 
     debug!("Remove Project and Token request {task:#?}");
+    let progress = JobProgress::start(
+        &task,
+        OpalRequestType::DELETE,
+        None,
+        Some(&token_params.project_id),
+    );
 
-    match remove_project_and_tokens_from_beam(task).await {
+    match first_result_from_beam(task).await {
         Ok(response) => {
-            db.delete_project_db(&token_params);
+            publish_opal_response(&progress, &token_params.bk, &response);
+            if let OpalResponse::Ok { .. } = response {
+                db.set_project_tokens_status_db(
+                    &token_params.project_id,
+                    &token_params.bk,
+                    OpalTokenStatus::REVOKED,
+                );
+                webhooks::notify(TokenEvent::new(
+                    TokenEventKind::Deleted,
+                    None,
+                    &token_params.project_id,
+                    &token_params.bk,
+                ));
+            } else {
+                queue_project_removal(&mut db, token_params);
+            }
             Ok(response)
         }
-        Err(e) => Err(e),
+        Err(e) => {
+            progress.failed(&token_params.bk, e.to_string());
+            queue_project_removal(&mut db, token_params);
+            Err(e)
+        }
     }
 }
 
 pub async fn remove_tokens_request(
     mut db: Db,
     token_params: &TokensQueryParams,
-) -> Result<OpalResponse<String>, anyhow::Error> {
+) -> Result<OpalResponse<IgnoredAny>, anyhow::Error> {
     let token_name = match db.get_token_name(&token_params) {
         Ok(Some(name)) => name,
         Ok(None) => return Err(anyhow::Error::msg("Token not found")),
@@ -93,30 +247,442 @@ pub async fn remove_tokens_request(
         }
     };
 
-    let task = create_and_send_task_request(
+    let request = DeleteTokenRequest {
+        name: token_name.clone(),
+    };
+    let task = create_and_send_task_request(request, vec![token_params.bk.clone()]).await?;
+
+    debug!("Remove Tokens request {task:#?}");
+    let progress = JobProgress::start(
+        &task,
         OpalRequestType::DELETE,
-        Some(token_name.clone()),
+        Some(&token_params.user_id),
+        Some(&token_params.project_id),
+    );
+
+    match first_result_from_beam(task).await {
+        Ok(response) => {
+            publish_opal_response(&progress, &token_params.bk, &response);
+            if let OpalResponse::Ok { .. } = response {
+                db.set_token_status_at_bridgehead_db(
+                    &token_name,
+                    &token_params.bk,
+                    OpalTokenStatus::REVOKED,
+                );
+                webhooks::notify(TokenEvent::new(
+                    TokenEventKind::Deleted,
+                    Some(&token_params.user_id),
+                    &token_params.project_id,
+                    &token_params.bk,
+                ));
+            } else {
+                queue_token_removal(&mut db, &token_name, token_params);
+            }
+            Ok(response)
+        }
+        Err(e) => {
+            progress.failed(&token_params.bk, e.to_string());
+            queue_token_removal(&mut db, &token_name, token_params);
+            Err(e)
+        }
+    }
+}
+
+/// Keeps retrying the deletion the site did not confirm, the local rows are kept as revoking.
+fn queue_project_removal(db: &mut Db, params: &ProjectQueryParams) {
+    outbox::enqueue(
+        db,
+        OutboxOperation::DeleteProject,
+        &params.bk,
         None,
-        Some(vec![token_params.bk.clone()]),
+        Some(&params.project_id),
         None,
+    );
+}
+
+fn queue_token_removal(db: &mut Db, token_name: &str, params: &TokensQueryParams) {
+    outbox::enqueue(
+        db,
+        OutboxOperation::DeleteToken,
+        &params.bk,
+        Some(&params.user_id),
+        Some(&params.project_id),
+        Some(token_name),
+    );
+}
+
+pub async fn offboard_user_request(
+    pool: &DbPool,
+    user_params: &UserQueryParams,
+) -> Result<Json<OffboardingReport>, anyhow::Error> {
+    let records = {
+        let mut db = Db::from_pool(pool)?;
+        let records = db.get_user_tokens(&user_params.user_id)?;
+        // Not handed out or created anymore while the sites are asked to delete them
+        db.mark_user_tokens_revoking_db(&user_params.user_id)?;
+        records
+    };
+
+    let mut tokens_per_bridgehead: HashMap<String, Vec<TokenManager>> = HashMap::new();
+    for record in records {
+        tokens_per_bridgehead
+            .entry(record.bk.clone())
+            .or_default()
+            .push(record);
+    }
+
+    debug!(
+        "Offboarding user {} at {} bridgeheads",
+        user_params.user_id,
+        tokens_per_bridgehead.len()
+    );
+
+    let results = join_all(
+        tokens_per_bridgehead
+            .into_iter()
+            .map(|(bridgehead, records)| revoke_bridgehead_tokens(bridgehead, records)),
     )
-    .await?;
+    .await;
 
-    debug!("Remove Tokens request {task:#?}");
+    // Taken once the sites answered
+    let mut db = Db::from_pool(pool)?;
+    let mut bridgeheads = Vec::new();
+    for (bridgehead, outcomes) in results {
+        let mut revoked = Vec::new();
+        let mut outstanding = Vec::new();
+        for (record, outcome) in outcomes {
+            match outcome {
+                Ok(()) => {
+                    db.set_token_status_at_bridgehead_db(
+                        &record.token_name,
+                        &bridgehead,
+                        OpalTokenStatus::REVOKED,
+                    );
+                    webhooks::notify(TokenEvent::new(
+                        TokenEventKind::Deleted,
+                        Some(&record.user_id),
+                        &record.project_id,
+                        &bridgehead,
+                    ));
+                    revoked.push(record.project_id);
+                }
+                Err(error) => {
+                    warn!(
+                        "Could not revoke token of user {} for project {} in BK {}: {}",
+                        user_params.user_id, record.project_id, bridgehead, error
+                    );
+                    outbox::enqueue(
+                        &mut db,
+                        OutboxOperation::DeleteToken,
+                        &bridgehead,
+                        Some(&record.user_id),
+                        Some(&record.project_id),
+                        Some(&record.token_name),
+                    );
+                    outstanding.push(OutstandingToken {
+                        project_id: record.project_id,
+                        error,
+                    });
+                }
+            }
+        }
+        bridgeheads.push(BridgeheadRevocationReport {
+            bk: bridgehead,
+            revoked,
+            outstanding,
+        });
+    }
+
+    info!("Offboarding finished for user: {}", user_params.user_id);
+
+    Ok(Json(OffboardingReport {
+        user_id: user_params.user_id.clone(),
+        bridgeheads,
+    }))
+}
+
+/// Deletes the tokens at the given bridgehead and waits for the confirmations. The sites delete
+/// one token per task, so up to `REVOCATION_CONCURRENCY` tasks are sent at a time.
+async fn revoke_bridgehead_tokens(
+    bridgehead: String,
+    records: Vec<TokenManager>,
+) -> (String, Vec<(TokenManager, Result<(), String>)>) {
+    let outcomes = futures_util::stream::iter(records)
+        .map(|record| {
+            let bridgehead = &bridgehead;
+            async move {
+                let outcome = revoke_token_at_site(bridgehead, &record).await;
+                (record, outcome)
+            }
+        })
+        .buffer_unordered(REVOCATION_CONCURRENCY)
+        .collect()
+        .await;
+    (bridgehead, outcomes)
+}
+
+async fn revoke_token_at_site(bridgehead: &str, record: &TokenManager) -> Result<(), String> {
+    let request = DeleteTokenRequest {
+        name: record.token_name.clone(),
+    };
+    let task = create_and_send_task_request(request, vec![bridgehead.to_string()])
+        .await
+        .map_err(|e| format!("Error creating task: {e}"))?;
+
+    let progress = JobProgress::start(
+        &task,
+        OpalRequestType::DELETE,
+        Some(&record.user_id),
+        Some(&record.project_id),
+    );
+    let outcome = match first_result_from_beam(task).await {
+        Ok(OpalResponse::Ok { .. }) => Ok(()),
+        Ok(OpalResponse::Err {
+            status_code,
+            error_message,
+        }) => Err(format!("{status_code}: {error_message}")),
+        Err(e) => Err(e.to_string()),
+    };
+    publish_outcome(&progress, bridgehead, &outcome);
+    outcome
+}
 
-    match remove_tokens_from_beam(task).await {
+/// Marks all tokens at the given bridgehead (or at every bridgehead) as revoking and queues their
+/// deletion in the outbox, which keeps sending DELETE tasks until every site confirmed.
+pub fn revoke_all_tokens_request(
+    pool: DbPool,
+    params: &RevocationParams,
+) -> Result<usize, anyhow::Error> {
+    let mut db = Db::from_pool(&pool)?;
+    let marked = db.mark_tokens_revoking_db(params.bk.as_deref())?;
+    let revoking = db.get_tokens_by_status(OpalTokenStatus::REVOKING)?;
+    for record in revoking
+        .iter()
+        .filter(|record| params.bk.iter().all(|bk| *bk == record.bk))
+    {
+        outbox::enqueue(
+            &mut db,
+            OutboxOperation::DeleteToken,
+            &record.bk,
+            Some(&record.user_id),
+            Some(&record.project_id),
+            Some(&record.token_name),
+        );
+    }
+    Ok(marked)
+}
+
+/// Applies a change the bridgehead reported for its site to the local tokens of that bridgehead,
+/// records it and notifies the webhooks. Returns the number of tokens whose status changed, tasks
+/// handed out again are only applied once.
+pub fn apply_site_change(
+    mut db: Db,
+    bridgehead: &str,
+    task_id: &str,
+    change: SiteChange,
+    token_name: Option<&str>,
+    project: Option<&str>,
+) -> Result<usize, (StatusCode, String)> {
+    let missing = match change {
+        SiteChange::TokenDeleted | SiteChange::TokenDisabled | SiteChange::TokenExpired => {
+            token_name.is_none().then_some("name")
+        }
+        SiteChange::ProjectDeleted => project.is_none().then_some("project"),
+    };
+    if let Some(field) = missing {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("{} needs the {field}", change.as_str()),
+        ));
+    }
+
+    let received_at = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
+    let notification = NewSiteNotification {
+        task_id,
+        bk: bridgehead,
+        kind: change.as_str(),
+        token_name,
+        project_id: project,
+        tokens_updated: 0,
+        received_at: &received_at,
+    };
+    let applied = db
+        .apply_site_notification_db(notification, change.token_status())
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    // Beam hands out the task again if its result could not be stored
+    let Some((id, updated)) = applied else {
+        info!(
+            "BK {bridgehead} reported {} in task {task_id} again, it was already applied",
+            change.as_str()
+        );
+        return Ok(0);
+    };
+    info!(
+        "BK {bridgehead} reported {} (notification {id}), {} tokens set to {}",
+        change.as_str(),
+        updated.len(),
+        change.token_status()
+    );
+
+    for record in &updated {
+        webhooks::notify(TokenEvent::new(
+            change.event_kind(),
+            Some(&record.user_id),
+            &record.project_id,
+            bridgehead,
+        ));
+    }
+    Ok(updated.len())
+}
+
+/// Creates the project and token with the stored token value at the site of the record.
+pub async fn recreate_token_at_site(record: &TokenManager) -> Result<(), String> {
+    let token_value = decrypt_data(record.token.clone(), &record.token_name.as_bytes()[..16]);
+    let request = CreateTokenRequest {
+        name: record.token_name.clone(),
+        project: record.project_id.clone(),
+        token: Some(token_value),
+        permissions: record.permissions()?,
+    };
+    let task = create_and_send_task_request(request, vec![record.bk.clone()])
+        .await
+        .map_err(|e| format!("Error creating task: {e}"))?;
+
+    let progress = JobProgress::start(
+        &task,
+        OpalRequestType::CREATE,
+        Some(&record.user_id),
+        Some(&record.project_id),
+    );
+    let outcome = match first_result_from_beam(task).await {
+        Ok(OpalResponse::Ok { .. }) => Ok(()),
+        Ok(OpalResponse::Err {
+            status_code,
+            error_message,
+        }) => Err(format!("{status_code}: {error_message}")),
+        Err(e) => Err(e.to_string()),
+    };
+    publish_outcome(&progress, &record.bk, &outcome);
+    outcome
+}
+
+/// Asks the site of the record for the status of its token.
+pub async fn token_status_at_site(
+    record: &TokenManager,
+) -> Result<OpalResponse<SiteValue<OpalTokenStatus>>, String> {
+    let request = TokenStatusRequest {
+        name: record.token_name.clone(),
+    };
+    let task = create_and_send_task_request(request, vec![record.bk.clone()])
+        .await
+        .map_err(|e| format!("Error creating task: {e}"))?;
+
+    let progress = JobProgress::start(
+        &task,
+        OpalRequestType::STATUS,
+        Some(&record.user_id),
+        Some(&record.project_id),
+    );
+    let response = first_result_from_beam(task)
+        .await
+        .map_err(|e| e.to_string());
+    match &response {
+        Ok(response) => publish_opal_response(&progress, &record.bk, response),
+        Err(e) => progress.failed(&record.bk, e.clone()),
+    }
+    response
+}
+
+/// Asks the bridgehead for the names of the tokens of the project at its site.
+pub async fn token_names_at_site(bridgehead: &str, project: &str) -> Result<Vec<String>, String> {
+    let request = ProjectTokensRequest {
+        project: project.to_string(),
+    };
+    let task = create_and_send_task_request(request, vec![bridgehead.to_string()])
+        .await
+        .map_err(|e| format!("Error creating task: {e}"))?;
+
+    let progress = JobProgress::start(&task, OpalRequestType::TOKENS, None, Some(project));
+    match first_result_from_beam(task).await {
         Ok(response) => {
-            db.delete_token_db(token_name, &token_params);
-            Ok(response)
+            publish_opal_response(&progress, bridgehead, &response);
+            match response {
+                OpalResponse::Ok { response } => Ok(response),
+                OpalResponse::Err {
+                    status_code,
+                    error_message,
+                } => Err(format!("{status_code}: {error_message}")),
+            }
+        }
+        Err(e) => {
+            progress.failed(bridgehead, e.to_string());
+            Err(e.to_string())
         }
-        Err(e) => Err(e),
     }
 }
 
+/// Re-creates every active project and token stored for the given bridgehead, e.g. after its
+/// Opal was rebuilt, and reports the outcome per token.
+pub async fn reprovision_bridgehead_request(
+    pool: &DbPool,
+    params: &BridgeheadParams,
+) -> Result<Json<ReprovisionReport>, anyhow::Error> {
+    let records = Db::from_pool(pool)?.get_active_bridgehead_tokens(&params.bk)?;
+    info!(
+        "Re-provisioning {} tokens in BK: {}",
+        records.len(),
+        params.bk
+    );
+
+    let results: Vec<_> = futures_util::stream::iter(records)
+        .map(|record| async move {
+            let outcome = recreate_token_at_site(&record).await;
+            (record, outcome)
+        })
+        .buffer_unordered(REPROVISION_CONCURRENCY)
+        .collect()
+        .await;
+
+    // Taken once the site answered
+    let mut db = Db::from_pool(pool)?;
+    let mut report = Vec::with_capacity(results.len());
+    let mut failed = 0;
+    for (record, outcome) in results {
+        let (status, error) = match outcome {
+            Ok(()) => {
+                db.set_token_status_by_id_db(record.id, OpalTokenStatus::CREATED);
+                (OpalTokenStatus::CREATED, None)
+            }
+            Err(error) => {
+                warn!(
+                    "Re-provisioning of token {} in BK {} failed: {}",
+                    record.token_name, params.bk, error
+                );
+                failed += 1;
+                (OpalTokenStatus::ERROR, Some(error))
+            }
+        };
+        report.push(ReprovisionedToken {
+            user_id: record.user_id,
+            project_id: record.project_id,
+            token_name: record.token_name,
+            token_status: status,
+            error,
+        });
+    }
+
+    Ok(Json(ReprovisionReport {
+        bk: params.bk.clone(),
+        succeeded: report.len() - failed,
+        failed,
+        tokens: report,
+    }))
+}
+
 pub async fn refresh_token_request(
     mut db: Db,
     token_params: TokenParams,
-) -> Result<(), anyhow::Error> {
+) -> Result<String, anyhow::Error> {
     let token_query_params: TokensQueryParams = TokensQueryParams {
         user_id: token_params.user_id.clone(),
         bk: token_params.bridgehead_ids[0].clone(),
@@ -142,59 +708,359 @@ pub async fn refresh_token_request(
         }
     };
 
-    let task = create_and_send_task_request(
-        OpalRequestType::UPDATE,
-        Some(token_name.clone()),
-        Some(token_params.project_id.clone().to_string()),
-        Some(token_params.bridgehead_ids.clone()),
-        Some(token_value.clone()),
-    )
-    .await?;
+    let request = UpdateTokenRequest {
+        name: token_name.clone(),
+        project: token_params.project_id.clone(),
+        token: token_value,
+    };
+    let task = create_and_send_task_request(request, token_params.bridgehead_ids.clone()).await?;
 
+    let progress = JobProgress::start(
+        &task,
+        OpalRequestType::UPDATE,
+        Some(&token_params.user_id),
+        Some(&token_params.project_id),
+    );
+    let job_id = task.id.to_string();
     tokio::task::spawn(update_tokens_from_beam(
         db,
         task,
         token_params,
         token_name.clone(),
+        progress,
     ));
-    Ok(())
+    Ok(job_id)
 }
 
 pub async fn fetch_project_tables_names_request(
     token_params: TokenParams,
 ) -> Result<HashMap<String, HashSet<String>>, anyhow::Error> {
-    let task = create_and_send_task_request(
-        OpalRequestType::SCRIPT,
-        Some(token_params.user_id.clone().to_string()),
-        Some(token_params.project_id.clone().to_string()),
-        Some(token_params.bridgehead_ids.clone()),
-        None,
-    )
-    .await?;
+    let request = ProjectTablesRequest {
+        project: token_params.project_id.clone(),
+        user: Some(token_params.user_id.clone()),
+    };
+    let task = create_and_send_task_request(request, token_params.bridgehead_ids.clone()).await?;
 
     debug!("Fetch Project Tables Status  {task:#?}");
 
-    fetch_project_tables_from_beam(task).await
+    let tables_per_bridgehead = fetch_project_tables_from_beam(task).await?;
+    tables::store_tables(&token_params.project_id, &tables_per_bridgehead);
+    Ok(tables_per_bridgehead)
+}
+
+/// Starts assembling the script in the background and returns the id of the job to download it.
+pub async fn start_script_job(
+    pool: DbPool,
+    token_params: TokenParams,
+    format: ScriptFormat,
+) -> Result<String, anyhow::Error> {
+    let request = ProjectTablesRequest {
+        project: token_params.project_id.clone(),
+        user: Some(token_params.user_id.clone()),
+    };
+    let task = create_and_send_task_request(request, token_params.bridgehead_ids.clone()).await?;
+    debug!("Script job task {task:#?}");
+
+    let now = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
+    let job_id = task.id.to_string();
+    Db::from_pool(&pool)?.save_script_job_db(&ScriptJob {
+        job_id: job_id.clone(),
+        user_id: token_params.user_id.clone(),
+        project_id: token_params.project_id.clone(),
+        bridgehead_ids: token_params.bridgehead_ids.join(","),
+        format,
+        status: ScriptJobStatus::PENDING,
+        tables: "{}".to_string(),
+        error: None,
+        created_at: now.clone(),
+        updated_at: now,
+        task_settings: Some(serde_json::to_string(task.settings())?),
+    })?;
+
+    let progress = JobProgress::start(
+        &task,
+        OpalRequestType::SCRIPT,
+        Some(&token_params.user_id),
+        Some(&token_params.project_id),
+    );
+    tokio::task::spawn(run_script_job(
+        pool,
+        task,
+        token_params.project_id,
+        progress,
+    ));
+    Ok(job_id)
+}
+
+/// Records the tables of the job as the bridgeheads respond, so partial scripts can be downloaded.
+async fn run_script_job(
+    pool: DbPool,
+    task: OpalTaskRequest<ProjectTablesRequest>,
+    project_id: String,
+    progress: JobProgress,
+) {
+    let job_id = task.id.to_string();
+    let bridgeheads: Vec<String> = task.to.iter().map(|bk| bk.as_ref().to_string()).collect();
+    let mut responded = HashSet::new();
+
+    let result = stream_project_tables_from_beam(task, |bridgehead, outcome, tables_so_far| {
+        publish_outcome(&progress, bridgehead, &outcome);
+        responded.insert(bridgehead.to_string());
+        if outcome.is_ok() {
+            if let Err(e) = record_script_job_tables(&pool, &job_id, tables_so_far) {
+                warn!("Could not record tables of script job {job_id}: {e}");
+            }
+        }
+    })
+    .await;
+
+    for bridgehead in bridgeheads.iter().filter(|bk| !responded.contains(*bk)) {
+        progress.failed(bridgehead, "No response from bridgehead");
+    }
+
+    let (status, error) = match result {
+        Ok(tables_per_bridgehead) => {
+            tables::store_tables(&project_id, &tables_per_bridgehead);
+            (ScriptJobStatus::COMPLETED, None)
+        }
+        Err(e) => {
+            warn!("Script job {job_id} failed: {e}");
+            (ScriptJobStatus::FAILED, Some(e.to_string()))
+        }
+    };
+    let now = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
+    let finished = Db::from_pool(&pool)
+        .and_then(|mut db| Ok(db.finish_script_job_db(&job_id, status, error.as_deref(), &now)?));
+    match finished {
+        Ok(()) => info!(
+            "Script job {job_id} finished with status {}",
+            status.as_str()
+        ),
+        Err(e) => warn!("Could not finish script job {job_id}: {e}"),
+    }
+}
+
+/// Fails the script jobs interrupted by the last shutdown and periodically deletes the jobs
+/// finished more than `SCRIPT_JOB_RETENTION` seconds ago.
+pub fn start_script_job_cleanup(pool: DbPool) {
+    let now = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
+    match Db::from_pool(&pool)
+        .and_then(|mut db| Ok(db.fail_pending_script_jobs_db("Interrupted by a restart", &now)?))
+    {
+        Ok(0) => {}
+        Ok(failed) => info!("Failed {failed} script jobs interrupted by the last shutdown"),
+        Err(e) => warn!("Could not fail the interrupted script jobs: {e}"),
+    }
+
+    tokio::task::spawn(async move {
+        let mut ticker = tokio::time::interval(SCRIPT_JOB_CLEANUP_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let cleanup_pool = pool.clone();
+            match tokio::task::spawn_blocking(move || delete_expired_script_jobs(&cleanup_pool))
+                .await
+            {
+                Ok(Ok(0)) => {}
+                Ok(Ok(deleted)) => info!("Deleted {deleted} expired script jobs"),
+                Ok(Err(e)) => warn!("Could not delete expired script jobs: {e}"),
+                Err(e) => warn!("Could not delete expired script jobs: {e}"),
+            }
+        }
+    });
+}
+
+fn delete_expired_script_jobs(pool: &DbPool) -> Result<usize, anyhow::Error> {
+    let retention = chrono::Duration::seconds(CONFIG.script_job_retention as i64);
+    let cutoff = Local::now().naive_local() - retention;
+    let mut db = Db::from_pool(pool)?;
+    let expired: Vec<String> = db
+        .get_finished_script_jobs()?
+        .into_iter()
+        .filter(|(_, updated_at)| {
+            NaiveDateTime::parse_from_str(updated_at, "%d-%m-%Y %H:%M:%S")
+                .map_or(true, |updated_at| updated_at < cutoff)
+        })
+        .map(|(job_id, _)| job_id)
+        .collect();
+    if expired.is_empty() {
+        return Ok(0);
+    }
+    Ok(db.delete_script_jobs_db(&expired)?)
+}
+
+fn record_script_job_tables(
+    pool: &DbPool,
+    job_id: &str,
+    tables_per_bridgehead: &HashMap<String, HashSet<String>>,
+) -> Result<(), anyhow::Error> {
+    let now = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
+    let tables = serde_json::to_string(tables_per_bridgehead)?;
+    Db::from_pool(pool)?.update_script_job_tables_db(job_id, &tables, &now)?;
+    Ok(())
+}
+
+/// Tables of the project per bridgehead, only asking the bridgeheads without cached tables.
+pub async fn fetch_project_tables_catalogue_request(
+    project_id: &str,
+    user_id: &str,
+    bridgehead_ids: Vec<String>,
+) -> Result<ProjectTablesResponse, anyhow::Error> {
+    let (mut bridgeheads, mut uncached) = (Vec::new(), Vec::new());
+    for bridgehead in bridgehead_ids {
+        match tables::cached_tables(project_id, &bridgehead) {
+            Some(cached) => bridgeheads.push(cached),
+            None => uncached.push(bridgehead),
+        }
+    }
+
+    let mut unavailable = Vec::new();
+    if !uncached.is_empty() {
+        let request = ProjectTablesRequest {
+            project: project_id.to_string(),
+            user: Some(user_id.to_string()),
+        };
+        let task = create_and_send_task_request(request, uncached.clone()).await?;
+        debug!("Fetch Project Tables Catalogue {task:#?}");
+
+        let tables_per_bridgehead = fetch_project_tables_from_beam(task).await?;
+        tables::store_tables(project_id, &tables_per_bridgehead);
+        for bridgehead in uncached {
+            match tables::cached_tables(project_id, &bridgehead) {
+                Some(fetched) => bridgeheads.push(fetched),
+                None => unavailable.push(bridgehead),
+            }
+        }
+    }
+    bridgeheads.sort_by(|a, b| a.bk.cmp(&b.bk));
+
+    Ok(ProjectTablesResponse {
+        project_id: project_id.to_string(),
+        bridgeheads,
+        unavailable,
+    })
+}
+
+/// Capabilities of the agents of the bridgeheads, only asking the bridgeheads without cached
+/// capabilities that are not being asked already. Agents that cannot answer the query are
+/// recorded as protocol version 1, bridgeheads that do not answer at all are not asked again for
+/// a while.
+pub async fn fetch_capabilities_request(
+    bridgehead_ids: Vec<String>,
+) -> Result<CapabilitiesResponse, anyhow::Error> {
+    let (mut bridgeheads, mut unavailable, mut uncached) = (Vec::new(), Vec::new(), Vec::new());
+    for bridgehead in bridgehead_ids {
+        match capabilities::cached(&bridgehead) {
+            Some(Some(cached)) => bridgeheads.push(cached),
+            Some(None) => unavailable.push(bridgehead),
+            None => uncached.push(bridgehead),
+        }
+    }
+
+    let (claim, in_flight) = capabilities::FetchClaim::claim(uncached);
+    if !claim.bridgeheads().is_empty() {
+        let task =
+            create_and_send_task_request(CapabilitiesRequest, claim.bridgeheads().to_vec()).await?;
+        debug!("Fetch Capabilities {task:#?}");
+        let progress = JobProgress::start(&task, OpalRequestType::CAPABILITIES, None, None);
+
+        let mut reported = capabilities_from_beam(task).await?;
+        for bridgehead in claim.bridgeheads().iter().cloned() {
+            match reported.remove(&bridgehead) {
+                Some(Some(site_capabilities)) => {
+                    progress.succeeded(&bridgehead);
+                    bridgeheads.push(capabilities::store_capabilities(
+                        &bridgehead,
+                        site_capabilities,
+                    ));
+                }
+                Some(None) => {
+                    progress.succeeded(&bridgehead);
+                    bridgeheads.push(capabilities::store_legacy(&bridgehead));
+                }
+                None => {
+                    progress.failed(&bridgehead, "No response from bridgehead");
+                    capabilities::store_unavailable(&bridgehead);
+                    unavailable.push(bridgehead);
+                }
+            }
+        }
+    }
+    drop(claim);
+
+    capabilities::wait_for_fetches(&in_flight).await;
+    for bridgehead in in_flight {
+        match capabilities::cached_capabilities(&bridgehead) {
+            Some(cached) => bridgeheads.push(cached),
+            None => unavailable.push(bridgehead),
+        }
+    }
+    bridgeheads.sort_by(|a, b| a.bk.cmp(&b.bk));
+
+    Ok(CapabilitiesResponse {
+        bridgeheads,
+        unavailable,
+    })
+}
+
+/// Checks the requested permissions of the token and that the agents of all bridgeheads support
+/// them. Agents without a feature would create an unrestricted token, so bridgeheads whose
+/// capabilities cannot be fetched are rejected as well.
+pub async fn validate_token_permissions(params: &TokenParams) -> Result<(), (StatusCode, String)> {
+    params
+        .permissions
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let required = params.permissions.required_features();
+    if required.is_empty() {
+        return Ok(());
+    }
+
+    let capabilities = fetch_capabilities_request(params.bridgehead_ids.clone())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if !capabilities.unavailable.is_empty() {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!(
+                "Could not check whether {:?} support the requested permissions",
+                capabilities.unavailable
+            ),
+        ));
+    }
+    let missing = capabilities::missing_features(&capabilities.bridgeheads, &required);
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let missing: Vec<_> = missing
+        .iter()
+        .map(|(bk, features)| {
+            let features: Vec<_> = features.iter().map(ToString::to_string).collect();
+            format!("{bk} lacks {}", features.join(", "))
+        })
+        .collect();
+    Err((
+        StatusCode::BAD_REQUEST,
+        format!(
+            "Not every bridgehead supports the requested permissions: {}",
+            missing.join("; ")
+        ),
+    ))
 }
 
 pub async fn check_project_status_request(
     query_params: ProjectQueryParams,
-) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
-    let mut response_json = json!({
-        "project_id": query_params.project_id.clone(),
-        "bk": query_params.bk.clone(),
-        "project_status": OpalTokenStatus::NOTFOUND,
-    });
+) -> Result<Json<ProjectStatusResponse>, (StatusCode, String)> {
+    let mut response = ProjectStatusResponse {
+        project_id: query_params.project_id.clone(),
+        bk: query_params.bk.clone(),
+        project_status: OpalProjectStatus::NOTFOUND,
+    };
 
-    let task = match create_and_send_task_request(
-        OpalRequestType::STATUS,
-        None,
-        Some(query_params.project_id.clone().to_string()),
-        Some(vec![query_params.bk.clone().to_string()]),
-        None,
-    )
-    .await
-    {
+    let request = ProjectStatusRequest {
+        project: query_params.project_id.clone(),
+    };
+    let task = match create_and_send_task_request(request, vec![query_params.bk.clone()]).await {
         Ok(result) => result,
         Err(e) => {
             warn!(
@@ -203,26 +1069,45 @@ pub async fn check_project_status_request(
                 query_params.bk,
                 e
             );
-            return Ok(Json(response_json))
+            return Ok(Json(response));
         }
     };
 
     debug!("Check Project Status  {task:#?}");
+    let progress = JobProgress::start(
+        &task,
+        OpalRequestType::STATUS,
+        None,
+        Some(&query_params.project_id),
+    );
 
-    let project_status_result = match status_from_beam(task).await {
+    let project_status_result = match first_result_from_beam(task).await {
         Ok(response) => Ok(response),
         Err(e) => Err(e),
     };
+    match &project_status_result {
+        Ok(response) => publish_opal_response(&progress, &query_params.bk, response),
+        Err(e) => progress.failed(&query_params.bk, e.to_string()),
+    }
 
     match project_status_result {
-        Ok(OpalResponse::Ok { response }) => {
+        Ok(OpalResponse::Ok {
+            response: project_status,
+        }) => {
             info!(
                 "Received status response for project. Project ID: {}, BK: {}, Response: {}",
-                query_params.project_id,
-                query_params.bk,
-                json!(response).to_string()
+                query_params.project_id, query_params.bk, project_status
             );
-            response_json["project_status"] = json!(response);
+            response.project_status = match project_status {
+                SiteValue::Known(status) => status,
+                SiteValue::Unknown(status) => {
+                    warn!(
+                        "BK {} reported unknown project status {status}",
+                        query_params.bk
+                    );
+                    OpalProjectStatus::ERROR
+                }
+            };
         }
         Ok(OpalResponse::Err {
             status_code,
@@ -239,35 +1124,27 @@ pub async fn check_project_status_request(
         Err(e) => {
             info!("Bridgehead: {}, Error retrieving project status: Failed to deserialize message", query_params.bk);
             debug!("Error retrieving project status: {:?}", e);
-            response_json["project_status"] = json!(OpalProjectStatus::ERROR);
+            response.project_status = OpalProjectStatus::ERROR;
         }
     };
 
-    Ok(Json(response_json))
+    Ok(Json(response))
 }
 
+/// Status of the token at the site. Tokens the site does not report as created are sent again
+/// from the database, so they are reported as created.
 pub async fn check_token_status_request(
     user_id: String,
     bridgehead: String,
     project: String,
     token_name: String,
     token: String,
-) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
-    let mut response_json = json!({
-        "user_id": user_id.clone(),
-        "bk": bridgehead.clone(),
-        "token_status": OpalTokenStatus::NOTFOUND,
-    });
-
-    let task = match create_and_send_task_request(
-        OpalRequestType::STATUS,
-        Some(token_name.clone().to_string()),
-        None,
-        Some(vec![bridgehead.clone().to_string()]),
-        None,
-    )
-    .await
-    {
+    permissions: TokenPermissions,
+) -> Result<OpalTokenStatus, (StatusCode, String)> {
+    let request = TokenStatusRequest {
+        name: token_name.clone(),
+    };
+    let task = match create_and_send_task_request(request, vec![bridgehead.clone()]).await {
         Ok(result) => result,
         Err(e) => {
             return Err((
@@ -278,31 +1155,59 @@ pub async fn check_token_status_request(
     };
 
     debug!("Check Token Status  {task:#?}");
+    let progress = JobProgress::start(
+        &task,
+        OpalRequestType::STATUS,
+        Some(&user_id),
+        Some(&project),
+    );
 
-    let token_status_result = match status_from_beam(task).await {
+    let token_status_result = match first_result_from_beam(task).await {
         Ok(response) => {
             debug!("Token Status response {response:#?}");
             Ok(response)
         }
         Err(e) => Err(e),
     };
+    match &token_status_result {
+        Ok(response) => publish_opal_response(&progress, &bridgehead, response),
+        Err(e) => progress.failed(&bridgehead, e.to_string()),
+    }
 
     match token_status_result {
+        Ok(OpalResponse::Ok {
+            response: SiteValue::Known(OpalTokenStatus::CREATED),
+        }) => Ok(OpalTokenStatus::CREATED),
         Ok(OpalResponse::Ok { response }) => {
-            response_json["token_status"] = json!(response);
+            match &response {
+                SiteValue::Known(OpalTokenStatus::EXPIRED) => {
+                    webhooks::notify(TokenEvent::new(
+                        TokenEventKind::Expired,
+                        Some(&user_id),
+                        &project,
+                        &bridgehead,
+                    ));
+                }
+                SiteValue::Unknown(status) => {
+                    warn!("BK {bridgehead} reported unknown status {status} of token {token_name}");
+                }
+                SiteValue::Known(_) => {}
+            }
 
-            if response == OpalTokenStatus::CREATED.as_str() {
-                response_json["token_status"] = json!(response);
-            } else {
-                let params = TokenParams {
-                    user_id: user_id.clone(),
-                    project_id: project.clone(),
-                    bridgehead_ids: vec![bridgehead.clone()],
-                };
-
-                send_token_from_db(params, token_name, token).await;
-                response_json["token_status"] = json!(OpalTokenStatus::CREATED.as_str());
+            // The site expired the token as requested, sending it again would revive it
+            if response == SiteValue::Known(OpalTokenStatus::EXPIRED) && permissions.has_expired() {
+                return Ok(OpalTokenStatus::EXPIRED);
             }
+
+            let params = TokenParams {
+                user_id: user_id.clone(),
+                project_id: project.clone(),
+                bridgehead_ids: vec![bridgehead.clone()],
+                permissions,
+            };
+
+            send_token_from_db(params, token_name, token).await;
+            Ok(OpalTokenStatus::CREATED)
         }
         Ok(OpalResponse::Err {
             status_code,
@@ -311,30 +1216,27 @@ pub async fn check_token_status_request(
             let status = StatusCode::from_u16(status_code as u16)
                 .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
             eprintln!("Token status error: {}, {}", status, error_message);
+            Ok(OpalTokenStatus::NOTFOUND)
         }
         Err(e) => {
             eprintln!("Error retrieving token status: {:?}", e);
-            return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+            Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
         }
-    };
-
-    Ok(Json(response_json))
+    }
 }
 
 async fn save_tokens_from_beam(
     mut db: Db,
-    task: TaskRequest<OpalRequest>,
+    task: OpalTaskRequest<CreateTokenRequest>,
     token_params: TokenParams,
     token_name: String,
+    progress: JobProgress,
 ) -> Result<()> {
     let today = Local::now();
     let formatted_date = today.format("%d-%m-%Y %H:%M:%S").to_string();
 
     let res = BEAM_CLIENT
-        .raw_beam_request(
-            Method::GET,
-            &format!("/v1/tasks/{}/results?wait_count={}", task.id, task.to.len()),
-        )
+        .raw_beam_request(Method::GET, &results_path(&task, task.wait_time()))
         .header(
             header::ACCEPT,
             HeaderValue::from_static("text/event-stream"),
@@ -350,6 +1252,7 @@ async fn save_tokens_from_beam(
     );
 
     let mut last_error: Option<String> = None;
+    let mut responded = HashSet::new();
 
     while let Some(Ok(Event::Message(msg))) = stream.next().await {
         let result: TaskResult<OpalResponse<String>> = match serde_json::from_slice(msg.data()) {
@@ -361,6 +1264,7 @@ async fn save_tokens_from_beam(
                 continue;
             }
         };
+        responded.insert(result.from.to_string());
 
         match result.body {
             OpalResponse::Err {
@@ -368,51 +1272,232 @@ async fn save_tokens_from_beam(
                 error_message,
             } => {
                 warn!("{} failed to create a token with status code: {status_code}, error: {error_message}", result.from);
+                progress.failed(result.from.as_ref(), &error_message);
+                webhooks::notify(
+                    TokenEvent::new(
+                        TokenEventKind::Failed,
+                        Some(&token_params.user_id),
+                        &token_params.project_id,
+                        result.from.as_ref(),
+                    )
+                    .with_error(&error_message),
+                );
                 last_error = Some(format!("Error: {error_message}"));
             }
             OpalResponse::Ok { response } => {
-                let encryp_token = encrypt_data(
-                    response.clone().as_bytes(),
-                    &token_name.clone().as_bytes()[..16],
-                );
-                let token_encoded = STANDARD.encode(encryp_token);
                 let site_name = result.from.as_ref();
-
-                let new_token = NewToken {
-                    token_name: &token_name,
-                    token: &token_encoded,
-                    project_id: &token_params.project_id,
-                    bk: site_name,
-                    token_status: OpalTokenStatus::CREATED.as_str(),
-                    project_status: OpalProjectStatus::CREATED.as_str(),
-                    user_id: &token_params.user_id,
-                    token_created_at: &formatted_date,
-                };
-                db.save_token_db(new_token);
+                store_created_token(
+                    &token_params,
+                    &token_name,
+                    site_name,
+                    &response,
+                    &formatted_date,
+                    |new_token| db.save_token_db(new_token),
+                );
+                progress.succeeded(site_name);
+                webhooks::notify(TokenEvent::new(
+                    TokenEventKind::Created,
+                    Some(&token_params.user_id),
+                    &token_params.project_id,
+                    site_name,
+                ));
             }
         }
     }
 
+    for bk in task.to.iter().filter(|bk| !responded.contains(bk.as_ref())) {
+        progress.failed(
+            bk.as_ref(),
+            "No response from bridgehead, retrying in the background",
+        );
+        outbox::enqueue_creation(
+            &mut db,
+            bk.as_ref(),
+            &token_params.user_id,
+            &token_params.project_id,
+            &token_name,
+            &token_params.permissions,
+        );
+    }
+
     if let Some(e) = last_error {
         warn!("Error processing task {}: {}", task.id, e);
     }
     Ok(())
 }
 
+/// Stores the token the site created for the user and project of `params` with its permissions
+/// using `save`.
+fn store_created_token<T>(
+    params: &TokenParams,
+    token_name: &str,
+    bridgehead: &str,
+    token: &str,
+    created_at: &str,
+    save: impl FnOnce(NewToken) -> T,
+) -> T {
+    let encryp_token = encrypt_data(token.as_bytes(), &token_name.as_bytes()[..16]);
+    let token_encoded = STANDARD.encode(encryp_token);
+    let scopes = serde_json::to_string(&params.permissions.scopes).unwrap_or_default();
+
+    let new_token = NewToken {
+        token_name,
+        token: &token_encoded,
+        project_id: &params.project_id,
+        bk: bridgehead,
+        token_status: OpalTokenStatus::CREATED,
+        project_status: OpalProjectStatus::CREATED,
+        user_id: &params.user_id,
+        token_created_at: created_at,
+        scopes: &scopes,
+        expires_at: params.permissions.expires_at.as_deref(),
+    };
+    save(new_token)
+}
+
+/// Sends the queued operation to its site and applies it locally once the site confirmed it.
+pub async fn send_outbox_entry(pool: &DbPool, entry: &OutboxEntry) -> Result<(), String> {
+    let project = entry.project_id.as_deref().unwrap_or_default();
+    match (entry.operation, &entry.token_name) {
+        (OutboxOperation::CreateToken, Some(token_name)) => {
+            let params = TokenParams {
+                user_id: entry.user_id.clone().unwrap_or_default(),
+                project_id: project.to_string(),
+                bridgehead_ids: vec![entry.bk.clone()],
+                permissions: entry.permissions()?,
+            };
+            let request = CreateTokenRequest {
+                name: token_name.clone(),
+                project: project.to_string(),
+                token: None,
+                permissions: params.permissions.clone(),
+            };
+            let token = match send_to_site(request, entry, Some(outbox::ALREADY_EXISTS)).await? {
+                Some(token) => token,
+                // An earlier attempt created the token, its value is unknown so it is replaced
+                None => {
+                    info!(
+                        "Token {token_name} in BK {} exists already, replacing its value",
+                        entry.bk
+                    );
+                    let request = UpdateTokenRequest {
+                        name: token_name.clone(),
+                        project: project.to_string(),
+                        token: Uuid::new_v4().simple().to_string(),
+                    };
+                    send_to_site(request, entry, None)
+                        .await?
+                        .unwrap_or_default()
+                }
+            };
+
+            let mut db = Db::from_pool(pool).map_err(|e| e.to_string())?;
+            let created_at = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
+            let stored = store_created_token(
+                &params,
+                token_name,
+                &entry.bk,
+                &token,
+                &created_at,
+                |new_token| db.save_outbox_token_db(entry.id, new_token),
+            )
+            .map_err(|e| e.to_string())?;
+            if !stored {
+                // Deleted or revoked while the creation was in flight
+                info!(
+                    "Token {token_name} in BK {} was revoked before it was created, deleting it",
+                    entry.bk
+                );
+                outbox::enqueue(
+                    &mut db,
+                    OutboxOperation::DeleteToken,
+                    &entry.bk,
+                    entry.user_id.as_deref(),
+                    entry.project_id.as_deref(),
+                    Some(token_name),
+                );
+                return Ok(());
+            }
+            webhooks::notify(TokenEvent::new(
+                TokenEventKind::Created,
+                Some(&params.user_id),
+                project,
+                &entry.bk,
+            ));
+        }
+        (OutboxOperation::DeleteToken, Some(token_name)) => {
+            let request = DeleteTokenRequest {
+                name: token_name.clone(),
+            };
+            send_to_site(request, entry, Some(outbox::MISSING)).await?;
+
+            let mut db = Db::from_pool(pool).map_err(|e| e.to_string())?;
+            db.set_token_status_at_bridgehead_db(token_name, &entry.bk, OpalTokenStatus::REVOKED);
+            webhooks::notify(TokenEvent::new(
+                TokenEventKind::Deleted,
+                entry.user_id.as_deref(),
+                project,
+                &entry.bk,
+            ));
+        }
+        (OutboxOperation::DeleteProject, _) => {
+            let request = DeleteProjectRequest {
+                project: project.to_string(),
+            };
+            send_to_site(request, entry, Some(outbox::MISSING)).await?;
+
+            let mut db = Db::from_pool(pool).map_err(|e| e.to_string())?;
+            db.set_project_tokens_status_db(project, &entry.bk, OpalTokenStatus::REVOKED);
+            webhooks::notify(TokenEvent::new(
+                TokenEventKind::Deleted,
+                None,
+                project,
+                &entry.bk,
+            ));
+        }
+        _ => warn!("Outbox entry {} has no token name", entry.id),
+    }
+    Ok(())
+}
+
+/// Sends the request of the outbox entry to its site and waits for the response, the
+/// `done_status` counts as confirmed without a response, see `outbox::confirmation`.
+async fn send_to_site<R: OpalTask>(
+    request: R,
+    entry: &OutboxEntry,
+    done_status: Option<i32>,
+) -> Result<Option<R::Response>, String> {
+    let task = create_and_send_task_request(request, vec![entry.bk.clone()])
+        .await
+        .map_err(|e| format!("Error creating task: {e}"))?;
+
+    let progress = JobProgress::start(
+        &task,
+        R::REQUEST_TYPE,
+        entry.user_id.as_deref(),
+        entry.project_id.as_deref(),
+    );
+    let outcome = outbox::confirmation(first_result_from_beam(task).await, done_status);
+    publish_outcome(
+        &progress,
+        &entry.bk,
+        &outcome.as_ref().map(|_| ()).map_err(Clone::clone),
+    );
+    outcome
+}
+
 async fn update_tokens_from_beam(
     mut db: Db,
-    task: TaskRequest<OpalRequest>,
+    task: OpalTaskRequest<UpdateTokenRequest>,
     token_params: TokenParams,
     token_name: String,
+    progress: JobProgress,
 ) -> Result<()> {
     let today = Local::now();
     let formatted_date = today.format("%d-%m-%Y %H:%M:%S").to_string();
 
     let res = BEAM_CLIENT
-        .raw_beam_request(
-            Method::GET,
-            &format!("/v1/tasks/{}/results?wait_count={}", task.id, task.to.len()),
-        )
+        .raw_beam_request(Method::GET, &results_path(&task, task.wait_time()))
         .header(
             header::ACCEPT,
             HeaderValue::from_static("text/event-stream"),
@@ -428,6 +1513,7 @@ async fn update_tokens_from_beam(
     );
 
     let mut last_error: Option<String> = None;
+    let mut responded = HashSet::new();
 
     while let Some(Ok(Event::Message(msg))) = stream.next().await {
         let result: TaskResult<OpalResponse<String>> = match serde_json::from_slice(msg.data()) {
@@ -439,6 +1525,7 @@ async fn update_tokens_from_beam(
                 continue;
             }
         };
+        responded.insert(result.from.to_string());
 
         match result.body {
             OpalResponse::Err {
@@ -446,6 +1533,16 @@ async fn update_tokens_from_beam(
                 error_message,
             } => {
                 warn!("{} failed to create a token with status code: {status_code}, error: {error_message}", result.from);
+                progress.failed(result.from.as_ref(), &error_message);
+                webhooks::notify(
+                    TokenEvent::new(
+                        TokenEventKind::Failed,
+                        Some(&token_params.user_id),
+                        &token_params.project_id,
+                        result.from.as_ref(),
+                    )
+                    .with_error(&error_message),
+                );
                 last_error = Some(format!("Error: {error_message}"));
             }
             OpalResponse::Ok { response } => {
@@ -456,35 +1553,40 @@ async fn update_tokens_from_beam(
                 let token_encoded = STANDARD.encode(encryp_token);
                 let site_name = result.from.as_ref();
 
-                let new_token = NewToken {
-                    token_name: &token_name,
-                    token: &token_encoded,
+                db.update_token_db(TokenUpdate {
+                    user_id: &token_params.user_id,
                     project_id: &token_params.project_id,
                     bk: site_name,
-                    token_status: OpalTokenStatus::CREATED.as_str(),
-                    project_status: OpalProjectStatus::CREATED.as_str(),
-                    user_id: &token_params.user_id,
+                    token: &token_encoded,
                     token_created_at: &formatted_date,
-                };
-                db.update_token_db(new_token);
+                });
+                progress.succeeded(site_name);
+                webhooks::notify(TokenEvent::new(
+                    TokenEventKind::Refreshed,
+                    Some(&token_params.user_id),
+                    &token_params.project_id,
+                    site_name,
+                ));
             }
         }
     }
 
+    for bk in task.to.iter().filter(|bk| !responded.contains(bk.as_ref())) {
+        progress.failed(bk.as_ref(), "No response from bridgehead");
+    }
+
     if let Some(e) = last_error {
         warn!("Error processing task {}: {}", task.id, e);
     }
     Ok(())
 }
 
-async fn status_from_beam(
-    task: TaskRequest<OpalRequest>,
-) -> Result<OpalResponse<String>, anyhow::Error> {
+/// Response of the first site that answered the task.
+async fn first_result_from_beam<R: OpalTask>(
+    task: OpalTaskRequest<R>,
+) -> Result<OpalResponse<R::Response>, anyhow::Error> {
     let res = BEAM_CLIENT
-        .raw_beam_request(
-            Method::GET,
-            &format!("/v1/tasks/{}/results?wait_count={}", task.id, task.to.len()),
-        )
+        .raw_beam_request(Method::GET, &results_path(&task, task.wait_time()))
         .header(
             header::ACCEPT,
             HeaderValue::from_static("text/event-stream"),
@@ -502,7 +1604,7 @@ async fn status_from_beam(
     let mut last_error: Option<String> = None;
 
     while let Some(Ok(Event::Message(msg))) = stream.next().await {
-        let result: TaskResult<OpalResponse<String>> = match serde_json::from_slice(msg.data()) {
+        let result: TaskResult<serde_json::Value> = match serde_json::from_slice(msg.data()) {
             Ok(v) => v,
             Err(e) => {
                 let error_msg = format!("Failed to deserialize message {msg:?} into a result: {e}");
@@ -511,15 +1613,33 @@ async fn status_from_beam(
                 continue;
             }
         };
+        let body: OpalResponse<R::Response> = match serde_json::from_value(result.body) {
+            Ok(body) => body,
+            Err(e) => {
+                let bk = result.from.as_ref();
+                let error_msg = format!(
+                    "{bk} answered the {} task with a response that could not be read, {}: {e}",
+                    R::REQUEST_TYPE,
+                    capabilities::incompatibility_hint(bk, R::REQUEST_TYPE)
+                );
+                warn!("{error_msg}");
+                if capabilities::needs_fetch(bk) {
+                    tokio::task::spawn(fetch_capabilities_request(vec![bk.to_string()]));
+                }
+                last_error = Some(error_msg);
+                continue;
+            }
+        };
 
-        match result.body {
+        match body {
             OpalResponse::Err {
                 status_code,
                 error_message,
             } => {
                 warn!(
-                    "{} failed to fecth project status code: {status_code}, error: {error_message}",
-                    result.from
+                    "{} failed {} task with status code: {status_code}, error: {error_message}",
+                    result.from,
+                    R::REQUEST_TYPE
                 );
                 return Ok(OpalResponse::Err {
                     status_code,
@@ -539,17 +1659,19 @@ async fn status_from_beam(
 }
 
 async fn fetch_project_tables_from_beam(
-    task: TaskRequest<OpalRequest>,
+    task: OpalTaskRequest<ProjectTablesRequest>,
+) -> Result<HashMap<String, HashSet<String>>, anyhow::Error> {
+    stream_project_tables_from_beam(task, |_, _, _| {}).await
+}
+
+/// Collects the tables reported by the bridgeheads, calling `on_result` with the outcome of
+/// every response and the tables collected so far.
+async fn stream_project_tables_from_beam(
+    task: OpalTaskRequest<ProjectTablesRequest>,
+    mut on_result: impl FnMut(&str, Result<(), String>, &HashMap<String, HashSet<String>>),
 ) -> Result<HashMap<String, HashSet<String>>, anyhow::Error> {
     let res = BEAM_CLIENT
-        .raw_beam_request(
-            Method::GET,
-            &format!(
-                "/v1/tasks/{}/results?wait_count={}&wait_time=30s",
-                task.id,
-                task.to.len()
-            ),
-        )
+        .raw_beam_request(Method::GET, &results_path(&task, task.wait_time()))
         .header(
             header::ACCEPT,
             HeaderValue::from_static("text/event-stream"),
@@ -584,6 +1706,11 @@ async fn fetch_project_tables_from_beam(
                     "status: {} from bk {} failed to fetch tables: {}",
                     status_code, result.from, error_message
                 );
+                on_result(
+                    result.from.as_ref(),
+                    Err(error_message),
+                    &tables_per_bridgehead,
+                );
                 continue;
             }
             OpalResponse::Ok { response } => {
@@ -593,6 +1720,7 @@ async fn fetch_project_tables_from_beam(
                 for table in response {
                     bridgehead_tables.insert(table.clone());
                 }
+                on_result(result.from.as_ref(), Ok(()), &tables_per_bridgehead);
             }
         };
     }
@@ -600,14 +1728,12 @@ async fn fetch_project_tables_from_beam(
     Ok(tables_per_bridgehead)
 }
 
-async fn remove_project_and_tokens_from_beam(
-    task: TaskRequest<OpalRequest>,
-) -> Result<OpalResponse<String>, anyhow::Error> {
+/// Capabilities reported per bridgehead, `None` for bridgeheads that answered without them.
+async fn capabilities_from_beam(
+    task: OpalTaskRequest<CapabilitiesRequest>,
+) -> Result<HashMap<String, Option<SiteCapabilities>>, anyhow::Error> {
     let res = BEAM_CLIENT
-        .raw_beam_request(
-            Method::GET,
-            &format!("/v1/tasks/{}/results?wait_count={}", task.id, task.to.len()),
-        )
+        .raw_beam_request(Method::GET, &results_path(&task, task.wait_time()))
         .header(
             header::ACCEPT,
             HeaderValue::from_static("text/event-stream"),
@@ -615,143 +1741,103 @@ async fn remove_project_and_tokens_from_beam(
         .send()
         .await
         .expect("Beam was reachable in the post request before this");
-
     let mut stream = async_sse::decode(
         res.bytes_stream()
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            .map_err(io::Error::other)
             .into_async_read(),
     );
 
-    let mut last_error: Option<String> = None;
-
+    let mut reported = HashMap::new();
     while let Some(Ok(Event::Message(msg))) = stream.next().await {
-        let result: TaskResult<OpalResponse<String>> = match serde_json::from_slice(msg.data()) {
+        let result: TaskResult<serde_json::Value> = match serde_json::from_slice(msg.data()) {
             Ok(v) => v,
             Err(e) => {
-                let error_msg = format!("Failed to deserialize message {msg:?} into a result: {e}");
-                debug!("{error_msg}");
-                last_error = Some(error_msg);
+                debug!("Failed to deserialize message {msg:?} into a result: {e}");
                 continue;
             }
         };
 
-        match result.body {
-            OpalResponse::Err {
+        let capabilities = match serde_json::from_value(result.body) {
+            Ok(OpalResponse::Ok { response }) => Some(response),
+            Ok(OpalResponse::Err {
                 status_code,
                 error_message,
-            } => {
-                warn!(
-                    "{} failed to fecth project status code: {status_code}, error: {error_message}",
+            }) => {
+                debug!(
+                    "{} rejected the capability query with status {status_code}: {error_message}",
                     result.from
                 );
-                return Ok(OpalResponse::Err {
-                    status_code,
-                    error_message,
-                });
-            }
-            OpalResponse::Ok { response } => {
-                return Ok(OpalResponse::Ok { response });
+                None
             }
-        }
-    }
-
-    match last_error {
-        Some(e) => Err(anyhow::Error::msg(e)),
-        None => Err(anyhow::Error::msg("No messages received or processed")),
-    }
-}
-
-async fn remove_tokens_from_beam(
-    task: TaskRequest<OpalRequest>,
-) -> Result<OpalResponse<String>, anyhow::Error> {
-    let res = BEAM_CLIENT
-        .raw_beam_request(
-            Method::GET,
-            &format!("/v1/tasks/{}/results?wait_count={}", task.id, task.to.len()),
-        )
-        .header(
-            header::ACCEPT,
-            HeaderValue::from_static("text/event-stream"),
-        )
-        .send()
-        .await
-        .expect("Beam was reachable in the post request before this");
-
-    let mut stream = async_sse::decode(
-        res.bytes_stream()
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
-            .into_async_read(),
-    );
-
-    let mut last_error: Option<String> = None;
-
-    while let Some(Ok(Event::Message(msg))) = stream.next().await {
-        let result: TaskResult<OpalResponse<String>> = match serde_json::from_slice(msg.data()) {
-            Ok(v) => v,
             Err(e) => {
-                let error_msg = format!("Failed to deserialize message {msg:?} into a result: {e}");
-                debug!("{error_msg}");
-                last_error = Some(error_msg);
-                continue;
-            }
-        };
-
-        match result.body {
-            OpalResponse::Err {
-                status_code,
-                error_message,
-            } => {
-                warn!(
-                    "{} failed to fecth project status code: {status_code}, error: {error_message}",
+                debug!(
+                    "{} answered the capability query unreadably: {e}",
                     result.from
                 );
-                return Ok(OpalResponse::Err {
-                    status_code,
-                    error_message,
-                });
+                None
             }
-            OpalResponse::Ok { response } => {
-                return Ok(OpalResponse::Ok { response });
-            }
-        }
+        };
+        reported.insert(result.from.as_ref().to_string(), capabilities);
     }
 
-    match last_error {
-        Some(e) => Err(anyhow::Error::msg(e)),
-        None => Err(anyhow::Error::msg("No messages received or processed")),
+    Ok(reported)
+}
+
+fn publish_outcome(progress: &JobProgress, bridgehead: &str, outcome: &Result<(), String>) {
+    match outcome {
+        Ok(()) => progress.succeeded(bridgehead),
+        Err(error) => progress.failed(bridgehead, error.clone()),
     }
 }
 
-async fn create_and_send_task_request(
-    request_type: OpalRequestType,
-    name: Option<String>,
-    project: Option<String>,
-    bridgeheads: Option<Vec<String>>,
-    token: Option<String>,
-) -> Result<TaskRequest<OpalRequest>, anyhow::Error> {
-    let bks: Vec<_> = bridgeheads
-        .unwrap_or_default()
-        .iter()
-        .map(|bridgehead_id| AppId::new_unchecked(bridgehead_id.to_string()))
-        .collect();
+fn publish_opal_response<T>(progress: &JobProgress, bridgehead: &str, response: &OpalResponse<T>) {
+    match response {
+        OpalResponse::Ok { .. } => progress.succeeded(bridgehead),
+        OpalResponse::Err {
+            status_code,
+            error_message,
+        } => progress.failed(bridgehead, format!("{status_code}: {error_message}")),
+    }
+}
 
-    let request = OpalRequest {
-        request_type: request_type.to_string(),
-        name,
-        project,
-        token,
-    };
+async fn create_and_send_task_request<R: OpalTask>(
+    request: R,
+    bridgeheads: Vec<String>,
+) -> Result<OpalTaskRequest<R>, anyhow::Error> {
+    // Bridgeheads that cannot answer are left out, they count as not responding
+    let (supported, outdated) = capabilities::partition_supported(&bridgeheads, R::REQUEST_TYPE);
+    for outdated in &outdated {
+        warn!(
+            "Not sending {} task to {} whose agent does not support it (protocol version {})",
+            R::REQUEST_TYPE,
+            outdated.bk,
+            outdated.protocol_version
+        );
+    }
+    if supported.is_empty() {
+        anyhow::bail!(
+            "No bridgehead of {bridgeheads:?} supports {} requests",
+            R::REQUEST_TYPE
+        );
+    }
+    let bks: Vec<_> = supported.into_iter().map(AppId::new_unchecked).collect();
 
+    let settings = TaskSettings::for_request_type(R::REQUEST_TYPE);
     let task = TaskRequest {
         id: MsgId::new(),
         from: CONFIG.beam_id.clone(),
         to: bks,
-        body: request,
-        ttl: "60s".into(),
-        failure_strategy: beam_lib::FailureStrategy::Discard,
+        body: request.into(),
+        ttl: settings.ttl.clone(),
+        failure_strategy: settings.failure_strategy.into(),
         metadata: serde_json::Value::Null,
     };
+    debug!(
+        "Sending {} task {} with {settings:?}",
+        R::REQUEST_TYPE,
+        task.id
+    );
 
     BEAM_CLIENT.post_task(&task).await?;
-    Ok(task)
+    Ok(OpalTaskRequest::new(task, settings))
 }
@@ -0,0 +1,307 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use axum::http::StatusCode;
+use beam_lib::{AppId, BlockingOptions, MsgId, TaskRequest, TaskResult, WorkStatus};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{debug, info, warn};
+
+use crate::config::{BEAM_CLIENT, CONFIG};
+use crate::db::{Db, DbPool};
+use crate::enums::{ScriptFormat, SiteChange};
+use crate::handlers::{
+    apply_site_change, provision_missing_tokens, send_token_registration_request,
+    validate_token_permissions,
+};
+use crate::models::{JobAcceptedResponse, ScriptResponse, TokenParams, TokensQueryParams};
+use crate::sites;
+
+/// Time a poll waits for a task before polling again.
+const POLL_WAIT: Duration = Duration::from_secs(60);
+/// Time the worker waits after Beam could not be polled.
+const ERROR_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Tasks being answered, Beam hands them out again until the result is stored.
+static IN_PROGRESS: Lazy<Mutex<HashSet<MsgId>>> = Lazy::new(Default::default);
+
+/// Request of another Beam app, the body of the tasks addressed to `BEAM_ID`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "request_type")]
+pub enum InboundRequest {
+    /// Requests the tokens of the user for the project from the bridgeheads
+    #[serde(rename = "CREATE")]
+    CreateToken(TokenParams),
+    /// Status of the token of the user for the project at the bridgehead
+    #[serde(rename = "STATUS")]
+    TokenStatus(TokensQueryParams),
+    /// Change made directly at the site of the sending bridgehead, accepted from the bridgeheads
+    /// in the site registry or with stored tokens if `SITE_NOTIFICATIONS` is set
+    #[serde(rename = "NOTIFY")]
+    SiteNotification {
+        change: SiteChange,
+        /// Name of the token for token changes
+        name: Option<String>,
+        /// Project for project changes
+        project: Option<String>,
+    },
+    /// Script with the tokens of the user for the project at the bridgeheads
+    #[serde(rename = "SCRIPT")]
+    Script {
+        #[serde(flatten)]
+        params: TokenParams,
+        #[serde(default)]
+        format: ScriptFormat,
+        /// Requests missing tokens first, see `SCRIPT_PROVISION_TIMEOUT`
+        #[serde(default)]
+        provision: bool,
+    },
+}
+
+/// Body of the results, shaped like the responses of the sites to the tasks of this app.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum InboundResponse {
+    Err {
+        status_code: u16,
+        error_message: String,
+    },
+    Ok {
+        response: serde_json::Value,
+    },
+}
+
+impl InboundResponse {
+    fn ok(response: impl Serialize) -> Self {
+        match serde_json::to_value(response) {
+            Ok(response) => InboundResponse::Ok { response },
+            Err(e) => InboundResponse::error(StatusCode::INTERNAL_SERVER_ERROR, e),
+        }
+    }
+
+    fn error(status: StatusCode, message: impl ToString) -> Self {
+        InboundResponse::Err {
+            status_code: status.as_u16(),
+            error_message: message.to_string(),
+        }
+    }
+}
+
+impl InboundRequest {
+    /// Bridgeheads may only notify about changes at their own site, which the sender is taken as.
+    /// Only apps registered as sites or tokens were stored for count as bridgeheads.
+    fn is_allowed_from(&self, app: &AppId, pool: &DbPool) -> bool {
+        match self {
+            InboundRequest::SiteNotification { .. } => {
+                CONFIG.site_notifications && is_known_bridgehead(app, pool)
+            }
+            _ => CONFIG.inbound_allowed_apps.contains(app),
+        }
+    }
+}
+
+fn is_known_bridgehead(app: &AppId, pool: &DbPool) -> bool {
+    if sites::registry().is_registered(app.as_ref()) {
+        return true;
+    }
+    match Db::from_pool(pool).and_then(|mut db| Ok(db.is_known_bridgehead(app.as_ref())?)) {
+        Ok(known) => known,
+        Err(e) => {
+            warn!("Could not check whether {app} is a bridgehead: {e}");
+            false
+        }
+    }
+}
+
+/// Polls Beam for tasks of the apps listed in `INBOUND_ALLOWED_APPS` and for notifications of the
+/// bridgeheads if `SITE_NOTIFICATIONS` is set.
+pub fn start_worker(pool: DbPool) {
+    if CONFIG.inbound_allowed_apps.is_empty() && !CONFIG.site_notifications {
+        info!("Inbound Beam tasks are disabled");
+        return;
+    }
+    info!(
+        "Accepting Beam tasks from {:?}",
+        CONFIG.inbound_allowed_apps
+    );
+
+    tokio::task::spawn(async move {
+        let blocking = BlockingOptions {
+            wait_time: Some(POLL_WAIT),
+            wait_count: Some(1),
+        };
+        loop {
+            let tasks = match BEAM_CLIENT
+                .poll_pending_tasks::<serde_json::Value>(&blocking)
+                .await
+            {
+                Ok(tasks) => tasks,
+                Err(e) => {
+                    warn!("Failed to poll Beam for inbound tasks: {e}");
+                    tokio::time::sleep(ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+            for task in tasks {
+                if IN_PROGRESS.lock().unwrap().insert(task.id) {
+                    tokio::task::spawn(answer(pool.clone(), task));
+                }
+            }
+        }
+    });
+}
+
+async fn answer(pool: DbPool, task: TaskRequest<serde_json::Value>) {
+    let response = match serde_json::from_value::<InboundRequest>(task.body.clone()) {
+        Ok(request) if !request.is_allowed_from(&task.from, &pool) => {
+            warn!(
+                "Rejected task {} of {}, it is not allowed to send {request:?}",
+                task.id, task.from
+            );
+            InboundResponse::error(StatusCode::FORBIDDEN, "Not allowed to send this request")
+        }
+        Ok(request) => {
+            info!("Answering task {} of {}: {request:?}", task.id, task.from);
+            handle(&pool, &task, request).await
+        }
+        Err(e) => {
+            debug!("Task {} of {} has an invalid body: {e}", task.id, task.from);
+            InboundResponse::error(StatusCode::BAD_REQUEST, format!("Invalid request: {e}"))
+        }
+    };
+
+    let status = match response {
+        InboundResponse::Ok { .. } => WorkStatus::Succeeded,
+        InboundResponse::Err { .. } => WorkStatus::PermFailed,
+    };
+    let result = TaskResult {
+        from: CONFIG.beam_id.clone(),
+        to: vec![task.from.clone()],
+        task: task.id,
+        status,
+        body: response,
+        metadata: serde_json::Value::Null,
+    };
+    if let Err(e) = BEAM_CLIENT.put_result(&result, &task.id).await {
+        warn!("Failed to answer task {} of {}: {e}", task.id, task.from);
+    }
+    IN_PROGRESS.lock().unwrap().remove(&task.id);
+}
+
+async fn handle(
+    pool: &DbPool,
+    task: &TaskRequest<serde_json::Value>,
+    request: InboundRequest,
+) -> InboundResponse {
+    // Connections are only taken once needed, not across the waits for the sites
+    let connect = || {
+        Db::from_pool(pool)
+            .map_err(|e| InboundResponse::error(StatusCode::INTERNAL_SERVER_ERROR, e))
+    };
+    match request {
+        InboundRequest::CreateToken(params) => {
+            if let Err(e) = validate_params(&params) {
+                return InboundResponse::error(StatusCode::BAD_REQUEST, e);
+            }
+            if let Err((status, message)) = validate_token_permissions(&params).await {
+                return InboundResponse::error(status, message);
+            }
+            let db = match connect() {
+                Ok(db) => db,
+                Err(response) => return response,
+            };
+            match send_token_registration_request(db, params).await {
+                Ok(Some(job_id)) => InboundResponse::ok(JobAcceptedResponse {
+                    message: "Token creation was sent to the bridgeheads".to_string(),
+                    job_id: Some(job_id),
+                }),
+                Ok(None) => InboundResponse::ok(JobAcceptedResponse {
+                    message: "Token is already available".to_string(),
+                    job_id: None,
+                }),
+                Err(e) => InboundResponse::error(StatusCode::INTERNAL_SERVER_ERROR, e),
+            }
+        }
+        InboundRequest::TokenStatus(params) => {
+            let mut db = match connect() {
+                Ok(db) => db,
+                Err(response) => return response,
+            };
+            match db.check_token_status(params).await {
+                Ok(status) => InboundResponse::ok(status.0),
+                Err((status, message)) => InboundResponse::error(status, message),
+            }
+        }
+        InboundRequest::SiteNotification {
+            change,
+            name,
+            project,
+        } => {
+            let db = match connect() {
+                Ok(db) => db,
+                Err(response) => return response,
+            };
+            match apply_site_change(
+                db,
+                task.from.as_ref(),
+                &task.id.to_string(),
+                change,
+                name.as_deref(),
+                project.as_deref(),
+            ) {
+                Ok(tokens_updated) => {
+                    InboundResponse::ok(json!({ "tokens_updated": tokens_updated }))
+                }
+                Err((status, message)) => InboundResponse::error(status, message),
+            }
+        }
+        InboundRequest::Script {
+            params,
+            format,
+            provision,
+        } => {
+            if let Err(e) = validate_params(&params) {
+                return InboundResponse::error(StatusCode::BAD_REQUEST, e);
+            }
+            if provision {
+                if let Err((status, message)) = validate_token_permissions(&params).await {
+                    return InboundResponse::error(status, message);
+                }
+                if let Err(e) = provision_missing_tokens(pool, &params).await {
+                    return InboundResponse::error(StatusCode::INTERNAL_SERVER_ERROR, e);
+                }
+            }
+            let mut db = match connect() {
+                Ok(db) => db,
+                Err(response) => return response,
+            };
+            match db.generate_user_script(params, format).await {
+                Ok(script) => InboundResponse::ok(ScriptResponse { script, format }),
+                Err(e) => InboundResponse::error(StatusCode::INTERNAL_SERVER_ERROR, e),
+            }
+        }
+    }
+}
+
+/// Checks that the user, project and bridgeheads are given and the bridgeheads are Beam AppIds
+/// like `app.proxy.broker`, tasks to other ids would never be answered.
+fn validate_params(params: &TokenParams) -> Result<(), String> {
+    if params.user_id.trim().is_empty() {
+        return Err("The user_id must not be empty".to_string());
+    }
+    if params.project_id.trim().is_empty() {
+        return Err("The project_id must not be empty".to_string());
+    }
+    if params.bridgehead_ids.is_empty() {
+        return Err("At least one bridgehead is required".to_string());
+    }
+    for bridgehead in &params.bridgehead_ids {
+        let segments: Vec<_> = bridgehead.split('.').collect();
+        if segments.len() < 3 || segments.iter().any(|segment| segment.is_empty()) {
+            return Err(format!("{bridgehead:?} is not a Beam AppId"));
+        }
+    }
+    Ok(())
+}
@@ -1,11 +1,26 @@
+mod auth;
+mod capabilities;
 mod config;
 mod db;
+mod downloads;
 mod enums;
+mod events;
 mod handlers;
+mod inbound;
 mod models;
+mod opal;
+mod openapi;
+mod outbox;
+mod reconcile;
 mod routes;
+mod routes_v2;
 mod schema;
+mod sites;
+mod tables;
+mod tasks;
+mod templates;
 mod utils;
+mod webhooks;
 
 use crate::config::CONFIG;
 use axum::Router;
@@ -27,7 +42,18 @@ async fn main() -> anyhow::Result<()> {
     tracing::subscriber::set_global_default(subscriber)?;
 
     info!("Starting server token ON!");
-    let app = Router::new().nest("/api", configure_routes(db::setup_db()?));
+    sites::init_registry()?;
+    let pool = db::setup_db()?;
+    // Resumes the site operations that were not confirmed before the last shutdown
+    outbox::start_worker(pool.clone());
+    reconcile::start_scheduler(pool.clone());
+    webhooks::start_dispatcher(pool.clone());
+    inbound::start_worker(pool.clone());
+    handlers::start_script_job_cleanup(pool.clone());
+
+    let app = Router::new()
+        .nest("/api", configure_routes(pool))
+        .merge(openapi::configure_docs());
 
     axum::serve(TcpListener::bind(&CONFIG.addr).await?, app.into_make_service())
         .with_graceful_shutdown(async {
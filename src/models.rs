@@ -1,12 +1,76 @@
-use crate::schema::tokens;
+use crate::schema::{
+    download_links, outbox, project_templates, reconciliation_runs, script_jobs, script_templates,
+    site_notifications, tokens, user_keys, webhook_deliveries, webhooks,
+};
+use chrono::{Local, NaiveDate};
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use utoipa::{IntoParams, ToSchema};
 
-#[derive(Debug, Deserialize, Clone)]
+use crate::enums::{
+    DriftKind, OpalProjectStatus, OpalRequestType, OpalTokenStatus, OutboxOperation, OutboxStatus,
+    ScriptFormat, ScriptJobStatus, SiteFeature, TokenEventKind, TokenScope, WebhookDeliveryStatus,
+};
+use crate::tasks::TaskSettings;
+
+#[derive(Debug, Deserialize, Clone, ToSchema)]
 pub struct TokenParams {
     pub user_id: String,
     pub project_id: String,
     pub bridgehead_ids: Vec<String>,
+    #[serde(flatten)]
+    pub permissions: TokenPermissions,
+}
+
+/// Scopes and expiry of a token, sent to the sites when the token is created.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct TokenPermissions {
+    /// Scopes restricting the token, unrestricted if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<TokenScope>,
+    /// Last day the token is valid as `YYYY-MM-DD`, valid until revoked if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>,
+}
+
+impl TokenPermissions {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(expires_at) = &self.expires_at {
+            let expiry = NaiveDate::parse_from_str(expires_at, "%Y-%m-%d").map_err(|e| {
+                format!("Invalid expiry date {expires_at:?}, expected YYYY-MM-DD: {e}")
+            })?;
+            if expiry < Local::now().date_naive() {
+                return Err(format!("Expiry date {expires_at} is in the past"));
+            }
+        }
+        for scope in &self.scopes {
+            if matches!(scope, TokenScope::Tables(tables) if tables.is_empty()) {
+                return Err("The TABLES scope needs at least one table".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Features the sites need to create a token with these permissions.
+    pub fn required_features(&self) -> Vec<SiteFeature> {
+        let mut features = Vec::new();
+        if !self.scopes.is_empty() {
+            features.push(SiteFeature::TokenScopes);
+        }
+        if self.expires_at.is_some() {
+            features.push(SiteFeature::TokenExpiry);
+        }
+        features
+    }
+
+    /// Whether the expiry date has passed.
+    pub fn has_expired(&self) -> bool {
+        self.expires_at
+            .as_deref()
+            .and_then(|expires_at| NaiveDate::parse_from_str(expires_at, "%Y-%m-%d").ok())
+            .is_some_and(|expiry| expiry < Local::now().date_naive())
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -15,14 +79,6 @@ pub struct ScriptParams {
     pub user_id: String,
 }
 
-#[derive(Serialize, Debug)]
-pub struct OpalRequest {
-    pub request_type: String,
-    pub name: Option<String>,
-    pub project: Option<String>,
-    pub token: Option<String>,
-}
-
 #[derive(Debug, Serialize, Deserialize, Queryable, Selectable)]
 #[diesel(table_name = crate::schema::tokens)]
 #[diesel(check_for_backend(diesel::sqlite::Sqlite))]
@@ -31,11 +87,30 @@ pub struct TokenManager {
     pub token_name: String,
     pub token: String,
     pub project_id: String,
-    pub project_status: String,
+    pub project_status: OpalProjectStatus,
     pub bk: String,
-    pub token_status: String,
+    pub token_status: OpalTokenStatus,
     pub user_id: String,
     pub token_created_at: String,
+    /// JSON array of the scopes of the token
+    pub scopes: String,
+    pub expires_at: Option<String>,
+}
+
+impl TokenManager {
+    /// Permissions the token was created with, an error if the stored scopes are corrupt.
+    pub fn permissions(&self) -> Result<TokenPermissions, String> {
+        let scopes = serde_json::from_str(&self.scopes).map_err(|e| {
+            format!(
+                "Stored scopes of token {} in BK {} are corrupt: {e}",
+                self.token_name, self.bk
+            )
+        })?;
+        Ok(TokenPermissions {
+            scopes,
+            expires_at: self.expires_at.clone(),
+        })
+    }
 }
 
 #[derive(Insertable)]
@@ -44,11 +119,23 @@ pub struct NewToken<'a> {
     pub token_name: &'a str,
     pub token: &'a str,
     pub project_id: &'a str,
-    pub project_status: &'a str,
+    pub project_status: OpalProjectStatus,
     pub bk: &'a str,
-    pub token_status: &'a str,
+    pub token_status: OpalTokenStatus,
     pub user_id: &'a str,
     pub token_created_at: &'a str,
+    pub scopes: &'a str,
+    pub expires_at: Option<&'a str>,
+}
+
+/// New value of the latest token of the user and project at the bridgehead, its permissions
+/// are kept.
+pub struct TokenUpdate<'a> {
+    pub user_id: &'a str,
+    pub project_id: &'a str,
+    pub bk: &'a str,
+    pub token: &'a str,
+    pub token_created_at: &'a str,
 }
 
 #[derive(Insertable)]
@@ -56,19 +143,890 @@ pub struct NewToken<'a> {
 pub struct TokenStatus<'a> {
     pub project_id: &'a str,
     pub bk: &'a str,
-    pub token_status: &'a str,
+    pub token_status: OpalTokenStatus,
     pub user_id: &'a str,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, IntoParams)]
 pub struct TokensQueryParams {
     pub user_id: String,
     pub bk: String,
     pub project_id: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, IntoParams)]
 pub struct ProjectQueryParams {
     pub bk: String,
     pub project_id: String,
 }
+
+#[derive(Deserialize, Debug, IntoParams)]
+pub struct UserQueryParams {
+    pub user_id: String,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct RevocationParams {
+    /// Bridgehead whose tokens are revoked; all bridgeheads if omitted
+    pub bk: Option<String>,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct BridgeheadParams {
+    pub bk: String,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct BridgeheadIdsBody {
+    pub bridgehead_ids: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct TokenCreationBody {
+    pub bridgehead_ids: Vec<String>,
+    #[serde(flatten)]
+    pub permissions: TokenPermissions,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+pub struct BridgeheadIdsQuery {
+    /// Comma separated list of bridgehead ids
+    pub bridgehead_ids: String,
+}
+
+impl BridgeheadIdsQuery {
+    pub fn bridgehead_ids(&self) -> Vec<String> {
+        split_bridgehead_ids(&self.bridgehead_ids)
+    }
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+pub struct ProjectTablesQuery {
+    /// User the tables are listed for
+    pub user_id: String,
+    /// Comma separated list of bridgehead ids
+    pub bridgehead_ids: String,
+}
+
+impl ProjectTablesQuery {
+    pub fn bridgehead_ids(&self) -> Vec<String> {
+        split_bridgehead_ids(&self.bridgehead_ids)
+    }
+}
+
+fn split_bridgehead_ids(bridgehead_ids: &str) -> Vec<String> {
+    bridgehead_ids
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(ToString::to_string)
+        .collect()
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct ProjectTablesResponse {
+    pub project_id: String,
+    pub bridgeheads: Vec<BridgeheadTables>,
+    /// Bridgeheads that did not report their tables
+    pub unavailable: Vec<String>,
+}
+
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct BridgeheadTables {
+    pub bk: String,
+    pub tables: Vec<String>,
+    pub fetched_at: String,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct CapabilitiesResponse {
+    pub bridgeheads: Vec<BridgeheadCapabilities>,
+    /// Bridgeheads that did not respond, recently or now
+    pub unavailable: Vec<String>,
+}
+
+/// Protocol version and request types the Opal agent of a bridgehead supports.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct BridgeheadCapabilities {
+    pub bk: String,
+    /// 1 for agents that predate capability queries
+    pub protocol_version: u32,
+    pub request_types: Vec<OpalRequestType>,
+    /// Request types reported by the agent that this version does not know
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unknown_request_types: Vec<String>,
+    pub features: Vec<SiteFeature>,
+    /// Features reported by the agent that this version does not know
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unknown_features: Vec<String>,
+    pub fetched_at: String,
+}
+
+impl BridgeheadCapabilities {
+    pub fn supports(&self, request_type: OpalRequestType) -> bool {
+        self.request_types.contains(&request_type)
+    }
+
+    pub fn has_feature(&self, feature: SiteFeature) -> bool {
+        self.features.contains(&feature)
+    }
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct TokenAvailabilityResponse {
+    pub available: bool,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+pub struct ScriptFormatQuery {
+    /// Output format of the script, defaults to `r`
+    #[serde(default)]
+    pub format: ScriptFormat,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+pub struct ScriptProvisionQuery {
+    /// Request tokens from the bridgeheads without one and wait for them before generating the script
+    #[serde(default)]
+    pub provision: bool,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+pub struct ScriptEncryptionQuery {
+    /// Encrypt the script to the public key registered for the user
+    #[serde(default)]
+    pub encrypt: bool,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct ScriptResponse {
+    pub script: String,
+    pub format: ScriptFormat,
+}
+
+/// Connection details of one login row of the generated script. Sites whose tables have
+/// several prefixes get one row per prefix.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "PascalCase")]
+pub struct SiteCredentials {
+    #[serde(rename = "Bridgehead")]
+    pub bk: String,
+    pub site_name: String,
+    #[serde(rename = "URL")]
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// Prefix of the tables the login row is used for
+    pub project_name: String,
+    pub token: String,
+    /// Tables with the prefix of the row
+    pub tables: Vec<String>,
+    /// Whether the tables were reported by the site; if not, the project id is used as prefix
+    pub tables_available: bool,
+}
+
+/// Per-site records a script is generated from.
+#[derive(Serialize, Debug, Default, ToSchema)]
+pub struct ScriptCredentials {
+    pub user_id: String,
+    pub project_id: String,
+    pub generated_at: String,
+    pub sites: Vec<SiteCredentials>,
+    /// Bridgeheads for which no token is available
+    pub missing: Vec<String>,
+    /// Bridgeheads that did not report the tables of the project
+    pub tables_unavailable: Vec<String>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct ProjectStatusResponse {
+    pub project_id: String,
+    pub bk: String,
+    pub project_status: OpalProjectStatus,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct TokenStatusResponse {
+    pub project_id: String,
+    pub bk: String,
+    pub user_id: String,
+    pub token_created_at: String,
+    pub project_status: OpalProjectStatus,
+    pub token_status: OpalTokenStatus,
+    #[serde(flatten)]
+    pub permissions: TokenPermissions,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct OffboardingReport {
+    pub user_id: String,
+    pub bridgeheads: Vec<BridgeheadRevocationReport>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct BridgeheadRevocationReport {
+    pub bk: String,
+    /// Projects whose token was confirmed deleted by the bridgehead
+    pub revoked: Vec<String>,
+    pub outstanding: Vec<OutstandingToken>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct OutstandingToken {
+    pub project_id: String,
+    pub error: String,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct UserStatusResponse {
+    pub user_id: String,
+    pub outstanding: Vec<UserToken>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct UserToken {
+    pub bk: String,
+    pub project_id: String,
+    pub token_status: OpalTokenStatus,
+    #[serde(flatten)]
+    pub permissions: TokenPermissions,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct RevocationResponse {
+    /// Number of tokens marked for revocation
+    pub marked: usize,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct RevocationProgress {
+    pub bridgeheads: Vec<BridgeheadRevocationProgress>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct BridgeheadRevocationProgress {
+    pub bk: String,
+    pub pending: usize,
+    pub revoked: usize,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct ReprovisionReport {
+    pub bk: String,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub tokens: Vec<ReprovisionedToken>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct ReprovisionedToken {
+    pub user_id: String,
+    pub project_id: String,
+    pub token_name: String,
+    pub token_status: OpalTokenStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct ReconciliationReport {
+    pub id: i32,
+    pub started_at: String,
+    pub finished_at: String,
+    /// Number of active tokens that were checked
+    pub checked: usize,
+    pub in_sync: usize,
+    pub drifts: Vec<TokenDrift>,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct TokenDrift {
+    /// Missing for tokens unknown locally
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    pub project_id: String,
+    pub bk: String,
+    pub token_name: String,
+    pub kind: DriftKind,
+    /// Status or error reported by the site
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub site_status: Option<String>,
+    /// Whether the token was re-created at the site
+    pub repaired: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Queryable, Selectable)]
+#[diesel(table_name = reconciliation_runs)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct ReconciliationRun {
+    pub id: i32,
+    pub started_at: String,
+    pub finished_at: String,
+    pub checked: i32,
+    pub in_sync: i32,
+    /// JSON list of the drifts found
+    pub drifts: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = reconciliation_runs)]
+pub struct NewReconciliationRun<'a> {
+    pub started_at: &'a str,
+    pub finished_at: &'a str,
+    pub checked: i32,
+    pub in_sync: i32,
+    pub drifts: &'a str,
+}
+
+impl TryFrom<ReconciliationRun> for ReconciliationReport {
+    type Error = serde_json::Error;
+
+    fn try_from(run: ReconciliationRun) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: run.id,
+            started_at: run.started_at,
+            finished_at: run.finished_at,
+            checked: run.checked as usize,
+            in_sync: run.in_sync as usize,
+            drifts: serde_json::from_str(&run.drifts)?,
+        })
+    }
+}
+
+/// Audit entry of a change a bridgehead reported for its site.
+#[derive(Debug, Serialize, Queryable, Selectable, ToSchema)]
+#[diesel(table_name = site_notifications)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct SiteNotification {
+    pub id: i32,
+    /// Id of the Beam task the notification arrived with
+    pub task_id: String,
+    pub bk: String,
+    pub kind: String,
+    pub token_name: Option<String>,
+    pub project_id: Option<String>,
+    /// Number of local tokens whose status changed
+    pub tokens_updated: i32,
+    pub received_at: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = site_notifications)]
+pub struct NewSiteNotification<'a> {
+    pub task_id: &'a str,
+    pub bk: &'a str,
+    pub kind: &'a str,
+    pub token_name: Option<&'a str>,
+    pub project_id: Option<&'a str>,
+    pub tokens_updated: i32,
+    pub received_at: &'a str,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+pub struct SiteNotificationQueryParams {
+    /// Only notifications of this bridgehead
+    pub bk: Option<String>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct JobAcceptedResponse {
+    pub message: String,
+    /// Id to follow the progress at `/api/events`; absent if there was nothing to do
+    pub job_id: Option<String>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct MessageResponse {
+    pub message: String,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+/// Token lifecycle event, sent as payload to the registered webhooks.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct TokenEvent {
+    pub event: TokenEventKind,
+    pub user_id: Option<String>,
+    pub project_id: String,
+    pub bk: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub occurred_at: String,
+}
+
+impl TokenEvent {
+    pub fn new(event: TokenEventKind, user_id: Option<&str>, project_id: &str, bk: &str) -> Self {
+        Self {
+            event,
+            user_id: user_id.map(ToString::to_string),
+            project_id: project_id.to_string(),
+            bk: bk.to_string(),
+            error: None,
+            occurred_at: chrono::Utc::now().to_rfc3339(),
+        }
+    }
+
+    pub fn with_error(mut self, error: impl Into<String>) -> Self {
+        self.error = Some(error.into());
+        self
+    }
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct WebhookParams {
+    pub url: String,
+    /// Shared secret used to sign the payload with HMAC-SHA256
+    pub secret: String,
+    /// Events the webhook is subscribed to; all events if empty
+    #[serde(default)]
+    pub events: Vec<TokenEventKind>,
+}
+
+#[derive(Debug, Clone, Serialize, Queryable, Selectable, ToSchema)]
+#[diesel(table_name = crate::schema::webhooks)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    /// Comma separated list of subscribed events; empty for all events
+    pub events: String,
+    pub created_at: String,
+}
+
+impl Webhook {
+    pub fn is_subscribed(&self, event: TokenEventKind) -> bool {
+        self.events.is_empty() || self.events.split(',').any(|e| e == event.as_str())
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = webhooks)]
+pub struct NewWebhook<'a> {
+    pub url: &'a str,
+    pub secret: &'a str,
+    pub events: &'a str,
+    pub created_at: &'a str,
+}
+
+#[derive(Debug, Serialize, Queryable, Selectable, ToSchema)]
+#[diesel(table_name = crate::schema::webhook_deliveries)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct WebhookDelivery {
+    pub id: i32,
+    pub webhook_id: i32,
+    pub event: String,
+    pub payload: String,
+    pub status: WebhookDeliveryStatus,
+    pub attempts: i32,
+    pub response_code: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = webhook_deliveries)]
+pub struct NewWebhookDelivery<'a> {
+    pub webhook_id: i32,
+    pub event: &'a str,
+    pub payload: &'a str,
+    pub status: WebhookDeliveryStatus,
+    pub attempts: i32,
+    pub created_at: &'a str,
+    pub updated_at: &'a str,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+pub struct WebhookQueryParams {
+    pub id: i32,
+}
+
+/// Operation at a site that is retried until the site confirmed it or an admin abandoned it.
+#[derive(Debug, Clone, Serialize, Queryable, Selectable, ToSchema)]
+#[diesel(table_name = crate::schema::outbox)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct OutboxEntry {
+    pub id: i32,
+    pub operation: OutboxOperation,
+    pub bk: String,
+    pub user_id: Option<String>,
+    pub project_id: Option<String>,
+    pub token_name: Option<String>,
+    pub status: OutboxStatus,
+    pub attempts: i32,
+    /// Unix timestamp of the next attempt
+    pub next_attempt_at: i64,
+    pub last_error: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+    /// JSON of the permissions of a token to create
+    #[serde(skip)]
+    pub permissions: Option<String>,
+}
+
+impl OutboxEntry {
+    /// Permissions of the token to create, an error if they are missing or corrupt.
+    pub fn permissions(&self) -> Result<TokenPermissions, String> {
+        let permissions = self
+            .permissions
+            .as_deref()
+            .ok_or_else(|| format!("Outbox entry {} has no permissions", self.id))?;
+        serde_json::from_str(permissions)
+            .map_err(|e| format!("Permissions of outbox entry {} are corrupt: {e}", self.id))
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = outbox)]
+pub struct NewOutboxEntry<'a> {
+    pub operation: OutboxOperation,
+    pub bk: &'a str,
+    pub user_id: Option<&'a str>,
+    pub project_id: Option<&'a str>,
+    pub token_name: Option<&'a str>,
+    pub status: OutboxStatus,
+    pub attempts: i32,
+    pub next_attempt_at: i64,
+    pub created_at: &'a str,
+    pub updated_at: &'a str,
+    pub permissions: Option<&'a str>,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+pub struct OutboxQueryParams {
+    /// Only entries with this status, e.g. `PENDING`
+    pub status: Option<OutboxStatus>,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+pub struct EventsQueryParams {
+    /// Only stream events of this job
+    pub job_id: Option<String>,
+    /// Only stream events of this user
+    pub user_id: Option<String>,
+    /// Expiry of an events link, required without the admin api key
+    pub expires: Option<i64>,
+    /// Signature of an events link, required without the admin api key
+    pub signature: Option<String>,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct EventsLinkParams {
+    /// Only stream events of this job
+    pub job_id: Option<String>,
+    /// Only stream events of this user
+    pub user_id: Option<String>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct EventsLinkResponse {
+    /// Signed url of the event stream, restricted to the job and user of the link
+    pub url: String,
+    /// Unix timestamp after which the link can no longer be used to connect
+    pub expires_at: i64,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct ScriptTemplateParams {
+    pub name: String,
+    /// Jinja template, see the script generation for the available variables
+    pub content: String,
+}
+
+#[derive(Debug, Serialize, Queryable, Selectable, ToSchema)]
+#[diesel(table_name = crate::schema::script_templates)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct ScriptTemplate {
+    pub id: i32,
+    pub name: String,
+    pub version: i32,
+    pub content: String,
+    pub created_at: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = script_templates)]
+pub struct NewScriptTemplate<'a> {
+    pub name: &'a str,
+    pub version: i32,
+    pub content: &'a str,
+    pub created_at: &'a str,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+pub struct ScriptTemplateQueryParams {
+    /// Only list the versions of this template
+    pub name: Option<String>,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct ProjectTemplateParams {
+    pub project_id: String,
+    /// `r` or `python`
+    pub format: ScriptFormat,
+    pub template_name: String,
+    /// Pins a version of the template; the latest version is used if omitted
+    pub template_version: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Queryable, Selectable, Insertable, ToSchema)]
+#[diesel(table_name = project_templates)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct ProjectTemplate {
+    pub project_id: String,
+    /// `r` or `python`
+    pub format: ScriptFormat,
+    pub template_name: String,
+    /// Pinned version of the template; the latest version if empty
+    pub template_version: Option<i32>,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+pub struct ProjectTemplateQueryParams {
+    pub project_id: String,
+    pub format: ScriptFormat,
+}
+
+#[derive(Debug, Queryable, Selectable, Insertable)]
+#[diesel(table_name = script_jobs)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct ScriptJob {
+    pub job_id: String,
+    pub user_id: String,
+    pub project_id: String,
+    /// Comma separated list of the requested bridgeheads
+    pub bridgehead_ids: String,
+    pub format: ScriptFormat,
+    pub status: ScriptJobStatus,
+    /// JSON object of the tables reported per bridgehead so far
+    pub tables: String,
+    pub error: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+    /// JSON of the effective Beam settings of the task, unset for jobs of older versions
+    pub task_settings: Option<String>,
+}
+
+impl ScriptJob {
+    pub fn token_params(&self) -> TokenParams {
+        TokenParams {
+            user_id: self.user_id.clone(),
+            project_id: self.project_id.clone(),
+            bridgehead_ids: self
+                .bridgehead_ids
+                .split(',')
+                .filter(|id| !id.is_empty())
+                .map(ToString::to_string)
+                .collect(),
+            permissions: TokenPermissions::default(),
+        }
+    }
+
+    pub fn tables(&self) -> HashMap<String, HashSet<String>> {
+        serde_json::from_str(&self.tables).unwrap_or_default()
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.status == ScriptJobStatus::PENDING
+    }
+
+    pub fn task_settings(&self) -> Option<TaskSettings> {
+        serde_json::from_str(self.task_settings.as_deref()?).ok()
+    }
+
+    /// Requested bridgeheads that did not report their tables (yet)
+    pub fn unreported_bridgeheads(&self) -> Vec<String> {
+        let tables = self.tables();
+        self.token_params()
+            .bridgehead_ids
+            .into_iter()
+            .filter(|bridgehead| !tables.contains_key(bridgehead))
+            .collect()
+    }
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct ScriptJobResponse {
+    pub job_id: String,
+    pub user_id: String,
+    pub project_id: String,
+    pub format: ScriptFormat,
+    pub status: ScriptJobStatus,
+    /// Bridgeheads whose tables are still awaited
+    pub pending: Vec<String>,
+    /// Bridgeheads that did not report their tables before the job completed
+    pub missing: Vec<String>,
+    pub error: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+    /// Effective Beam settings of the task
+    pub task_settings: Option<TaskSettings>,
+}
+
+impl From<&ScriptJob> for ScriptJobResponse {
+    fn from(job: &ScriptJob) -> Self {
+        Self {
+            job_id: job.job_id.clone(),
+            user_id: job.user_id.clone(),
+            project_id: job.project_id.clone(),
+            format: job.format,
+            status: job.status,
+            pending: if job.is_pending() {
+                job.unreported_bridgeheads()
+            } else {
+                Vec::new()
+            },
+            missing: if job.is_pending() {
+                Vec::new()
+            } else {
+                job.unreported_bridgeheads()
+            },
+            error: job.error.clone(),
+            created_at: job.created_at.clone(),
+            updated_at: job.updated_at.clone(),
+            task_settings: job.task_settings(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+pub struct ScriptDownloadQueryParams {
+    /// Return the script assembled so far while bridgeheads are still pending
+    #[serde(default)]
+    pub partial: bool,
+}
+
+#[derive(Debug, Queryable, Selectable, Insertable)]
+#[diesel(table_name = download_links)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct DownloadLink {
+    pub id: String,
+    pub job_id: String,
+    /// Unix timestamp after which the link is rejected
+    pub expires_at: i64,
+    pub used_at: Option<String>,
+    pub created_at: String,
+    /// Script is encrypted to the public key registered for the user
+    pub encrypted: bool,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct DownloadLinkResponse {
+    /// Signed single use url of the script
+    pub url: String,
+    /// Unix timestamp after which the link is rejected
+    pub expires_at: i64,
+    /// Script is encrypted to the public key registered for the user
+    pub encrypted: bool,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+pub struct DownloadLinkQueryParams {
+    pub expires: i64,
+    pub signature: String,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct UserKeyParams {
+    pub user_id: String,
+    /// age X25519 public key (`age1...`) scripts of the user are encrypted to
+    pub public_key: String,
+}
+
+#[derive(Debug, Serialize, Queryable, Selectable, Insertable, ToSchema)]
+#[diesel(table_name = user_keys)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct UserKey {
+    pub user_id: String,
+    pub public_key: String,
+    pub created_at: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Days;
+
+    fn expiring(days_from_today: i64) -> TokenPermissions {
+        let today = Local::now().date_naive();
+        let date = if days_from_today < 0 {
+            today - Days::new(days_from_today.unsigned_abs())
+        } else {
+            today + Days::new(days_from_today as u64)
+        };
+        TokenPermissions {
+            scopes: Vec::new(),
+            expires_at: Some(date.format("%Y-%m-%d").to_string()),
+        }
+    }
+
+    fn token_with_scopes(scopes: &str) -> TokenManager {
+        TokenManager {
+            id: 1,
+            token_name: "t".to_string(),
+            token: "secret".to_string(),
+            project_id: "p".to_string(),
+            project_status: OpalProjectStatus::CREATED,
+            bk: "bk".to_string(),
+            token_status: OpalTokenStatus::CREATED,
+            user_id: "u".to_string(),
+            token_created_at: String::new(),
+            scopes: scopes.to_string(),
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn validate_accepts_unrestricted_and_future_expiry() {
+        assert_eq!(TokenPermissions::default().validate(), Ok(()));
+        assert_eq!(expiring(0).validate(), Ok(()));
+        assert_eq!(expiring(30).validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_rejects_past_or_malformed_expiry() {
+        assert!(expiring(-1).validate().is_err());
+        let malformed = TokenPermissions {
+            scopes: Vec::new(),
+            expires_at: Some("31.12.2030".to_string()),
+        };
+        assert!(malformed.validate().is_err());
+    }
+
+    #[test]
+    fn validate_rejects_tables_scope_without_tables() {
+        let permissions = TokenPermissions {
+            scopes: vec![TokenScope::Tables(Vec::new())],
+            expires_at: None,
+        };
+        assert!(permissions.validate().is_err());
+        let permissions = TokenPermissions {
+            scopes: vec![TokenScope::Tables(vec!["table".to_string()])],
+            expires_at: None,
+        };
+        assert_eq!(permissions.validate(), Ok(()));
+    }
+
+    #[test]
+    fn has_expired_only_after_the_expiry_date() {
+        assert!(expiring(-1).has_expired());
+        assert!(!expiring(0).has_expired());
+        assert!(!expiring(1).has_expired());
+        assert!(!TokenPermissions::default().has_expired());
+    }
+
+    #[test]
+    fn corrupt_scopes_are_an_error() {
+        assert!(token_with_scopes("").permissions().is_err());
+        assert!(token_with_scopes("[\"UNKNOWN\"]").permissions().is_err());
+        let permissions = token_with_scopes("[\"READ_ONLY\"]").permissions().unwrap();
+        assert_eq!(permissions.scopes, vec![TokenScope::ReadOnly]);
+    }
+}
@@ -0,0 +1,351 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+use beam_lib::TaskRequest;
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
+
+use crate::enums::{OpalProjectStatus, OpalRequestType, OpalTokenStatus, SiteFeature};
+use crate::models::TokenPermissions;
+use crate::tasks::TaskSettings;
+
+/// Version of the protocol sent with every request. Agents that do not answer capability
+/// queries speak version 1.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Request types of the agents that predate capability queries.
+pub const LEGACY_REQUEST_TYPES: [OpalRequestType; 5] = [
+    OpalRequestType::CREATE,
+    OpalRequestType::DELETE,
+    OpalRequestType::UPDATE,
+    OpalRequestType::STATUS,
+    OpalRequestType::SCRIPT,
+];
+
+/// Response of a site to a task, `T` is the response of the request type of the task.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum OpalResponse<T> {
+    Err {
+        status_code: i32,
+        error_message: String,
+    },
+    Ok {
+        response: T,
+    },
+}
+
+/// Value reported by a site, values this version does not know are kept as reported.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum SiteValue<T> {
+    Known(T),
+    Unknown(String),
+}
+
+impl<T: fmt::Display> fmt::Display for SiteValue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SiteValue::Known(value) => value.fmt(f),
+            SiteValue::Unknown(value) => write!(f, "{value} (unknown)"),
+        }
+    }
+}
+
+/// Payload of a request type together with the response the sites answer it with.
+pub trait OpalTask: Into<OpalRequest> {
+    const REQUEST_TYPE: OpalRequestType;
+    type Response: DeserializeOwned;
+}
+
+/// Creates the project if needed and a token in it, the site generates the token if none is given.
+#[derive(Debug, Clone)]
+pub struct CreateTokenRequest {
+    pub name: String,
+    pub project: String,
+    pub token: Option<String>,
+    /// Only sent if set, sites without the matching features ignore them
+    pub permissions: TokenPermissions,
+}
+
+impl OpalTask for CreateTokenRequest {
+    const REQUEST_TYPE: OpalRequestType = OpalRequestType::CREATE;
+    /// The created token
+    type Response = String;
+}
+
+/// Replaces the value of an existing token.
+#[derive(Debug, Clone)]
+pub struct UpdateTokenRequest {
+    pub name: String,
+    pub project: String,
+    pub token: String,
+}
+
+impl OpalTask for UpdateTokenRequest {
+    const REQUEST_TYPE: OpalRequestType = OpalRequestType::UPDATE;
+    /// The updated token
+    type Response = String;
+}
+
+#[derive(Debug, Clone)]
+pub struct DeleteTokenRequest {
+    pub name: String,
+}
+
+impl OpalTask for DeleteTokenRequest {
+    const REQUEST_TYPE: OpalRequestType = OpalRequestType::DELETE;
+    type Response = IgnoredAny;
+}
+
+/// Deletes the project and every token of it.
+#[derive(Debug, Clone)]
+pub struct DeleteProjectRequest {
+    pub project: String,
+}
+
+impl OpalTask for DeleteProjectRequest {
+    const REQUEST_TYPE: OpalRequestType = OpalRequestType::DELETE;
+    type Response = IgnoredAny;
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenStatusRequest {
+    pub name: String,
+}
+
+impl OpalTask for TokenStatusRequest {
+    const REQUEST_TYPE: OpalRequestType = OpalRequestType::STATUS;
+    type Response = SiteValue<OpalTokenStatus>;
+}
+
+#[derive(Debug, Clone)]
+pub struct ProjectStatusRequest {
+    pub project: String,
+}
+
+impl OpalTask for ProjectStatusRequest {
+    const REQUEST_TYPE: OpalRequestType = OpalRequestType::STATUS;
+    type Response = SiteValue<OpalProjectStatus>;
+}
+
+/// Lists the tables of the project.
+#[derive(Debug, Clone)]
+pub struct ProjectTablesRequest {
+    pub project: String,
+    /// User the tables are listed for, if any
+    pub user: Option<String>,
+}
+
+impl OpalTask for ProjectTablesRequest {
+    const REQUEST_TYPE: OpalRequestType = OpalRequestType::SCRIPT;
+    /// Names of the tables
+    type Response = Vec<String>;
+}
+
+/// Lists the names of the tokens of the project at the site.
+#[derive(Debug, Clone)]
+pub struct ProjectTokensRequest {
+    pub project: String,
+}
+
+impl OpalTask for ProjectTokensRequest {
+    const REQUEST_TYPE: OpalRequestType = OpalRequestType::TOKENS;
+    /// Names of the tokens
+    type Response = Vec<String>;
+}
+
+/// Asks the agent of the site for the protocol version, request types and features it supports.
+#[derive(Debug, Clone)]
+pub struct CapabilitiesRequest;
+
+impl OpalTask for CapabilitiesRequest {
+    const REQUEST_TYPE: OpalRequestType = OpalRequestType::CAPABILITIES;
+    type Response = SiteCapabilities;
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SiteCapabilities {
+    pub protocol_version: u32,
+    pub request_types: Vec<SiteValue<OpalRequestType>>,
+    /// Missing for agents that support no optional features
+    #[serde(default)]
+    pub features: Vec<SiteValue<SiteFeature>>,
+}
+
+/// Body of the tasks sent to the sites, serialized in the format the sites expect.
+#[derive(Debug, Clone, Serialize)]
+#[serde(into = "WireRequest")]
+pub enum OpalRequest {
+    CreateToken(CreateTokenRequest),
+    UpdateToken(UpdateTokenRequest),
+    DeleteToken(DeleteTokenRequest),
+    DeleteProject(DeleteProjectRequest),
+    TokenStatus(TokenStatusRequest),
+    ProjectStatus(ProjectStatusRequest),
+    ProjectTables(ProjectTablesRequest),
+    ProjectTokens(ProjectTokensRequest),
+    Capabilities(CapabilitiesRequest),
+}
+
+impl OpalRequest {
+    pub fn request_type(&self) -> OpalRequestType {
+        match self {
+            OpalRequest::CreateToken(_) => CreateTokenRequest::REQUEST_TYPE,
+            OpalRequest::UpdateToken(_) => UpdateTokenRequest::REQUEST_TYPE,
+            OpalRequest::DeleteToken(_) => DeleteTokenRequest::REQUEST_TYPE,
+            OpalRequest::DeleteProject(_) => DeleteProjectRequest::REQUEST_TYPE,
+            OpalRequest::TokenStatus(_) => TokenStatusRequest::REQUEST_TYPE,
+            OpalRequest::ProjectStatus(_) => ProjectStatusRequest::REQUEST_TYPE,
+            OpalRequest::ProjectTables(_) => ProjectTablesRequest::REQUEST_TYPE,
+            OpalRequest::ProjectTokens(_) => ProjectTokensRequest::REQUEST_TYPE,
+            OpalRequest::Capabilities(_) => CapabilitiesRequest::REQUEST_TYPE,
+        }
+    }
+}
+
+impl From<CreateTokenRequest> for OpalRequest {
+    fn from(request: CreateTokenRequest) -> Self {
+        OpalRequest::CreateToken(request)
+    }
+}
+
+impl From<UpdateTokenRequest> for OpalRequest {
+    fn from(request: UpdateTokenRequest) -> Self {
+        OpalRequest::UpdateToken(request)
+    }
+}
+
+impl From<DeleteTokenRequest> for OpalRequest {
+    fn from(request: DeleteTokenRequest) -> Self {
+        OpalRequest::DeleteToken(request)
+    }
+}
+
+impl From<DeleteProjectRequest> for OpalRequest {
+    fn from(request: DeleteProjectRequest) -> Self {
+        OpalRequest::DeleteProject(request)
+    }
+}
+
+impl From<TokenStatusRequest> for OpalRequest {
+    fn from(request: TokenStatusRequest) -> Self {
+        OpalRequest::TokenStatus(request)
+    }
+}
+
+impl From<ProjectStatusRequest> for OpalRequest {
+    fn from(request: ProjectStatusRequest) -> Self {
+        OpalRequest::ProjectStatus(request)
+    }
+}
+
+impl From<ProjectTablesRequest> for OpalRequest {
+    fn from(request: ProjectTablesRequest) -> Self {
+        OpalRequest::ProjectTables(request)
+    }
+}
+
+impl From<ProjectTokensRequest> for OpalRequest {
+    fn from(request: ProjectTokensRequest) -> Self {
+        OpalRequest::ProjectTokens(request)
+    }
+}
+
+impl From<CapabilitiesRequest> for OpalRequest {
+    fn from(request: CapabilitiesRequest) -> Self {
+        OpalRequest::Capabilities(request)
+    }
+}
+
+#[derive(Serialize)]
+struct WireRequest {
+    protocol_version: u32,
+    request_type: OpalRequestType,
+    name: Option<String>,
+    project: Option<String>,
+    token: Option<String>,
+    #[serde(flatten)]
+    permissions: TokenPermissions,
+}
+
+impl From<OpalRequest> for WireRequest {
+    fn from(request: OpalRequest) -> Self {
+        let request_type = request.request_type();
+        let mut permissions = TokenPermissions::default();
+        let (name, project, token) = match request {
+            OpalRequest::CreateToken(CreateTokenRequest {
+                name,
+                project,
+                token,
+                permissions: token_permissions,
+            }) => {
+                permissions = token_permissions;
+                (Some(name), Some(project), token)
+            }
+            OpalRequest::UpdateToken(UpdateTokenRequest {
+                name,
+                project,
+                token,
+            }) => (Some(name), Some(project), Some(token)),
+            OpalRequest::DeleteToken(DeleteTokenRequest { name })
+            | OpalRequest::TokenStatus(TokenStatusRequest { name }) => (Some(name), None, None),
+            OpalRequest::DeleteProject(DeleteProjectRequest { project })
+            | OpalRequest::ProjectStatus(ProjectStatusRequest { project })
+            | OpalRequest::ProjectTokens(ProjectTokensRequest { project }) => {
+                (None, Some(project), None)
+            }
+            OpalRequest::ProjectTables(ProjectTablesRequest { project, user }) => {
+                (user, Some(project), None)
+            }
+            OpalRequest::Capabilities(CapabilitiesRequest) => (None, None, None),
+        };
+        WireRequest {
+            protocol_version: PROTOCOL_VERSION,
+            request_type,
+            name,
+            project,
+            token,
+            permissions,
+        }
+    }
+}
+
+/// Task sent to the sites whose results are responses to `R`.
+#[derive(Debug)]
+pub struct OpalTaskRequest<R> {
+    task: TaskRequest<OpalRequest>,
+    /// Effective settings the task was sent with
+    settings: TaskSettings,
+    response: PhantomData<fn() -> R>,
+}
+
+impl<R: OpalTask> OpalTaskRequest<R> {
+    pub fn new(task: TaskRequest<OpalRequest>, settings: TaskSettings) -> Self {
+        Self {
+            task,
+            settings,
+            response: PhantomData,
+        }
+    }
+}
+
+impl<R> OpalTaskRequest<R> {
+    pub fn settings(&self) -> &TaskSettings {
+        &self.settings
+    }
+
+    /// Time to wait for the results, Beam's default is used if `None`
+    pub fn wait_time(&self) -> Option<&str> {
+        self.settings.wait_time.as_deref()
+    }
+}
+
+impl<R> Deref for OpalTaskRequest<R> {
+    type Target = TaskRequest<OpalRequest>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.task
+    }
+}
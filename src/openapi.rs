@@ -0,0 +1,173 @@
+use utoipa::{
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
+};
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::enums::{
+    DriftKind, OpalProjectStatus, OpalRequestType, OpalTokenStatus, OutboxOperation, OutboxStatus,
+    ProgressStatus, ScriptFormat, ScriptJobStatus, SiteChange, SiteFeature, TokenEventKind,
+    TokenScope, WebhookDeliveryStatus,
+};
+use crate::events::ProgressEvent;
+use crate::models::{
+    BridgeheadCapabilities, BridgeheadIdsBody, BridgeheadParams, BridgeheadRevocationProgress,
+    BridgeheadRevocationReport, BridgeheadTables, CapabilitiesResponse, DownloadLinkResponse,
+    ErrorResponse, EventsLinkParams, EventsLinkResponse, JobAcceptedResponse, MessageResponse,
+    OffboardingReport, OutboxEntry, OutstandingToken, ProjectStatusResponse, ProjectTablesResponse,
+    ProjectTemplate, ProjectTemplateParams, ReconciliationReport, ReprovisionReport,
+    ReprovisionedToken, RevocationParams, RevocationProgress, RevocationResponse,
+    ScriptCredentials, ScriptJobResponse, ScriptResponse, ScriptTemplate, ScriptTemplateParams,
+    SiteCredentials, SiteNotification, TokenAvailabilityResponse, TokenCreationBody, TokenDrift,
+    TokenEvent, TokenParams, TokenPermissions, TokenStatusResponse, UserKey, UserKeyParams,
+    UserStatusResponse, UserToken, Webhook, WebhookDelivery, WebhookParams,
+};
+use crate::sites::Site;
+use crate::tasks::{TaskFailureStrategy, TaskSettings};
+use crate::{routes, routes_v2};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Token Manager", description = "Manage Opal tokens within the bridgehead network"),
+    paths(
+        routes::create_token,
+        routes::remove_tokens,
+        routes::check_token_status,
+        routes::check_project_status,
+        routes::generate_script,
+        routes::refresh_token,
+        routes::remove_project_and_token,
+        routes::check_script_status,
+        routes::offboard_user,
+        routes::check_user_status,
+        routes::revoke_all_tokens,
+        routes::check_revocation_status,
+        routes::reprovision_bridgehead,
+        routes::create_webhook,
+        routes::list_webhooks,
+        routes::remove_webhook,
+        routes::list_webhook_deliveries,
+        routes::start_reconciliation,
+        routes::get_reconciliation_report,
+        routes::list_outbox,
+        routes::abandon_outbox_entry,
+        routes::list_site_notifications,
+        routes::create_template,
+        routes::list_templates,
+        routes::set_project_template,
+        routes::list_project_templates,
+        routes::remove_project_template,
+        routes::create_script_job,
+        routes::get_script_job,
+        routes::download_script,
+        routes::create_script_link,
+        routes::download_with_link,
+        routes::set_user_key,
+        routes::get_user_key,
+        routes::remove_user_key,
+        routes::list_project_tables,
+        routes::list_bridgeheads,
+        routes::list_bridgehead_capabilities,
+        routes::stream_events,
+        routes::create_events_link,
+        routes_v2::create_tokens,
+        routes_v2::check_tokens_available,
+        routes_v2::get_token_status,
+        routes_v2::refresh_token,
+        routes_v2::delete_token,
+        routes_v2::generate_script,
+        routes_v2::get_project_status,
+        routes_v2::delete_project,
+        routes_v2::get_user_tokens,
+        routes_v2::offboard_user,
+        routes_v2::revoke_all_tokens,
+        routes_v2::get_revocation_progress,
+        routes_v2::reprovision_bridgehead,
+    ),
+    components(schemas(
+        TokenParams,
+        TokenPermissions,
+        TokenScope,
+        TokenCreationBody,
+        BridgeheadIdsBody,
+        TokenAvailabilityResponse,
+        ScriptResponse,
+        ScriptFormat,
+        ScriptCredentials,
+        SiteCredentials,
+        Site,
+        ProjectTablesResponse,
+        BridgeheadTables,
+        CapabilitiesResponse,
+        BridgeheadCapabilities,
+        SiteFeature,
+        ScriptJobResponse,
+        DownloadLinkResponse,
+        EventsLinkParams,
+        EventsLinkResponse,
+        UserKeyParams,
+        UserKey,
+        ScriptTemplateParams,
+        ScriptTemplate,
+        ProjectTemplateParams,
+        ProjectTemplate,
+        RevocationParams,
+        BridgeheadParams,
+        ProjectStatusResponse,
+        TokenStatusResponse,
+        OffboardingReport,
+        BridgeheadRevocationReport,
+        OutstandingToken,
+        UserStatusResponse,
+        UserToken,
+        RevocationResponse,
+        RevocationProgress,
+        BridgeheadRevocationProgress,
+        ReprovisionReport,
+        ReprovisionedToken,
+        WebhookParams,
+        Webhook,
+        WebhookDelivery,
+        OutboxEntry,
+        SiteNotification,
+        SiteChange,
+        ReconciliationReport,
+        TokenDrift,
+        DriftKind,
+        TokenEvent,
+        TokenEventKind,
+        ProgressEvent,
+        ProgressStatus,
+        TaskSettings,
+        TaskFailureStrategy,
+        OpalRequestType,
+        JobAcceptedResponse,
+        MessageResponse,
+        ErrorResponse,
+        OpalTokenStatus,
+        OpalProjectStatus,
+        ScriptJobStatus,
+        OutboxOperation,
+        OutboxStatus,
+        WebhookDeliveryStatus,
+    )),
+    modifiers(&AdminApiKey)
+)]
+pub struct ApiDoc;
+
+struct AdminApiKey;
+
+impl Modify for AdminApiKey {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "admin_api_key",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+    }
+}
+
+/// Serves the OpenAPI document at `/api/openapi.json` and Swagger UI at `/api/docs`.
+pub fn configure_docs() -> SwaggerUi {
+    SwaggerUi::new("/api/docs").url("/api/openapi.json", ApiDoc::openapi())
+}
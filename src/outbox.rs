@@ -0,0 +1,287 @@
+use std::time::Duration;
+
+use chrono::{Local, Utc};
+use futures_util::StreamExt;
+use once_cell::sync::Lazy;
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+
+use crate::db::{Db, DbPool};
+use crate::enums::{OutboxOperation, OutboxStatus};
+use crate::handlers::send_outbox_entry;
+use crate::models::{NewOutboxEntry, OutboxEntry, TokenPermissions};
+use crate::opal::OpalResponse;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+/// Time the worker sleeps when nothing is pending, entries are also picked up when queued.
+const IDLE_INTERVAL: Duration = Duration::from_secs(60);
+const CONCURRENCY: usize = 8;
+
+static QUEUED: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Queues the operation at the site until the site confirmed it. Tokens that are deleted are
+/// marked as revoking so they are not handed out anymore in the meantime, and their creations
+/// still pending are abandoned.
+pub fn enqueue(
+    db: &mut Db,
+    operation: OutboxOperation,
+    bk: &str,
+    user_id: Option<&str>,
+    project_id: Option<&str>,
+    token_name: Option<&str>,
+) {
+    save(db, operation, bk, user_id, project_id, token_name, None);
+}
+
+/// Queues the creation of the token at the site, created with the given permissions.
+pub fn enqueue_creation(
+    db: &mut Db,
+    bk: &str,
+    user_id: &str,
+    project_id: &str,
+    token_name: &str,
+    permissions: &TokenPermissions,
+) {
+    let permissions = serde_json::to_string(permissions).ok();
+    save(
+        db,
+        OutboxOperation::CreateToken,
+        bk,
+        Some(user_id),
+        Some(project_id),
+        Some(token_name),
+        permissions.as_deref(),
+    );
+}
+
+fn save(
+    db: &mut Db,
+    operation: OutboxOperation,
+    bk: &str,
+    user_id: Option<&str>,
+    project_id: Option<&str>,
+    token_name: Option<&str>,
+    permissions: Option<&str>,
+) {
+    let now = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
+    let entry = NewOutboxEntry {
+        operation,
+        bk,
+        user_id,
+        project_id,
+        token_name,
+        status: OutboxStatus::PENDING,
+        attempts: 0,
+        next_attempt_at: Utc::now().timestamp(),
+        created_at: &now,
+        updated_at: &now,
+        permissions,
+    };
+    match db.save_outbox_entry_db(entry) {
+        Ok(true) => {
+            info!("Queued {} in BK {bk} for retries", operation.as_str());
+            QUEUED.notify_one();
+        }
+        Ok(false) => debug!("{} in BK {bk} is already queued", operation.as_str()),
+        Err(e) => warn!("Failed to queue {} in BK {bk}: {e}", operation.as_str()),
+    }
+}
+
+/// Starts the worker retrying the pending outbox entries with exponential backoff.
+pub fn start_worker(pool: DbPool) {
+    tokio::task::spawn(async move {
+        loop {
+            let now = Utc::now().timestamp();
+            match Db::from_pool(&pool).and_then(|mut db| Ok(db.get_due_outbox_entries(now)?)) {
+                Ok(due) => {
+                    futures_util::stream::iter(due)
+                        .for_each_concurrent(CONCURRENCY, |entry| attempt(&pool, entry))
+                        .await
+                }
+                Err(e) => warn!("Outbox worker could not load pending entries: {e}"),
+            }
+
+            let next_attempt = Db::from_pool(&pool)
+                .and_then(|mut db| Ok(db.get_next_outbox_attempt()?))
+                .unwrap_or_else(|e| {
+                    warn!("Outbox worker could not load the next attempt: {e}");
+                    None
+                });
+            let sleep = match next_attempt {
+                Some(at) => Duration::from_secs((at - Utc::now().timestamp()).max(1) as u64)
+                    .min(IDLE_INTERVAL),
+                None => IDLE_INTERVAL,
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(sleep) => {}
+                _ = QUEUED.notified() => {}
+            }
+        }
+    });
+}
+
+async fn attempt(pool: &DbPool, entry: OutboxEntry) {
+    let outcome = send_outbox_entry(pool, &entry).await;
+
+    let attempts = entry.attempts + 1;
+    let (status, next_attempt) = match &outcome {
+        Ok(()) => {
+            info!(
+                "{} in BK {} confirmed after {attempts} attempts",
+                entry.operation.as_str(),
+                entry.bk
+            );
+            (OutboxStatus::CONFIRMED, entry.next_attempt_at)
+        }
+        Err(e) => {
+            let backoff = backoff(attempts);
+            warn!(
+                "Attempt {attempts} of {} in BK {} failed, retrying in {backoff:?}: {e}",
+                entry.operation.as_str(),
+                entry.bk
+            );
+            (
+                OutboxStatus::PENDING,
+                Utc::now().timestamp() + backoff.as_secs() as i64,
+            )
+        }
+    };
+
+    let now = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
+    match Db::from_pool(pool) {
+        Ok(mut db) => db.update_outbox_entry_db(
+            entry.id,
+            status,
+            attempts,
+            next_attempt,
+            outcome.err().as_deref(),
+            &now,
+        ),
+        Err(e) => warn!("Could not record outbox attempt {}: {e}", entry.id),
+    }
+}
+
+/// Status of the sites for deletions of tokens or projects they do not know.
+pub const MISSING: i32 = 404;
+/// Status of the sites for creations of tokens that exist already, e.g. created by an earlier
+/// attempt whose response was lost.
+pub const ALREADY_EXISTS: i32 = 409;
+
+/// Outcome of an operation the site responded to. The `done_status` counts as confirmed without
+/// a response, e.g. `MISSING` for deletions as nothing is left to delete.
+pub fn confirmation<T>(
+    response: anyhow::Result<OpalResponse<T>>,
+    done_status: Option<i32>,
+) -> Result<Option<T>, String> {
+    match response {
+        Ok(OpalResponse::Ok { response }) => Ok(Some(response)),
+        Ok(OpalResponse::Err { status_code, .. }) if Some(status_code) == done_status => Ok(None),
+        Ok(OpalResponse::Err {
+            status_code,
+            error_message,
+        }) => Err(format!("{status_code}: {error_message}")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn backoff(attempts: i32) -> Duration {
+    let exponent = attempts.saturating_sub(1).clamp(0, 31) as u32;
+    INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(exponent))
+        .min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+    use crate::enums::{OpalProjectStatus, OpalTokenStatus};
+    use crate::models::NewToken;
+
+    fn site_error<T>(status_code: i32) -> anyhow::Result<OpalResponse<T>> {
+        Ok(OpalResponse::Err {
+            status_code,
+            error_message: "error".to_string(),
+        })
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        assert_eq!(backoff(1), Duration::from_secs(10));
+        assert_eq!(backoff(2), Duration::from_secs(20));
+        assert_eq!(backoff(4), Duration::from_secs(80));
+        assert_eq!(backoff(7), MAX_BACKOFF);
+        assert_eq!(backoff(i32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn missing_is_only_done_for_deletions() {
+        assert_eq!(confirmation::<()>(site_error(404), Some(MISSING)), Ok(None));
+        assert!(confirmation::<()>(site_error(404), None).is_err());
+        assert!(confirmation::<()>(site_error(500), Some(MISSING)).is_err());
+        let timeout = Err(anyhow::anyhow!("timeout"));
+        assert!(confirmation::<()>(timeout, Some(MISSING)).is_err());
+        let confirmed = Ok(OpalResponse::Ok { response: 1 });
+        assert_eq!(confirmation(confirmed, None), Ok(Some(1)));
+    }
+
+    #[test]
+    fn existing_token_confirms_a_retried_creation() {
+        let existing = confirmation::<String>(site_error(409), Some(ALREADY_EXISTS));
+        assert_eq!(existing, Ok(None));
+        assert!(confirmation::<String>(site_error(409), Some(MISSING)).is_err());
+        assert!(confirmation::<String>(site_error(404), Some(ALREADY_EXISTS)).is_err());
+    }
+
+    #[test]
+    fn queued_operations_are_not_queued_twice() {
+        let mut db = Db::from_pool(&test_pool()).unwrap();
+        let operation = OutboxOperation::DeleteToken;
+        enqueue(&mut db, operation, "bk", Some("u"), Some("p"), Some("t"));
+        enqueue(&mut db, operation, "bk", Some("u"), Some("p"), Some("t"));
+
+        let pending = db.get_outbox_entries(Some(OutboxStatus::PENDING)).unwrap();
+        assert_eq!(pending.len(), 1);
+    }
+
+    #[test]
+    fn deletion_abandons_pending_creation() {
+        let mut db = Db::from_pool(&test_pool()).unwrap();
+        let permissions = TokenPermissions::default();
+        enqueue_creation(&mut db, "bk", "u", "p", "t", &permissions);
+        enqueue(
+            &mut db,
+            OutboxOperation::DeleteToken,
+            "bk",
+            Some("u"),
+            Some("p"),
+            Some("t"),
+        );
+
+        let abandoned = db
+            .get_outbox_entries(Some(OutboxStatus::ABANDONED))
+            .unwrap();
+        assert_eq!(abandoned.len(), 1);
+        assert_eq!(abandoned[0].operation, OutboxOperation::CreateToken);
+        let created = db
+            .save_outbox_token_db(abandoned[0].id, new_token())
+            .unwrap();
+        assert!(!created);
+    }
+
+    fn new_token() -> NewToken<'static> {
+        NewToken {
+            token_name: "t",
+            token: "secret",
+            project_id: "p",
+            project_status: OpalProjectStatus::CREATED,
+            bk: "bk",
+            token_status: OpalTokenStatus::CREATED,
+            user_id: "u",
+            token_created_at: "",
+            scopes: "",
+            expires_at: None,
+        }
+    }
+}
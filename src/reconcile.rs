@@ -0,0 +1,249 @@
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use chrono::Local;
+use futures_util::StreamExt;
+use tokio::time::MissedTickBehavior;
+use tracing::{info, warn};
+
+use crate::capabilities;
+use crate::config::CONFIG;
+use crate::db::{Db, DbPool};
+use crate::enums::{DriftKind, OpalRequestType, OpalTokenStatus, TokenEventKind};
+use crate::handlers::{recreate_token_at_site, token_names_at_site, token_status_at_site};
+use crate::models::{
+    NewReconciliationRun, ReconciliationReport, TokenDrift, TokenEvent, TokenManager,
+};
+use crate::opal::{OpalResponse, SiteValue};
+use crate::webhooks;
+
+const CONCURRENCY: usize = 8;
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Marks the reconciliation as finished when dropped, also if it panicked.
+struct RunningGuard;
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        RUNNING.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Reconciles the tokens every `RECONCILE_INTERVAL` seconds if set.
+pub fn start_scheduler(pool: DbPool) {
+    let Some(interval) = CONFIG.reconcile_interval.filter(|secs| *secs > 0) else {
+        info!("Scheduled reconciliation is disabled");
+        return;
+    };
+
+    tokio::task::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick completes immediately
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = reconcile(&pool).await {
+                warn!("Reconciliation failed: {e}");
+            }
+        }
+    });
+}
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::SeqCst)
+}
+
+/// Checks every active token at its site, repairs the drift kinds listed in `RECONCILE_REPAIR`
+/// and stores the report. Sites supporting TOKENS requests are also checked for tokens of the
+/// projects that are not stored locally. Returns `None` if a reconciliation is already running.
+pub async fn reconcile(pool: &DbPool) -> anyhow::Result<Option<ReconciliationReport>> {
+    if RUNNING.swap(true, Ordering::SeqCst) {
+        info!("Reconciliation already running");
+        return Ok(None);
+    }
+    let _running = RunningGuard;
+    run(pool).await.map(Some)
+}
+
+async fn run(pool: &DbPool) -> anyhow::Result<ReconciliationReport> {
+    let started_at = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
+    let records = Db::from_pool(pool)?.get_active_tokens()?;
+    let checked = records.len();
+    info!("Reconciling {checked} tokens with the sites");
+    // Projects at sites that can list their tokens, other sites are only asked per token
+    let projects: BTreeSet<_> = records
+        .iter()
+        .filter(|record| {
+            capabilities::cached_capabilities(&record.bk)
+                .is_some_and(|capabilities| capabilities.supports(OpalRequestType::TOKENS))
+        })
+        .map(|record| (record.bk.clone(), record.project_id.clone()))
+        .collect();
+
+    let results: Vec<_> = futures_util::stream::iter(records)
+        .map(check_token)
+        .buffer_unordered(CONCURRENCY)
+        .collect()
+        .await;
+
+    let mut db = Db::from_pool(pool)?;
+    let mut drifts = Vec::new();
+    for (record, drift) in results {
+        let Some(drift) = drift else { continue };
+        if drift.repaired {
+            db.set_token_status_by_id_db(record.id, OpalTokenStatus::CREATED);
+        }
+        drifts.push(drift);
+    }
+    let in_sync = checked - drifts.len();
+
+    let listings: Vec<_> = futures_util::stream::iter(projects)
+        .map(|(bridgehead, project)| async move {
+            let names = token_names_at_site(&bridgehead, &project).await;
+            (bridgehead, project, names)
+        })
+        .buffer_unordered(CONCURRENCY)
+        .collect()
+        .await;
+    for (bridgehead, project, names) in listings {
+        let names = match names {
+            Ok(names) => names,
+            Err(e) => {
+                warn!("Could not list the tokens of project {project} in BK {bridgehead}: {e}");
+                continue;
+            }
+        };
+        let known = db.get_token_names_at_bridgehead(&project, &bridgehead)?;
+        drifts.extend(
+            names
+                .into_iter()
+                .filter(|name| !known.contains(name))
+                .map(|name| TokenDrift {
+                    user_id: None,
+                    project_id: project.clone(),
+                    bk: bridgehead.clone(),
+                    token_name: name,
+                    kind: DriftKind::UnknownAtSite,
+                    site_status: None,
+                    repaired: false,
+                    error: None,
+                }),
+        );
+    }
+
+    let finished_at = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
+    let id = db.save_reconciliation_run_db(NewReconciliationRun {
+        started_at: &started_at,
+        finished_at: &finished_at,
+        checked: checked as i32,
+        in_sync: in_sync as i32,
+        drifts: &serde_json::to_string(&drifts)?,
+    })?;
+    info!(
+        "Reconciliation {id} finished: {in_sync} of {checked} tokens in sync, {} repaired",
+        drifts.iter().filter(|drift| drift.repaired).count()
+    );
+
+    Ok(ReconciliationReport {
+        id,
+        started_at,
+        finished_at,
+        checked,
+        in_sync,
+        drifts,
+    })
+}
+
+async fn check_token(record: TokenManager) -> (TokenManager, Option<TokenDrift>) {
+    let response = token_status_at_site(&record).await;
+    let Some((kind, site_status)) = classify(&response) else {
+        return (record, None);
+    };
+    // The site expired the token at the expiry date it was created with
+    if kind == DriftKind::Expired
+        && record
+            .permissions()
+            .is_ok_and(|permissions| permissions.has_expired())
+    {
+        return (record, None);
+    }
+
+    if kind == DriftKind::Expired {
+        webhooks::notify(TokenEvent::new(
+            TokenEventKind::Expired,
+            Some(&record.user_id),
+            &record.project_id,
+            &record.bk,
+        ));
+    }
+
+    let repair = kind != DriftKind::Undetermined && CONFIG.reconcile_repair.contains(&kind);
+    let (repaired, error) = if repair {
+        match recreate_token_at_site(&record).await {
+            Ok(()) => (true, None),
+            Err(e) => {
+                warn!(
+                    "Could not repair token {} in BK {}: {e}",
+                    record.token_name, record.bk
+                );
+                (false, Some(e))
+            }
+        }
+    } else {
+        (false, None)
+    };
+
+    let drift = TokenDrift {
+        user_id: Some(record.user_id.clone()),
+        project_id: record.project_id.clone(),
+        bk: record.bk.clone(),
+        token_name: record.token_name.clone(),
+        kind,
+        site_status,
+        repaired,
+        error,
+    };
+    (record, Some(drift))
+}
+
+/// Drift of a token given the response of its site to a STATUS request, `None` if in sync.
+fn classify(
+    response: &Result<OpalResponse<SiteValue<OpalTokenStatus>>, String>,
+) -> Option<(DriftKind, Option<String>)> {
+    match response {
+        Ok(OpalResponse::Ok {
+            response: SiteValue::Known(OpalTokenStatus::CREATED),
+        }) => None,
+        Ok(OpalResponse::Ok {
+            response: SiteValue::Known(status @ OpalTokenStatus::EXPIRED),
+        }) => Some((DriftKind::Expired, Some(status.to_string()))),
+        Ok(OpalResponse::Ok {
+            response: SiteValue::Known(status @ OpalTokenStatus::NOTFOUND),
+        }) => Some((DriftKind::MissingAtSite, Some(status.to_string()))),
+        // A state the site should not report for a token
+        Ok(OpalResponse::Ok {
+            response: SiteValue::Known(status),
+        }) => Some((DriftKind::Undetermined, Some(status.to_string()))),
+        Ok(OpalResponse::Ok {
+            response: SiteValue::Unknown(status),
+        }) => Some((DriftKind::Undetermined, Some(status.clone()))),
+        Ok(OpalResponse::Err {
+            status_code: 404,
+            error_message,
+        }) => Some((
+            DriftKind::MissingAtSite,
+            Some(format!("404: {error_message}")),
+        )),
+        Ok(OpalResponse::Err {
+            status_code,
+            error_message,
+        }) => Some((
+            DriftKind::Undetermined,
+            Some(format!("{status_code}: {error_message}")),
+        )),
+        Err(e) => Some((DriftKind::Undetermined, Some(e.clone()))),
+    }
+}
@@ -1,56 +1,193 @@
-use crate::db::Db;
-use crate::enums::OpalResponse;
+use crate::auth::Admin;
+use crate::config::CONFIG;
+use crate::db::{Db, DbPool};
+use crate::downloads;
+use crate::events::{progress_stream, ProgressEvent};
 use crate::handlers::{
-    check_project_status_request, refresh_token_request, remove_project_and_tokens_request,
-    remove_tokens_request, send_token_registration_request,
+    check_project_status_request, fetch_capabilities_request,
+    fetch_project_tables_catalogue_request, offboard_user_request, provision_missing_tokens,
+    refresh_token_request, remove_project_and_tokens_request, remove_tokens_request,
+    reprovision_bridgehead_request, revoke_all_tokens_request, send_token_registration_request,
+    start_script_job, validate_token_permissions,
 };
-use crate::models::{ProjectQueryParams, TokenParams, TokensQueryParams};
+use crate::models::{
+    BridgeheadIdsQuery, BridgeheadParams, CapabilitiesResponse, DownloadLink,
+    DownloadLinkQueryParams, DownloadLinkResponse, ErrorResponse, EventsLinkParams,
+    EventsLinkResponse, EventsQueryParams, JobAcceptedResponse, MessageResponse, NewWebhook,
+    OffboardingReport, OutboxEntry, OutboxQueryParams, ProjectQueryParams, ProjectStatusResponse,
+    ProjectTablesQuery, ProjectTablesResponse, ProjectTemplate, ProjectTemplateParams,
+    ProjectTemplateQueryParams, ReconciliationReport, ReprovisionReport, RevocationParams,
+    RevocationProgress, RevocationResponse, ScriptDownloadQueryParams, ScriptEncryptionQuery,
+    ScriptFormatQuery, ScriptJob, ScriptJobResponse, ScriptProvisionQuery, ScriptTemplate,
+    ScriptTemplateParams, ScriptTemplateQueryParams, SiteNotification, SiteNotificationQueryParams,
+    TokenParams, TokenStatusResponse, TokensQueryParams, UserKey, UserKeyParams, UserQueryParams,
+    UserStatusResponse, Webhook, WebhookDelivery, WebhookParams, WebhookQueryParams,
+};
+use crate::opal::OpalResponse;
+use crate::reconcile;
+use crate::routes_v2::configure_v2_routes;
+use crate::sites::{self, Site};
+use crate::templates::validate_template;
+use crate::utils::parse_public_key;
 use axum::{
-    extract::Query,
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{Path, Query, State},
+    http::{header, HeaderName, StatusCode},
+    response::{IntoResponse, Response},
     routing::{delete, get, post, put},
     Json, Router,
 };
-use serde_json::json;
-use tracing::debug;
+use chrono::{Local, Utc};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+/// Armored age encrypted files are plain text.
+const AGE_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
 
+#[utoipa::path(
+    post,
+    path = "/api/token",
+    request_body = TokenParams,
+    responses(
+        (status = 200, description = "Token creation was sent to the bridgeheads"),
+        (status = 400, description = "Invalid permissions or not supported by every bridgehead", body = MessageResponse),
+        (status = 500, description = "Token creation could not be sent"),
+        (status = 503, description = "Bridgeheads did not report whether they support the permissions", body = MessageResponse),
+    ),
+    tag = "tokens"
+)]
 async fn create_token(db: Db, token_params: Json<TokenParams>) -> impl IntoResponse {
+    if let Err((status, message)) = validate_token_permissions(&token_params).await {
+        return (status, Json(MessageResponse { message })).into_response();
+    }
     if let Err(e) = send_token_registration_request(db, token_params.0).await {
         debug!("Unhandled error: {e:?}");
-        StatusCode::INTERNAL_SERVER_ERROR
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
     } else {
-        StatusCode::OK
+        StatusCode::OK.into_response()
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/project-status",
+    params(ProjectQueryParams),
+    responses(
+        (status = 200, body = ProjectStatusResponse),
+        (status = 500, body = MessageResponse),
+    ),
+    tag = "projects"
+)]
 async fn check_project_status(status_query: Query<ProjectQueryParams>) -> impl IntoResponse {
     match check_project_status_request(status_query.0).await {
         Ok(json) => (StatusCode::OK, json).into_response(),
-        Err((status, message)) => (status, Json(json!({"message": message}))).into_response(),
+        Err((status, message)) => (status, Json(MessageResponse { message })).into_response(),
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/token-status",
+    params(TokensQueryParams),
+    responses(
+        (status = 200, body = TokenStatusResponse),
+        (status = 500, body = MessageResponse),
+    ),
+    tag = "tokens"
+)]
 async fn check_token_status(
     mut db: Db,
     status_query: Query<TokensQueryParams>,
 ) -> impl IntoResponse {
     match db.check_token_status(status_query.0).await {
         Ok(json) => (StatusCode::OK, json).into_response(),
-        Err((status, message)) => (status, Json(json!({"message": message}))).into_response(),
+        Err((status, message)) => (status, Json(MessageResponse { message })).into_response(),
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/authentication-status",
+    request_body = TokenParams,
+    responses(
+        (status = 200, description = "`true` if a token is available for any of the bridgeheads", body = String),
+        (status = 500, body = MessageResponse),
+    ),
+    tag = "scripts"
+)]
 async fn check_script_status(mut db: Db, status_params: Json<TokenParams>) -> impl IntoResponse {
     match db.check_script_status(status_params.0).await {
         Ok(json) => (StatusCode::OK, json).into_response(),
-        Err((status, message)) => (status, Json(json!({"message": message}))).into_response(),
+        Err((status, message)) => (status, Json(MessageResponse { message })).into_response(),
     }
 }
 
-async fn generate_script(mut db: Db, script_params: Json<TokenParams>) -> impl IntoResponse {
-    match db.generate_user_script(script_params.0).await {
-        Ok(script) => (StatusCode::OK, script).into_response(),
+#[utoipa::path(
+    post,
+    path = "/api/script",
+    params(ScriptFormatQuery, ScriptEncryptionQuery, ScriptProvisionQuery),
+    request_body = TokenParams,
+    responses(
+        (status = 200, description = "Generated authentication script in the requested format", body = String, content_type = "text/plain"),
+        (status = 400, description = "Provisioning was requested with invalid permissions or permissions not supported by every bridgehead", body = MessageResponse),
+        (status = 412, description = "Encryption was requested but the user has no public key", body = MessageResponse),
+        (status = 500, description = "Missing tokens could not be provisioned or the script could not be generated", body = MessageResponse),
+        (status = 503, description = "Bridgeheads did not report whether they support the permissions to provision with", body = MessageResponse),
+    ),
+    tag = "scripts"
+)]
+async fn generate_script(
+    State(pool): State<DbPool>,
+    query: Query<ScriptFormatQuery>,
+    encryption: Query<ScriptEncryptionQuery>,
+    provision: Query<ScriptProvisionQuery>,
+    script_params: Json<TokenParams>,
+) -> impl IntoResponse {
+    let format = query.0.format;
+    let user = script_params.user_id.clone();
+    if provision.provision {
+        if let Err((status, message)) = validate_token_permissions(&script_params).await {
+            return (status, Json(MessageResponse { message })).into_response();
+        }
+        if let Err(e) = provision_missing_tokens(&pool, &script_params).await {
+            warn!("Failed to provision missing tokens: {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(MessageResponse {
+                    message: format!("Failed to provision missing tokens: {e}"),
+                }),
+            )
+                .into_response();
+        }
+    }
+    // Acquired after provisioning, which takes a connection of its own
+    let mut db = match Db::from_pool(&pool) {
+        Ok(db) => db,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(MessageResponse {
+                    message: e.to_string(),
+                }),
+            )
+                .into_response()
+        }
+    };
+    match db.generate_user_script(script_params.0, format).await {
+        Ok(script) if encryption.encrypt => match db.encrypt_script_for_user(&user, &script) {
+            Ok(encrypted) => (
+                StatusCode::OK,
+                [(header::CONTENT_TYPE, AGE_CONTENT_TYPE)],
+                encrypted,
+            )
+                .into_response(),
+            Err((status, message)) => (status, Json(MessageResponse { message })).into_response(),
+        },
+        Ok(script) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, format.content_type())],
+            script,
+        )
+            .into_response(),
         Err(e) => {
             debug!("Error generating script: {e}");
             StatusCode::INTERNAL_SERVER_ERROR.into_response()
@@ -58,6 +195,382 @@ async fn generate_script(mut db: Db, script_params: Json<TokenParams>) -> impl I
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/script-jobs",
+    params(ScriptFormatQuery),
+    request_body = TokenParams,
+    responses(
+        (status = 202, description = "Script is assembled in the background", body = JobAcceptedResponse),
+        (status = 401, description = "Missing or wrong admin api key"),
+        (status = 500, description = "Script job could not be started", body = MessageResponse),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "scripts"
+)]
+async fn create_script_job(
+    _admin: Admin,
+    State(pool): State<DbPool>,
+    query: Query<ScriptFormatQuery>,
+    script_params: Json<TokenParams>,
+) -> impl IntoResponse {
+    match start_script_job(pool, script_params.0, query.format).await {
+        Ok(job_id) => (
+            StatusCode::ACCEPTED,
+            Json(JobAcceptedResponse {
+                message: "Script is generated when the bridgeheads responded".to_string(),
+                job_id: Some(job_id),
+            }),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/script-jobs/{job_id}",
+    params(("job_id" = String, Path)),
+    responses(
+        (status = 200, body = ScriptJobResponse),
+        (status = 401, description = "Missing or wrong admin api key"),
+        (status = 404, description = "Script job not found"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "scripts"
+)]
+async fn get_script_job(
+    _admin: Admin,
+    mut db: Db,
+    Path(job_id): Path<String>,
+) -> impl IntoResponse {
+    match db.get_script_job(&job_id) {
+        Ok(Some(job)) => (StatusCode::OK, Json(ScriptJobResponse::from(&job))).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/script-jobs/{job_id}/download",
+    params(("job_id" = String, Path), ScriptDownloadQueryParams, ScriptEncryptionQuery),
+    responses(
+        (status = 200, description = "Script of the job; `X-Pending-Bridgeheads` or `X-Missing-Bridgeheads` list the bridgeheads without tables", body = String, content_type = "text/plain"),
+        (status = 202, description = "Bridgeheads are still pending", body = ScriptJobResponse),
+        (status = 401, description = "Missing or wrong admin api key"),
+        (status = 404, description = "Script job not found"),
+        (status = 412, description = "Encryption was requested but the user has no public key", body = MessageResponse),
+        (status = 500, description = "Script job failed", body = MessageResponse),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "scripts"
+)]
+async fn download_script(
+    _admin: Admin,
+    mut db: Db,
+    Path(job_id): Path<String>,
+    query: Query<ScriptDownloadQueryParams>,
+    encryption: Query<ScriptEncryptionQuery>,
+) -> impl IntoResponse {
+    let job = match db.get_script_job(&job_id) {
+        Ok(Some(job)) => job,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(MessageResponse {
+                    message: e.to_string(),
+                }),
+            )
+                .into_response()
+        }
+    };
+    if job.is_pending() && !query.partial {
+        return (StatusCode::ACCEPTED, Json(ScriptJobResponse::from(&job))).into_response();
+    }
+    script_job_download(&mut db, job, encryption.encrypt)
+}
+
+/// Script of the job as a file download, listing the bridgeheads without tables in a header.
+/// With `encrypt` the script is encrypted to the public key registered for the user.
+/// Script of a job with the headers of its download.
+struct ScriptDownload {
+    script: String,
+    content_type: String,
+    disposition: String,
+    unreported_header: &'static str,
+    unreported: String,
+}
+
+impl IntoResponse for ScriptDownload {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::OK,
+            [
+                (header::CONTENT_TYPE, self.content_type),
+                (header::CONTENT_DISPOSITION, self.disposition),
+                (
+                    HeaderName::from_static(self.unreported_header),
+                    self.unreported,
+                ),
+            ],
+            self.script,
+        )
+            .into_response()
+    }
+}
+
+fn render_script_job(
+    db: &mut Db,
+    job: ScriptJob,
+    encrypt: bool,
+) -> Result<ScriptDownload, (StatusCode, String)> {
+    if let Some(error) = job.error {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, error));
+    }
+
+    let format = job.format;
+    let disposition = format!(
+        "attachment; filename=\"script-{}.{}{}\"",
+        job.project_id,
+        format.file_extension(),
+        if encrypt { ".age" } else { "" }
+    );
+    let unreported_header = if job.is_pending() {
+        "x-pending-bridgeheads"
+    } else {
+        "x-missing-bridgeheads"
+    };
+    let script = db
+        .generate_user_script_from_tables(&job.token_params(), format, job.tables())
+        .map_err(|e| {
+            debug!("Error generating script of job {}: {e:#}", job.job_id);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to generate user script due to template error.".to_string(),
+            )
+        })?;
+    let (script, content_type) = if encrypt {
+        (
+            db.encrypt_script_for_user(&job.user_id, &script)?,
+            AGE_CONTENT_TYPE,
+        )
+    } else {
+        (script, format.content_type())
+    };
+    Ok(ScriptDownload {
+        script,
+        content_type: content_type.to_string(),
+        disposition,
+        unreported_header,
+        unreported: job.unreported_bridgeheads().join(","),
+    })
+}
+
+fn script_job_download(db: &mut Db, job: ScriptJob, encrypt: bool) -> Response {
+    match render_script_job(db, job, encrypt) {
+        Ok(download) => download.into_response(),
+        Err((status, message)) => (status, Json(MessageResponse { message })).into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/script-links",
+    params(ScriptFormatQuery, ScriptEncryptionQuery),
+    request_body = TokenParams,
+    responses(
+        (status = 201, description = "Script job was started with a single use download link", body = DownloadLinkResponse),
+        (status = 401, description = "Missing or wrong admin api key"),
+        (status = 412, description = "Encryption was requested but the user has no public key", body = MessageResponse),
+        (status = 500, description = "Script job could not be started", body = MessageResponse),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "scripts"
+)]
+async fn create_script_link(
+    _admin: Admin,
+    State(pool): State<DbPool>,
+    query: Query<ScriptFormatQuery>,
+    encryption: Query<ScriptEncryptionQuery>,
+    script_params: Json<TokenParams>,
+) -> impl IntoResponse {
+    if encryption.encrypt {
+        let user_key =
+            Db::from_pool(&pool).and_then(|mut db| Ok(db.get_user_key(&script_params.user_id)?));
+        match user_key {
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                return (
+                    StatusCode::PRECONDITION_FAILED,
+                    Json(MessageResponse {
+                        message: format!(
+                            "No public key registered for user {}",
+                            script_params.user_id
+                        ),
+                    }),
+                )
+                    .into_response()
+            }
+            Err(e) => {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    Json(MessageResponse {
+                        message: e.to_string(),
+                    }),
+                )
+                    .into_response()
+            }
+        }
+    }
+    let link = match start_script_job(pool.clone(), script_params.0, query.format).await {
+        Ok(job_id) => DownloadLink {
+            id: Uuid::new_v4().to_string(),
+            job_id,
+            expires_at: Utc::now().timestamp() + CONFIG.download_link_ttl as i64,
+            used_at: None,
+            created_at: Local::now().format("%d-%m-%Y %H:%M:%S").to_string(),
+            encrypted: encryption.encrypt,
+        },
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(MessageResponse {
+                    message: e.to_string(),
+                }),
+            )
+                .into_response()
+        }
+    };
+
+    match Db::from_pool(&pool).and_then(|mut db| Ok(db.save_download_link_db(&link)?)) {
+        Ok(()) => (
+            StatusCode::CREATED,
+            Json(DownloadLinkResponse {
+                url: downloads::link_url(&link.id, link.expires_at),
+                expires_at: link.expires_at,
+                encrypted: link.encrypted,
+            }),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/downloads/{link_id}",
+    params(("link_id" = String, Path), DownloadLinkQueryParams),
+    responses(
+        (status = 200, description = "Script of the link, the link can not be used again", body = String, content_type = "text/plain"),
+        (status = 202, description = "Bridgeheads are still pending, the link stays valid", body = ScriptJobResponse),
+        (status = 403, description = "Signature is invalid"),
+        (status = 404, description = "Link not found"),
+        (status = 410, description = "Link expired or was already used"),
+        (status = 412, description = "Script is encrypted but the user has no public key anymore", body = MessageResponse),
+    ),
+    tag = "scripts"
+)]
+async fn download_with_link(
+    mut db: Db,
+    Path(link_id): Path<String>,
+    query: Query<DownloadLinkQueryParams>,
+) -> impl IntoResponse {
+    if !downloads::verify_link(&link_id, query.expires, &query.signature) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    if Utc::now().timestamp() > query.expires {
+        return StatusCode::GONE.into_response();
+    }
+
+    let link_and_job = db.get_download_link(&link_id).and_then(|link| match link {
+        Some(link) => Ok(db.get_script_job(&link.job_id)?.map(|job| (link, job))),
+        None => Ok(None),
+    });
+    let (link, job) = match link_and_job {
+        Ok(Some(link_and_job)) => link_and_job,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(MessageResponse {
+                    message: e.to_string(),
+                }),
+            )
+                .into_response()
+        }
+    };
+    if link.used_at.is_some() || Utc::now().timestamp() > link.expires_at {
+        return StatusCode::GONE.into_response();
+    }
+    if job.is_pending() {
+        return (StatusCode::ACCEPTED, Json(ScriptJobResponse::from(&job))).into_response();
+    }
+    // Keeps the link usable if the user key was removed since the link was created
+    if link.encrypted && !matches!(db.get_user_key(&job.user_id), Ok(Some(_))) {
+        return (
+            StatusCode::PRECONDITION_FAILED,
+            Json(MessageResponse {
+                message: format!("No public key registered for user {}", job.user_id),
+            }),
+        )
+            .into_response();
+    }
+
+    // The link is only used up once the user gets the script
+    let job_id = job.job_id.clone();
+    let download = match render_script_job(&mut db, job, link.encrypted) {
+        Ok(download) => download,
+        Err((status, message)) => {
+            return (status, Json(MessageResponse { message })).into_response()
+        }
+    };
+    let used_at = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
+    match db.claim_download_link_db(&link.id, &used_at) {
+        Ok(true) => {
+            info!("Download link {} of job {job_id} was used", link.id);
+            download.into_response()
+        }
+        Ok(false) => StatusCode::GONE.into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/refreshToken",
+    request_body = TokenParams,
+    responses(
+        (status = 200, description = "Token refresh was sent to the bridgehead"),
+        (status = 500, description = "Token could not be refreshed"),
+    ),
+    tag = "tokens"
+)]
 async fn refresh_token(db: Db, token_params: Json<TokenParams>) -> StatusCode {
     if let Err(e) = refresh_token_request(db, token_params.0).await {
         debug!("Unhandled error: {e:?}");
@@ -67,6 +580,16 @@ async fn refresh_token(db: Db, token_params: Json<TokenParams>) -> StatusCode {
     }
 }
 
+#[utoipa::path(
+    delete,
+    path = "/api/project",
+    params(ProjectQueryParams),
+    responses(
+        (status = 200, description = "Project and its tokens were deleted"),
+        (status = "default", description = "Error reported by the bridgehead", body = ErrorResponse),
+    ),
+    tag = "projects"
+)]
 async fn remove_project_and_token(db: Db, query: Query<ProjectQueryParams>) -> impl IntoResponse {
     match remove_project_and_tokens_request(db, &query.0).await {
         Ok(OpalResponse::Ok { .. }) => StatusCode::OK.into_response(),
@@ -82,7 +605,13 @@ async fn remove_project_and_token(db: Db, query: Query<ProjectQueryParams>) -> i
             );
             let status = StatusCode::from_u16(status_code as u16)
                 .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
-            (status, Json(json!({ "error": error_message }))).into_response()
+            (
+                status,
+                Json(ErrorResponse {
+                    error: error_message,
+                }),
+            )
+                .into_response()
         }
         Err(e) => {
             debug!("Unhandled error: {e:?}");
@@ -91,6 +620,16 @@ async fn remove_project_and_token(db: Db, query: Query<ProjectQueryParams>) -> i
     }
 }
 
+#[utoipa::path(
+    delete,
+    path = "/api/token",
+    params(TokensQueryParams),
+    responses(
+        (status = 200, description = "Token was deleted"),
+        (status = "default", description = "Error reported by the bridgehead", body = ErrorResponse),
+    ),
+    tag = "tokens"
+)]
 async fn remove_tokens(db: Db, query: Query<TokensQueryParams>) -> impl IntoResponse {
     match remove_tokens_request(db, &query.0).await {
         Ok(OpalResponse::Ok { .. }) => StatusCode::OK.into_response(),
@@ -106,7 +645,13 @@ async fn remove_tokens(db: Db, query: Query<TokensQueryParams>) -> impl IntoResp
             );
             let status = StatusCode::from_u16(status_code as u16)
                 .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
-            (status, Json(json!({ "error": error_message }))).into_response()
+            (
+                status,
+                Json(ErrorResponse {
+                    error: error_message,
+                }),
+            )
+                .into_response()
         }
         Err(e) => {
             debug!("Unhandled error: {e:?}");
@@ -115,17 +660,881 @@ async fn remove_tokens(db: Db, query: Query<TokensQueryParams>) -> impl IntoResp
     }
 }
 
-pub fn configure_routes(
-    pool: diesel::r2d2::Pool<diesel::r2d2::ConnectionManager<diesel::prelude::SqliteConnection>>,
-) -> Router {
+#[utoipa::path(
+    delete,
+    path = "/api/user",
+    params(UserQueryParams),
+    responses(
+        (status = 200, body = OffboardingReport),
+        (status = 401, description = "Missing or wrong admin api key"),
+        (status = 500, description = "Tokens of the user could not be loaded"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "users"
+)]
+async fn offboard_user(
+    _admin: Admin,
+    State(pool): State<DbPool>,
+    query: Query<UserQueryParams>,
+) -> impl IntoResponse {
+    match offboard_user_request(&pool, &query.0).await {
+        Ok(json) => (StatusCode::OK, json).into_response(),
+        Err(e) => {
+            debug!("Unhandled error: {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/user-status",
+    params(UserQueryParams),
+    responses(
+        (status = 200, body = UserStatusResponse),
+        (status = 500, body = MessageResponse),
+    ),
+    tag = "users"
+)]
+async fn check_user_status(mut db: Db, query: Query<UserQueryParams>) -> impl IntoResponse {
+    match db.check_user_offboarding_status(query.0).await {
+        Ok(json) => (StatusCode::OK, json).into_response(),
+        Err((status, message)) => (status, Json(MessageResponse { message })).into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/revoke",
+    request_body = RevocationParams,
+    responses(
+        (status = 202, body = RevocationResponse),
+        (status = 401, description = "Missing or wrong admin api key"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "admin"
+)]
+async fn revoke_all_tokens(
+    _admin: Admin,
+    State(pool): State<DbPool>,
+    params: Json<RevocationParams>,
+) -> impl IntoResponse {
+    match revoke_all_tokens_request(pool, &params.0) {
+        Ok(marked) => (StatusCode::ACCEPTED, Json(RevocationResponse { marked })).into_response(),
+        Err(e) => {
+            debug!("Unhandled error: {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/revocation-status",
+    responses(
+        (status = 200, body = RevocationProgress),
+        (status = 401, description = "Missing or wrong admin api key"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "admin"
+)]
+async fn check_revocation_status(_admin: Admin, mut db: Db) -> impl IntoResponse {
+    match db.get_revocation_progress() {
+        Ok(json) => (StatusCode::OK, json).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/reprovision",
+    request_body = BridgeheadParams,
+    responses(
+        (status = 200, body = ReprovisionReport),
+        (status = 401, description = "Missing or wrong admin api key"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "admin"
+)]
+async fn reprovision_bridgehead(
+    _admin: Admin,
+    State(pool): State<DbPool>,
+    params: Json<BridgeheadParams>,
+) -> impl IntoResponse {
+    match reprovision_bridgehead_request(&pool, &params.0).await {
+        Ok(json) => (StatusCode::OK, json).into_response(),
+        Err(e) => {
+            debug!("Unhandled error: {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/webhooks",
+    request_body = WebhookParams,
+    responses(
+        (status = 201, body = Webhook),
+        (status = 400, body = MessageResponse),
+        (status = 401, description = "Missing or wrong admin api key"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "admin"
+)]
+async fn create_webhook(
+    _admin: Admin,
+    mut db: Db,
+    params: Json<WebhookParams>,
+) -> impl IntoResponse {
+    if let Err(e) = reqwest::Url::parse(&params.url) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(MessageResponse {
+                message: format!("Invalid webhook url: {e}"),
+            }),
+        )
+            .into_response();
+    }
+
+    let events = params
+        .events
+        .iter()
+        .map(|event| event.as_str())
+        .collect::<Vec<_>>()
+        .join(",");
+    let created_at = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
+    let new_webhook = NewWebhook {
+        url: &params.url,
+        secret: &params.secret,
+        events: &events,
+        created_at: &created_at,
+    };
+
+    match db.save_webhook_db(new_webhook) {
+        Ok(webhook) => (StatusCode::CREATED, Json(webhook)).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/webhooks",
+    responses(
+        (status = 200, body = Vec<Webhook>),
+        (status = 401, description = "Missing or wrong admin api key"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "admin"
+)]
+async fn list_webhooks(_admin: Admin, mut db: Db) -> impl IntoResponse {
+    match db.get_webhooks() {
+        Ok(webhooks) => (StatusCode::OK, Json(webhooks)).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/admin/webhooks",
+    params(WebhookQueryParams),
+    responses(
+        (status = 200, description = "Webhook and its delivery log were deleted"),
+        (status = 404, description = "Webhook not found"),
+        (status = 401, description = "Missing or wrong admin api key"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "admin"
+)]
+async fn remove_webhook(
+    _admin: Admin,
+    mut db: Db,
+    query: Query<WebhookQueryParams>,
+) -> impl IntoResponse {
+    match db.delete_webhook_db(query.id) {
+        Ok(true) => StatusCode::OK.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/webhook-deliveries",
+    params(WebhookQueryParams),
+    responses(
+        (status = 200, body = Vec<WebhookDelivery>),
+        (status = 401, description = "Missing or wrong admin api key"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "admin"
+)]
+async fn list_webhook_deliveries(
+    _admin: Admin,
+    mut db: Db,
+    query: Query<WebhookQueryParams>,
+) -> impl IntoResponse {
+    match db.get_webhook_deliveries(query.id) {
+        Ok(deliveries) => (StatusCode::OK, Json(deliveries)).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/reconciliation",
+    responses(
+        (status = 202, description = "Reconciliation was started", body = JobAcceptedResponse),
+        (status = 409, description = "A reconciliation is already running", body = MessageResponse),
+        (status = 401, description = "Missing or wrong admin api key"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "admin"
+)]
+async fn start_reconciliation(_admin: Admin, State(pool): State<DbPool>) -> impl IntoResponse {
+    if reconcile::is_running() {
+        return (
+            StatusCode::CONFLICT,
+            Json(MessageResponse {
+                message: "A reconciliation is already running".to_string(),
+            }),
+        )
+            .into_response();
+    }
+
+    tokio::task::spawn(async move {
+        if let Err(e) = reconcile::reconcile(&pool).await {
+            warn!("Reconciliation failed: {e}");
+        }
+    });
+    (
+        StatusCode::ACCEPTED,
+        Json(JobAcceptedResponse {
+            message: "Tokens are reconciled with the sites".to_string(),
+            job_id: None,
+        }),
+    )
+        .into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/reconciliation",
+    responses(
+        (status = 200, description = "Report of the latest reconciliation", body = ReconciliationReport),
+        (status = 404, description = "No reconciliation finished yet"),
+        (status = 401, description = "Missing or wrong admin api key"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "admin"
+)]
+async fn get_reconciliation_report(_admin: Admin, mut db: Db) -> impl IntoResponse {
+    let report = db
+        .get_latest_reconciliation_run()
+        .map_err(|e| e.to_string())
+        .and_then(|run| {
+            run.map(ReconciliationReport::try_from)
+                .transpose()
+                .map_err(|e| e.to_string())
+        });
+    match report {
+        Ok(Some(report)) => (StatusCode::OK, Json(report)).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(message) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse { message }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/outbox",
+    params(OutboxQueryParams),
+    responses(
+        (status = 200, description = "Most recent operations queued for the sites", body = Vec<OutboxEntry>),
+        (status = 401, description = "Missing or wrong admin api key"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "admin"
+)]
+async fn list_outbox(
+    _admin: Admin,
+    mut db: Db,
+    query: Query<OutboxQueryParams>,
+) -> impl IntoResponse {
+    match db.get_outbox_entries(query.status) {
+        Ok(entries) => (StatusCode::OK, Json(entries)).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/admin/outbox/{id}",
+    params(("id" = i32, Path)),
+    responses(
+        (status = 200, description = "Operation is not retried anymore"),
+        (status = 404, description = "No pending operation with this id"),
+        (status = 401, description = "Missing or wrong admin api key"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "admin"
+)]
+async fn abandon_outbox_entry(
+    _admin: Admin,
+    mut db: Db,
+    Path(entry_id): Path<i32>,
+) -> impl IntoResponse {
+    let now = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
+    match db.abandon_outbox_entry_db(entry_id, &now) {
+        Ok(true) => {
+            info!("Outbox entry {entry_id} abandoned");
+            StatusCode::OK.into_response()
+        }
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/site-notifications",
+    params(SiteNotificationQueryParams),
+    responses(
+        (status = 200, description = "Most recent changes the bridgeheads reported for their sites", body = Vec<SiteNotification>),
+        (status = 401, description = "Missing or wrong admin api key"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "admin"
+)]
+async fn list_site_notifications(
+    _admin: Admin,
+    mut db: Db,
+    query: Query<SiteNotificationQueryParams>,
+) -> impl IntoResponse {
+    match db.get_site_notifications(query.bk.as_deref()) {
+        Ok(notifications) => (StatusCode::OK, Json(notifications)).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/admin/templates",
+    request_body = ScriptTemplateParams,
+    responses(
+        (status = 201, description = "Template was stored as a new version", body = ScriptTemplate),
+        (status = 400, description = "Template does not compile or render", body = MessageResponse),
+        (status = 401, description = "Missing or wrong admin api key"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "admin"
+)]
+async fn create_template(
+    _admin: Admin,
+    mut db: Db,
+    params: Json<ScriptTemplateParams>,
+) -> impl IntoResponse {
+    if let Err(e) = validate_template(&params.content) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(MessageResponse {
+                message: format!("Invalid template: {e:#}"),
+            }),
+        )
+            .into_response();
+    }
+
+    let created_at = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
+    match db.save_script_template_db(&params.name, &params.content, &created_at) {
+        Ok(template) => (StatusCode::CREATED, Json(template)).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/templates",
+    params(ScriptTemplateQueryParams),
+    responses(
+        (status = 200, body = Vec<ScriptTemplate>),
+        (status = 401, description = "Missing or wrong admin api key"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "admin"
+)]
+async fn list_templates(
+    _admin: Admin,
+    mut db: Db,
+    query: Query<ScriptTemplateQueryParams>,
+) -> impl IntoResponse {
+    match db.get_script_templates(query.name.as_deref()) {
+        Ok(templates) => (StatusCode::OK, Json(templates)).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/admin/project-templates",
+    request_body = ProjectTemplateParams,
+    responses(
+        (status = 200, description = "Template is used for the scripts of the project", body = ProjectTemplate),
+        (status = 400, description = "Format is not rendered from a template", body = MessageResponse),
+        (status = 404, description = "Template not found", body = MessageResponse),
+        (status = 401, description = "Missing or wrong admin api key"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "admin"
+)]
+async fn set_project_template(
+    _admin: Admin,
+    mut db: Db,
+    params: Json<ProjectTemplateParams>,
+) -> impl IntoResponse {
+    if !params.format.is_templated() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(MessageResponse {
+                message: format!("The {} format has no template", params.format.as_str()),
+            }),
+        )
+            .into_response();
+    }
+
+    match db.get_script_template(&params.template_name, params.template_version) {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(MessageResponse {
+                    message: "Template not found".to_string(),
+                }),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(MessageResponse {
+                    message: e.to_string(),
+                }),
+            )
+                .into_response()
+        }
+    }
+
+    let project_template = ProjectTemplate {
+        project_id: params.0.project_id,
+        format: params.0.format,
+        template_name: params.0.template_name,
+        template_version: params.0.template_version,
+    };
+    match db.set_project_template_db(&project_template) {
+        Ok(()) => (StatusCode::OK, Json(project_template)).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/project-templates",
+    responses(
+        (status = 200, body = Vec<ProjectTemplate>),
+        (status = 401, description = "Missing or wrong admin api key"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "admin"
+)]
+async fn list_project_templates(_admin: Admin, mut db: Db) -> impl IntoResponse {
+    match db.get_project_templates() {
+        Ok(project_templates) => (StatusCode::OK, Json(project_templates)).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/admin/project-templates",
+    params(ProjectTemplateQueryParams),
+    responses(
+        (status = 200, description = "Project uses the default template again"),
+        (status = 404, description = "No template selected for the project"),
+        (status = 401, description = "Missing or wrong admin api key"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "admin"
+)]
+async fn remove_project_template(
+    _admin: Admin,
+    mut db: Db,
+    query: Query<ProjectTemplateQueryParams>,
+) -> impl IntoResponse {
+    match db.delete_project_template_db(&query.project_id, query.format) {
+        Ok(true) => StatusCode::OK.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/user-key",
+    request_body = UserKeyParams,
+    responses(
+        (status = 200, description = "Scripts requested with `encrypt=true` are encrypted to the key", body = UserKey),
+        (status = 400, description = "Public key is invalid", body = MessageResponse),
+    ),
+    tag = "users"
+)]
+async fn set_user_key(mut db: Db, params: Json<UserKeyParams>) -> impl IntoResponse {
+    if let Err(e) = parse_public_key(&params.public_key) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response();
+    }
+
+    let user_key = UserKey {
+        user_id: params.0.user_id,
+        public_key: params.0.public_key.trim().to_string(),
+        created_at: Local::now().format("%d-%m-%Y %H:%M:%S").to_string(),
+    };
+    match db.save_user_key_db(&user_key) {
+        Ok(()) => (StatusCode::OK, Json(user_key)).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/user-key",
+    params(UserQueryParams),
+    responses(
+        (status = 200, body = UserKey),
+        (status = 404, description = "No public key registered for the user"),
+    ),
+    tag = "users"
+)]
+async fn get_user_key(mut db: Db, query: Query<UserQueryParams>) -> impl IntoResponse {
+    match db.get_user_key(&query.user_id) {
+        Ok(Some(user_key)) => (StatusCode::OK, Json(user_key)).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/user-key",
+    params(UserQueryParams),
+    responses(
+        (status = 200, description = "Public key was removed"),
+        (status = 404, description = "No public key registered for the user"),
+    ),
+    tag = "users"
+)]
+async fn remove_user_key(mut db: Db, query: Query<UserQueryParams>) -> impl IntoResponse {
+    match db.delete_user_key_db(&query.user_id) {
+        Ok(true) => StatusCode::OK.into_response(),
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/projects/{project_id}/tables",
+    params(("project_id" = String, Path), ProjectTablesQuery),
+    responses(
+        (status = 200, description = "Tables of the project per bridgehead", body = ProjectTablesResponse),
+        (status = 500, description = "Tables could not be requested from the bridgeheads", body = MessageResponse),
+    ),
+    tag = "projects"
+)]
+async fn list_project_tables(
+    Path(project_id): Path<String>,
+    query: Query<ProjectTablesQuery>,
+) -> impl IntoResponse {
+    match fetch_project_tables_catalogue_request(
+        &project_id,
+        &query.user_id,
+        query.bridgehead_ids(),
+    )
+    .await
+    {
+        Ok(tables) => (StatusCode::OK, Json(tables)).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/bridgeheads",
+    responses(
+        (status = 200, description = "Registered bridgeheads with their Opal connection details", body = Vec<Site>),
+    ),
+    tag = "bridgeheads"
+)]
+async fn list_bridgeheads() -> Json<Vec<Site>> {
+    Json(sites::registry().sites())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/bridgeheads/capabilities",
+    params(BridgeheadIdsQuery),
+    responses(
+        (status = 200, description = "Protocol version and request types supported by the agents of the bridgeheads", body = CapabilitiesResponse),
+        (status = 500, description = "Capabilities could not be requested from the bridgeheads", body = MessageResponse),
+    ),
+    tag = "bridgeheads"
+)]
+async fn list_bridgehead_capabilities(query: Query<BridgeheadIdsQuery>) -> impl IntoResponse {
+    match fetch_capabilities_request(query.bridgehead_ids()).await {
+        Ok(capabilities) => (StatusCode::OK, Json(capabilities)).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(MessageResponse {
+                message: e.to_string(),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/event-links",
+    request_body = EventsLinkParams,
+    responses(
+        (status = 201, description = "Signed link to the event stream of the job or user", body = EventsLinkResponse),
+        (status = 400, description = "Neither a job nor a user was given", body = MessageResponse),
+        (status = 401, description = "Missing or wrong admin api key"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "events"
+)]
+async fn create_events_link(_admin: Admin, params: Json<EventsLinkParams>) -> impl IntoResponse {
+    let job_id = params.job_id.as_deref().filter(|id| !id.is_empty());
+    let user_id = params.user_id.as_deref().filter(|id| !id.is_empty());
+    if job_id.is_none() && user_id.is_none() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(MessageResponse {
+                message: "An events link needs a job or a user".to_string(),
+            }),
+        )
+            .into_response();
+    }
+
+    let expires_at = Utc::now().timestamp() + CONFIG.download_link_ttl as i64;
+    (
+        StatusCode::CREATED,
+        Json(EventsLinkResponse {
+            url: downloads::events_link_url(job_id, user_id, expires_at),
+            expires_at,
+        }),
+    )
+        .into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/events",
+    params(EventsQueryParams),
+    responses(
+        (status = 200, description = "Stream of `progress` events", body = ProgressEvent, content_type = "text/event-stream"),
+        (status = 401, description = "Neither the admin api key nor a valid events link"),
+        (status = 410, description = "Events link expired"),
+    ),
+    security((), ("admin_api_key" = [])),
+    tag = "events"
+)]
+async fn stream_events(admin: Option<Admin>, query: Query<EventsQueryParams>) -> impl IntoResponse {
+    // Browsers' EventSource cannot send the api key, they connect with a link signed for the filter
+    if admin.is_none() {
+        let (Some(expires), Some(signature)) = (query.expires, query.signature.as_deref()) else {
+            return StatusCode::UNAUTHORIZED.into_response();
+        };
+        if !downloads::verify_events_link(
+            query.job_id.as_deref(),
+            query.user_id.as_deref(),
+            expires,
+            signature,
+        ) {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        if Utc::now().timestamp() > expires {
+            return StatusCode::GONE.into_response();
+        }
+    }
+    progress_stream(query.0).into_response()
+}
+
+pub fn configure_routes(pool: DbPool) -> Router {
     Router::new()
         .route("/token", post(create_token))
         .route("/token", delete(remove_tokens))
         .route("/token-status", get(check_token_status))
         .route("/project-status", get(check_project_status))
         .route("/script", post(generate_script))
+        .route("/script-jobs", post(create_script_job))
+        .route("/script-jobs/:job_id", get(get_script_job))
+        .route("/script-jobs/:job_id/download", get(download_script))
+        .route("/script-links", post(create_script_link))
+        .route("/downloads/:link_id", get(download_with_link))
         .route("/refreshToken", put(refresh_token))
         .route("/project", delete(remove_project_and_token))
         .route("/authentication-status", post(check_script_status))
+        .route("/user", delete(offboard_user))
+        .route("/user-status", get(check_user_status))
+        .route("/admin/revoke", post(revoke_all_tokens))
+        .route("/admin/revocation-status", get(check_revocation_status))
+        .route("/admin/reprovision", post(reprovision_bridgehead))
+        .route(
+            "/admin/webhooks",
+            post(create_webhook)
+                .get(list_webhooks)
+                .delete(remove_webhook),
+        )
+        .route("/admin/webhook-deliveries", get(list_webhook_deliveries))
+        .route(
+            "/admin/reconciliation",
+            post(start_reconciliation).get(get_reconciliation_report),
+        )
+        .route("/admin/outbox", get(list_outbox))
+        .route("/admin/outbox/:id", delete(abandon_outbox_entry))
+        .route("/admin/site-notifications", get(list_site_notifications))
+        .route(
+            "/admin/templates",
+            post(create_template).get(list_templates),
+        )
+        .route(
+            "/admin/project-templates",
+            put(set_project_template)
+                .get(list_project_templates)
+                .delete(remove_project_template),
+        )
+        .route(
+            "/user-key",
+            put(set_user_key).get(get_user_key).delete(remove_user_key),
+        )
+        .route("/projects/:project_id/tables", get(list_project_tables))
+        .route("/bridgeheads", get(list_bridgeheads))
+        .route(
+            "/bridgeheads/capabilities",
+            get(list_bridgehead_capabilities),
+        )
+        .route("/events", get(stream_events))
+        .route("/event-links", post(create_events_link))
+        .nest("/v2", configure_v2_routes())
         .with_state(pool)
 }
@@ -0,0 +1,461 @@
+use crate::auth::Admin;
+use crate::db::{Db, DbPool};
+use crate::handlers::{
+    check_project_status_request, offboard_user_request, provision_missing_tokens,
+    refresh_token_request, remove_project_and_tokens_request, remove_tokens_request,
+    reprovision_bridgehead_request, revoke_all_tokens_request, send_token_registration_request,
+    validate_token_permissions,
+};
+use crate::models::{
+    BridgeheadIdsBody, BridgeheadIdsQuery, BridgeheadParams, ErrorResponse, JobAcceptedResponse,
+    OffboardingReport, ProjectQueryParams, ProjectStatusResponse, ReprovisionReport,
+    RevocationParams, RevocationProgress, RevocationResponse, ScriptEncryptionQuery,
+    ScriptFormatQuery, ScriptProvisionQuery, ScriptResponse, TokenAvailabilityResponse,
+    TokenCreationBody, TokenParams, TokenStatusResponse, TokensQueryParams, UserQueryParams,
+    UserStatusResponse,
+};
+use crate::opal::OpalResponse;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use tracing::debug;
+
+/// Error of the v2 api, always rendered as an [`ErrorResponse`] JSON body.
+pub struct ApiError(StatusCode, String);
+
+impl ApiError {
+    fn internal(e: impl std::fmt::Display) -> Self {
+        debug!("Unhandled error: {e}");
+        Self(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    }
+
+    fn not_found(message: &str) -> Self {
+        Self(StatusCode::NOT_FOUND, message.to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(ErrorResponse { error: self.1 })).into_response()
+    }
+}
+
+impl From<(StatusCode, String)> for ApiError {
+    fn from((status, message): (StatusCode, String)) -> Self {
+        Self(status, message)
+    }
+}
+
+/// Maps an error reported by a bridgehead to the status code it sent.
+fn opal_error(status_code: i32, error_message: String) -> ApiError {
+    let status =
+        StatusCode::from_u16(status_code as u16).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    ApiError(status, error_message)
+}
+
+fn accepted(message: &str, job_id: Option<String>) -> (StatusCode, Json<JobAcceptedResponse>) {
+    (
+        StatusCode::ACCEPTED,
+        Json(JobAcceptedResponse {
+            message: message.to_string(),
+            job_id,
+        }),
+    )
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v2/projects/{project_id}/users/{user_id}/tokens",
+    params(("project_id" = String, Path), ("user_id" = String, Path)),
+    request_body = TokenCreationBody,
+    responses(
+        (status = 202, body = JobAcceptedResponse),
+        (status = 400, body = ErrorResponse),
+        (status = 500, body = ErrorResponse),
+        (status = 503, body = ErrorResponse),
+    ),
+    tag = "v2"
+)]
+async fn create_tokens(
+    db: Db,
+    Path((project_id, user_id)): Path<(String, String)>,
+    Json(body): Json<TokenCreationBody>,
+) -> Result<impl IntoResponse, ApiError> {
+    let token_params = TokenParams {
+        user_id,
+        project_id,
+        bridgehead_ids: body.bridgehead_ids,
+        permissions: body.permissions,
+    };
+    validate_token_permissions(&token_params).await?;
+    match send_token_registration_request(db, token_params)
+        .await
+        .map_err(ApiError::internal)?
+    {
+        Some(job_id) => Ok(accepted(
+            "Token creation was sent to the bridgeheads",
+            Some(job_id),
+        )),
+        None => Ok(accepted("Token is already available", None)),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v2/projects/{project_id}/users/{user_id}/tokens",
+    params(("project_id" = String, Path), ("user_id" = String, Path), BridgeheadIdsQuery),
+    responses(
+        (status = 200, body = TokenAvailabilityResponse),
+        (status = 500, body = ErrorResponse),
+    ),
+    tag = "v2"
+)]
+async fn check_tokens_available(
+    mut db: Db,
+    Path((project_id, user_id)): Path<(String, String)>,
+    Query(query): Query<BridgeheadIdsQuery>,
+) -> Result<Json<TokenAvailabilityResponse>, ApiError> {
+    let token_params = TokenParams {
+        user_id,
+        project_id,
+        bridgehead_ids: query.bridgehead_ids(),
+        permissions: Default::default(),
+    };
+    let available = db
+        .is_token_available(&token_params)
+        .map_err(ApiError::internal)?;
+    Ok(Json(TokenAvailabilityResponse { available }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v2/projects/{project_id}/users/{user_id}/tokens/{bk}",
+    params(("project_id" = String, Path), ("user_id" = String, Path), ("bk" = String, Path)),
+    responses(
+        (status = 200, body = TokenStatusResponse),
+        (status = 500, body = ErrorResponse),
+    ),
+    tag = "v2"
+)]
+async fn get_token_status(
+    mut db: Db,
+    Path((project_id, user_id, bk)): Path<(String, String, String)>,
+) -> Result<Json<TokenStatusResponse>, ApiError> {
+    let params = TokensQueryParams {
+        user_id,
+        bk,
+        project_id,
+    };
+    Ok(db.check_token_status(params).await?)
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v2/projects/{project_id}/users/{user_id}/tokens/{bk}",
+    params(("project_id" = String, Path), ("user_id" = String, Path), ("bk" = String, Path)),
+    responses(
+        (status = 202, body = JobAcceptedResponse),
+        (status = 404, body = ErrorResponse),
+        (status = 500, body = ErrorResponse),
+    ),
+    tag = "v2"
+)]
+async fn refresh_token(
+    mut db: Db,
+    Path((project_id, user_id, bk)): Path<(String, String, String)>,
+) -> Result<impl IntoResponse, ApiError> {
+    let params = TokensQueryParams {
+        user_id,
+        bk,
+        project_id,
+    };
+    if db
+        .get_token_name(&params)
+        .map_err(ApiError::internal)?
+        .is_none()
+    {
+        return Err(ApiError::not_found("Token not found"));
+    }
+
+    let token_params = TokenParams {
+        user_id: params.user_id,
+        project_id: params.project_id,
+        bridgehead_ids: vec![params.bk],
+        permissions: Default::default(),
+    };
+    let job_id = refresh_token_request(db, token_params)
+        .await
+        .map_err(ApiError::internal)?;
+    Ok(accepted(
+        "Token refresh was sent to the bridgehead",
+        Some(job_id),
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v2/projects/{project_id}/users/{user_id}/tokens/{bk}",
+    params(("project_id" = String, Path), ("user_id" = String, Path), ("bk" = String, Path)),
+    responses(
+        (status = 204, description = "Token was deleted"),
+        (status = 404, body = ErrorResponse),
+        (status = "default", description = "Error reported by the bridgehead", body = ErrorResponse),
+    ),
+    tag = "v2"
+)]
+async fn delete_token(
+    mut db: Db,
+    Path((project_id, user_id, bk)): Path<(String, String, String)>,
+) -> Result<StatusCode, ApiError> {
+    let params = TokensQueryParams {
+        user_id,
+        bk,
+        project_id,
+    };
+    if db
+        .get_token_name(&params)
+        .map_err(ApiError::internal)?
+        .is_none()
+    {
+        return Err(ApiError::not_found("Token not found"));
+    }
+
+    match remove_tokens_request(db, &params)
+        .await
+        .map_err(ApiError::internal)?
+    {
+        OpalResponse::Ok { .. } => Ok(StatusCode::NO_CONTENT),
+        OpalResponse::Err {
+            status_code,
+            error_message,
+        } => Err(opal_error(status_code, error_message)),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v2/projects/{project_id}/users/{user_id}/script",
+    params(("project_id" = String, Path), ("user_id" = String, Path), ScriptFormatQuery, ScriptEncryptionQuery, ScriptProvisionQuery),
+    request_body = BridgeheadIdsBody,
+    responses(
+        (status = 200, body = ScriptResponse),
+        (status = 412, body = ErrorResponse),
+        (status = 500, body = ErrorResponse),
+    ),
+    tag = "v2"
+)]
+async fn generate_script(
+    State(pool): State<DbPool>,
+    Path((project_id, user_id)): Path<(String, String)>,
+    Query(query): Query<ScriptFormatQuery>,
+    Query(encryption): Query<ScriptEncryptionQuery>,
+    Query(provision): Query<ScriptProvisionQuery>,
+    Json(body): Json<BridgeheadIdsBody>,
+) -> Result<Json<ScriptResponse>, ApiError> {
+    let token_params = TokenParams {
+        user_id: user_id.clone(),
+        project_id,
+        bridgehead_ids: body.bridgehead_ids,
+        permissions: Default::default(),
+    };
+    if provision.provision {
+        provision_missing_tokens(&pool, &token_params)
+            .await
+            .map_err(ApiError::internal)?;
+    }
+    // Acquired after provisioning, which takes a connection of its own
+    let mut db = Db::from_pool(&pool).map_err(ApiError::internal)?;
+    let mut script = db
+        .generate_user_script(token_params, query.format)
+        .await
+        .map_err(ApiError::internal)?;
+    if encryption.encrypt {
+        script = db
+            .encrypt_script_for_user(&user_id, &script)
+            .map_err(ApiError::from)?;
+    }
+    Ok(Json(ScriptResponse {
+        script,
+        format: query.format,
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v2/projects/{project_id}/bridgeheads/{bk}",
+    params(("project_id" = String, Path), ("bk" = String, Path)),
+    responses(
+        (status = 200, body = ProjectStatusResponse),
+        (status = 500, body = ErrorResponse),
+    ),
+    tag = "v2"
+)]
+async fn get_project_status(
+    Path((project_id, bk)): Path<(String, String)>,
+) -> Result<Json<ProjectStatusResponse>, ApiError> {
+    Ok(check_project_status_request(ProjectQueryParams { bk, project_id }).await?)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v2/projects/{project_id}/bridgeheads/{bk}",
+    params(("project_id" = String, Path), ("bk" = String, Path)),
+    responses(
+        (status = 204, description = "Project and its tokens were deleted"),
+        (status = "default", description = "Error reported by the bridgehead", body = ErrorResponse),
+    ),
+    tag = "v2"
+)]
+async fn delete_project(
+    db: Db,
+    Path((project_id, bk)): Path<(String, String)>,
+) -> Result<StatusCode, ApiError> {
+    match remove_project_and_tokens_request(db, &ProjectQueryParams { bk, project_id })
+        .await
+        .map_err(ApiError::internal)?
+    {
+        OpalResponse::Ok { .. } => Ok(StatusCode::NO_CONTENT),
+        OpalResponse::Err {
+            status_code,
+            error_message,
+        } => Err(opal_error(status_code, error_message)),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v2/users/{user_id}/tokens",
+    params(("user_id" = String, Path)),
+    responses(
+        (status = 200, body = UserStatusResponse),
+        (status = 500, body = ErrorResponse),
+    ),
+    tag = "v2"
+)]
+async fn get_user_tokens(
+    mut db: Db,
+    Path(user_id): Path<String>,
+) -> Result<Json<UserStatusResponse>, ApiError> {
+    Ok(db
+        .check_user_offboarding_status(UserQueryParams { user_id })
+        .await?)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v2/users/{user_id}/tokens",
+    params(("user_id" = String, Path)),
+    responses(
+        (status = 200, body = OffboardingReport),
+        (status = 401, description = "Missing or wrong admin api key"),
+        (status = 500, body = ErrorResponse),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "v2"
+)]
+async fn offboard_user(
+    _admin: Admin,
+    State(pool): State<DbPool>,
+    Path(user_id): Path<String>,
+) -> Result<Json<OffboardingReport>, ApiError> {
+    offboard_user_request(&pool, &UserQueryParams { user_id })
+        .await
+        .map_err(ApiError::internal)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v2/admin/revocations",
+    request_body = RevocationParams,
+    responses(
+        (status = 202, body = RevocationResponse),
+        (status = 401, description = "Missing or wrong admin api key"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "v2"
+)]
+async fn revoke_all_tokens(
+    _admin: Admin,
+    State(pool): State<DbPool>,
+    Json(params): Json<RevocationParams>,
+) -> Result<impl IntoResponse, ApiError> {
+    let marked = revoke_all_tokens_request(pool, &params).map_err(ApiError::internal)?;
+    Ok((StatusCode::ACCEPTED, Json(RevocationResponse { marked })))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v2/admin/revocations",
+    responses(
+        (status = 200, body = RevocationProgress),
+        (status = 401, description = "Missing or wrong admin api key"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "v2"
+)]
+async fn get_revocation_progress(
+    _admin: Admin,
+    mut db: Db,
+) -> Result<Json<RevocationProgress>, ApiError> {
+    db.get_revocation_progress().map_err(ApiError::internal)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v2/admin/bridgeheads/{bk}/reprovision",
+    params(("bk" = String, Path)),
+    responses(
+        (status = 200, body = ReprovisionReport),
+        (status = 401, description = "Missing or wrong admin api key"),
+    ),
+    security(("admin_api_key" = [])),
+    tag = "v2"
+)]
+async fn reprovision_bridgehead(
+    _admin: Admin,
+    State(pool): State<DbPool>,
+    Path(bk): Path<String>,
+) -> Result<Json<ReprovisionReport>, ApiError> {
+    reprovision_bridgehead_request(&pool, &BridgeheadParams { bk })
+        .await
+        .map_err(ApiError::internal)
+}
+
+/// Resource oriented routes, nested under `/api/v2`.
+pub fn configure_v2_routes() -> Router<DbPool> {
+    Router::new()
+        .route(
+            "/projects/:project_id/users/:user_id/tokens",
+            post(create_tokens).get(check_tokens_available),
+        )
+        .route(
+            "/projects/:project_id/users/:user_id/tokens/:bk",
+            get(get_token_status)
+                .put(refresh_token)
+                .delete(delete_token),
+        )
+        .route(
+            "/projects/:project_id/users/:user_id/script",
+            post(generate_script),
+        )
+        .route(
+            "/projects/:project_id/bridgeheads/:bk",
+            get(get_project_status).delete(delete_project),
+        )
+        .route(
+            "/users/:user_id/tokens",
+            get(get_user_tokens).delete(offboard_user),
+        )
+        .route(
+            "/admin/revocations",
+            post(revoke_all_tokens).get(get_revocation_progress),
+        )
+        .route(
+            "/admin/bridgeheads/:bk/reprovision",
+            post(reprovision_bridgehead),
+        )
+}
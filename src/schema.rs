@@ -1,5 +1,93 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    download_links (id) {
+        id -> Text,
+        job_id -> Text,
+        expires_at -> BigInt,
+        used_at -> Nullable<Text>,
+        created_at -> Text,
+        encrypted -> Bool,
+    }
+}
+
+diesel::table! {
+    outbox (id) {
+        id -> Integer,
+        operation -> Text,
+        bk -> Text,
+        user_id -> Nullable<Text>,
+        project_id -> Nullable<Text>,
+        token_name -> Nullable<Text>,
+        status -> Text,
+        attempts -> Integer,
+        next_attempt_at -> BigInt,
+        last_error -> Nullable<Text>,
+        created_at -> Text,
+        updated_at -> Text,
+        permissions -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    project_templates (project_id, format) {
+        project_id -> Text,
+        format -> Text,
+        template_name -> Text,
+        template_version -> Nullable<Integer>,
+    }
+}
+
+diesel::table! {
+    reconciliation_runs (id) {
+        id -> Integer,
+        started_at -> Text,
+        finished_at -> Text,
+        checked -> Integer,
+        in_sync -> Integer,
+        drifts -> Text,
+    }
+}
+
+diesel::table! {
+    script_jobs (job_id) {
+        job_id -> Text,
+        user_id -> Text,
+        project_id -> Text,
+        bridgehead_ids -> Text,
+        format -> Text,
+        status -> Text,
+        tables -> Text,
+        error -> Nullable<Text>,
+        created_at -> Text,
+        updated_at -> Text,
+        task_settings -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    script_templates (id) {
+        id -> Integer,
+        name -> Text,
+        version -> Integer,
+        content -> Text,
+        created_at -> Text,
+    }
+}
+
+diesel::table! {
+    site_notifications (id) {
+        id -> Integer,
+        task_id -> Text,
+        bk -> Text,
+        kind -> Text,
+        token_name -> Nullable<Text>,
+        project_id -> Nullable<Text>,
+        tokens_updated -> Integer,
+        received_at -> Text,
+    }
+}
+
 diesel::table! {
     tokens (id) {
         id -> Integer,
@@ -11,5 +99,57 @@ diesel::table! {
         bk -> Text,
         user_id -> Text,
         token_created_at -> Text,
+        scopes -> Text,
+        expires_at -> Nullable<Text>,
     }
 }
+
+diesel::table! {
+    user_keys (user_id) {
+        user_id -> Text,
+        public_key -> Text,
+        created_at -> Text,
+    }
+}
+
+diesel::table! {
+    webhook_deliveries (id) {
+        id -> Integer,
+        webhook_id -> Integer,
+        event -> Text,
+        payload -> Text,
+        status -> Text,
+        attempts -> Integer,
+        response_code -> Nullable<Integer>,
+        last_error -> Nullable<Text>,
+        created_at -> Text,
+        updated_at -> Text,
+    }
+}
+
+diesel::table! {
+    webhooks (id) {
+        id -> Integer,
+        url -> Text,
+        secret -> Text,
+        events -> Text,
+        created_at -> Text,
+    }
+}
+
+diesel::joinable!(download_links -> script_jobs (job_id));
+diesel::joinable!(webhook_deliveries -> webhooks (webhook_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    download_links,
+    outbox,
+    project_templates,
+    reconciliation_runs,
+    script_jobs,
+    script_templates,
+    site_notifications,
+    tokens,
+    user_keys,
+    webhook_deliveries,
+    webhooks,
+);
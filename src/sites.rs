@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::fs;
+
+use anyhow::Context;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::config::CONFIG;
+
+static REGISTRY: OnceCell<SiteRegistry> = OnceCell::new();
+
+/// Connection details of a bridgehead's Opal.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Site {
+    /// Beam AppId of the bridgehead
+    pub bk: String,
+    /// Display name, also used as server name in the generated scripts
+    pub name: String,
+    pub opal_url: String,
+    /// DataSHIELD profile to connect with; the server default if omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+}
+
+impl Site {
+    /// Site of an unregistered bridgehead, named after the second segment of its AppId.
+    fn derived(bk: &str) -> Self {
+        let name = bk.split('.').nth(1).unwrap_or(bk);
+        Self {
+            bk: bk.to_string(),
+            name: name.to_string(),
+            opal_url: format!("https://{name}/opal/"),
+            profile: None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SiteRegistry {
+    sites: HashMap<String, Site>,
+}
+
+impl SiteRegistry {
+    fn load(path: &str) -> anyhow::Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read site registry {path}"))?;
+        let sites: Vec<Site> = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse site registry {path}"))?;
+        Ok(Self {
+            sites: sites
+                .into_iter()
+                .map(|site| (site.bk.clone(), site))
+                .collect(),
+        })
+    }
+
+    /// The registered site of the bridgehead or one derived from its AppId.
+    pub fn site(&self, bk: &str) -> Site {
+        self.sites
+            .get(bk)
+            .cloned()
+            .unwrap_or_else(|| Site::derived(bk))
+    }
+
+    pub fn is_registered(&self, bk: &str) -> bool {
+        self.sites.contains_key(bk)
+    }
+
+    /// All registered sites ordered by name.
+    pub fn sites(&self) -> Vec<Site> {
+        let mut sites: Vec<Site> = self.sites.values().cloned().collect();
+        sites.sort_by(|a, b| a.name.cmp(&b.name));
+        sites
+    }
+}
+
+/// Loads the registry from `SITE_REGISTRY_PATH`, a JSON list of sites.
+pub fn init_registry() -> anyhow::Result<()> {
+    let registry = match &CONFIG.site_registry_path {
+        Some(path) => SiteRegistry::load(path)?,
+        None => {
+            info!("No site registry configured, deriving sites from their AppIds");
+            SiteRegistry::default()
+        }
+    };
+    info!("Loaded {} sites", registry.sites.len());
+    if REGISTRY.set(registry).is_err() {
+        warn!("Site registry already loaded");
+    }
+    Ok(())
+}
+
+pub fn registry() -> &'static SiteRegistry {
+    REGISTRY.get_or_init(SiteRegistry::default)
+}
@@ -0,0 +1,59 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+use once_cell::sync::Lazy;
+
+use crate::config::CONFIG;
+use crate::models::BridgeheadTables;
+
+/// Project and bridgehead the tables were fetched for.
+type TablesKey = (String, String);
+
+struct CachedTables {
+    tables: HashSet<String>,
+    fetched_at: String,
+    fetched: Instant,
+}
+
+static TABLES_CACHE: Lazy<Mutex<HashMap<TablesKey, CachedTables>>> = Lazy::new(Default::default);
+
+/// Tables of the project at the bridgehead if they were fetched within `TABLES_CACHE_TTL`.
+pub fn cached_tables(project_id: &str, bk: &str) -> Option<BridgeheadTables> {
+    let ttl = Duration::from_secs(CONFIG.tables_cache_ttl);
+    let mut cache = TABLES_CACHE.lock().unwrap();
+    let key = (project_id.to_string(), bk.to_string());
+    match cache.get(&key) {
+        Some(cached) if cached.fetched.elapsed() < ttl => {
+            let mut tables: Vec<String> = cached.tables.iter().cloned().collect();
+            tables.sort();
+            Some(BridgeheadTables {
+                bk: bk.to_string(),
+                tables,
+                fetched_at: cached.fetched_at.clone(),
+            })
+        }
+        Some(_) => {
+            cache.remove(&key);
+            None
+        }
+        None => None,
+    }
+}
+
+/// Caches the tables the bridgeheads reported for the project.
+pub fn store_tables(project_id: &str, tables_per_bridgehead: &HashMap<String, HashSet<String>>) {
+    let fetched_at = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
+    let mut cache = TABLES_CACHE.lock().unwrap();
+    for (bk, tables) in tables_per_bridgehead {
+        cache.insert(
+            (project_id.to_string(), bk.clone()),
+            CachedTables {
+                tables: tables.clone(),
+                fetched_at: fetched_at.clone(),
+                fetched: Instant::now(),
+            },
+        );
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+
+use beam_lib::{FailureStrategy, TaskRequest};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::config::CONFIG;
+use crate::enums::OpalRequestType;
+use crate::opal::OpalRequest;
+
+const DEFAULT_TTL: &str = "60s";
+/// Tables and capabilities are only listed for the sites that answered in time, so SCRIPT and
+/// CAPABILITIES wait a bounded time.
+const DEFAULT_COLLECT_WAIT_TIME: &str = "30s";
+
+/// How failed tasks are handled by Beam.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskFailureStrategy {
+    Discard,
+    Retry {
+        backoff_millisecs: usize,
+        max_tries: usize,
+    },
+}
+
+impl From<TaskFailureStrategy> for FailureStrategy {
+    fn from(strategy: TaskFailureStrategy) -> Self {
+        match strategy {
+            TaskFailureStrategy::Discard => FailureStrategy::Discard,
+            TaskFailureStrategy::Retry {
+                backoff_millisecs,
+                max_tries,
+            } => FailureStrategy::Retry {
+                backoff_millisecs,
+                max_tries,
+            },
+        }
+    }
+}
+
+/// Effective settings of the Beam tasks of a request type, recorded with the progress and the
+/// script job of every task.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+pub struct TaskSettings {
+    pub request_type: OpalRequestType,
+    pub ttl: String,
+    pub failure_strategy: TaskFailureStrategy,
+    /// Time to wait for the results, Beam's default is used if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wait_time: Option<String>,
+}
+
+/// Settings of a request type overriding the defaults, see `BEAM_TASK_SETTINGS`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TaskSettingsOverride {
+    pub ttl: Option<String>,
+    pub failure_strategy: Option<TaskFailureStrategy>,
+    pub wait_time: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TaskSettingsConfig(HashMap<OpalRequestType, TaskSettingsOverride>);
+
+pub fn parse_task_settings(settings: &str) -> Result<TaskSettingsConfig, String> {
+    let overrides: HashMap<OpalRequestType, TaskSettingsOverride> =
+        serde_json::from_str(settings).map_err(|e| format!("Invalid task settings: {e}"))?;
+    for (request_type, settings) in &overrides {
+        for duration in [&settings.ttl, &settings.wait_time].into_iter().flatten() {
+            if !is_beam_duration(duration) {
+                return Err(format!(
+                    "Invalid duration {duration:?} for {request_type}, expected e.g. \"30s\" or \"500ms\""
+                ));
+            }
+        }
+    }
+    Ok(TaskSettingsConfig(overrides))
+}
+
+/// Beam accepts a number of seconds or milliseconds like `30s` or `500ms`.
+fn is_beam_duration(duration: &str) -> bool {
+    let digits = duration
+        .strip_suffix("ms")
+        .or_else(|| duration.strip_suffix('s'));
+    digits.is_some_and(|d| !d.is_empty() && d.chars().all(|c| c.is_ascii_digit()))
+}
+
+impl TaskSettings {
+    /// Settings configured for the request type, falling back to the defaults.
+    pub fn for_request_type(request_type: OpalRequestType) -> Self {
+        CONFIG.beam_task_settings.settings(request_type)
+    }
+}
+
+impl TaskSettingsConfig {
+    fn settings(&self, request_type: OpalRequestType) -> TaskSettings {
+        let configured = self.0.get(&request_type).cloned().unwrap_or_default();
+        let default_wait_time = match request_type {
+            OpalRequestType::SCRIPT | OpalRequestType::CAPABILITIES => {
+                Some(DEFAULT_COLLECT_WAIT_TIME.to_string())
+            }
+            _ => None,
+        };
+        TaskSettings {
+            request_type,
+            ttl: configured.ttl.unwrap_or_else(|| DEFAULT_TTL.to_string()),
+            failure_strategy: configured
+                .failure_strategy
+                .unwrap_or(TaskFailureStrategy::Discard),
+            wait_time: configured.wait_time.or(default_wait_time),
+        }
+    }
+}
+
+/// Path to wait up to `wait_time` for the results of the task from every addressed bridgehead.
+pub fn results_path(task: &TaskRequest<OpalRequest>, wait_time: Option<&str>) -> String {
+    let mut path = format!("/v1/tasks/{}/results?wait_count={}", task.id, task.to.len());
+    if let Some(wait_time) = wait_time {
+        path.push_str("&wait_time=");
+        path.push_str(wait_time);
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn beam_durations_are_seconds_or_milliseconds() {
+        for duration in ["30s", "500ms", "0s"] {
+            assert!(is_beam_duration(duration), "{duration}");
+        }
+        for duration in ["", "s", "ms", "30", "1.5s", "-1s", "30m", "30 s", "5min"] {
+            assert!(!is_beam_duration(duration), "{duration}");
+        }
+    }
+
+    #[test]
+    fn configured_settings_override_the_defaults() {
+        let config = parse_task_settings(
+            r#"{
+                "CREATE": {"ttl": "120s", "failure_strategy": {"retry": {"backoff_millisecs": 1000, "max_tries": 5}}},
+                "SCRIPT": {"wait_time": "500ms"}
+            }"#,
+        )
+        .unwrap();
+
+        let create = config.settings(OpalRequestType::CREATE);
+        assert_eq!(create.ttl, "120s");
+        assert_eq!(
+            create.failure_strategy,
+            TaskFailureStrategy::Retry {
+                backoff_millisecs: 1000,
+                max_tries: 5
+            }
+        );
+        assert_eq!(create.wait_time, None);
+        let script = config.settings(OpalRequestType::SCRIPT);
+        assert_eq!(script.ttl, DEFAULT_TTL);
+        assert_eq!(script.wait_time.as_deref(), Some("500ms"));
+    }
+
+    #[test]
+    fn unconfigured_request_types_fall_back_to_the_defaults() {
+        let config = parse_task_settings(r#"{"CREATE": {"ttl": "120s"}}"#).unwrap();
+
+        let delete = config.settings(OpalRequestType::DELETE);
+        assert_eq!(delete.ttl, DEFAULT_TTL);
+        assert_eq!(delete.failure_strategy, TaskFailureStrategy::Discard);
+        assert_eq!(delete.wait_time, None);
+        let capabilities = TaskSettingsConfig::default().settings(OpalRequestType::CAPABILITIES);
+        assert_eq!(
+            capabilities.wait_time.as_deref(),
+            Some(DEFAULT_COLLECT_WAIT_TIME)
+        );
+    }
+
+    #[test]
+    fn unknown_request_types_and_fields_are_rejected() {
+        assert!(parse_task_settings(r#"{"RENAME": {"ttl": "30s"}}"#).is_err());
+        assert!(parse_task_settings(r#"{"CREATE": {"timeout": "30s"}}"#).is_err());
+        assert!(parse_task_settings("not json").is_err());
+    }
+
+    #[test]
+    fn invalid_durations_are_rejected() {
+        let error = parse_task_settings(r#"{"CREATE": {"ttl": "1 minute"}}"#).unwrap_err();
+        assert!(error.contains("\"1 minute\""), "{error}");
+        assert!(parse_task_settings(r#"{"SCRIPT": {"wait_time": "30"}}"#).is_err());
+    }
+}
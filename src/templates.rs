@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use minijinja::{Environment, UndefinedBehavior};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::enums::ScriptFormat;
+use crate::models::{ScriptCredentials, SiteCredentials};
+use crate::sites;
+use crate::utils::credentials_csv;
+
+/// Placeholder of the original templates, still replaced with the credentials CSV after rendering.
+const CSV_PLACEHOLDER: &str = "${CSV_CREDENTIALS_CONFIG}";
+
+/// Project and format a template is resolved for.
+type TemplateKey = (String, ScriptFormat);
+
+/// Template assigned to the project per format, `None` for projects using the default template,
+/// which is read on every use so changes to the file apply without a restart.
+static TEMPLATE_CACHE: Lazy<RwLock<HashMap<TemplateKey, Option<Arc<str>>>>> =
+    Lazy::new(Default::default);
+/// Incremented on every invalidation, templates loaded before are not cached.
+static CACHE_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Values available to script templates.
+#[derive(Serialize, Debug)]
+struct ScriptContext<'a> {
+    user_id: &'a str,
+    project_id: &'a str,
+    generated_at: &'a str,
+    sites: Vec<SiteContext<'a>>,
+    /// Credentials of the available sites as CSV, the same as `${CSV_CREDENTIALS_CONFIG}`
+    credentials_csv: String,
+}
+
+#[derive(Serialize, Debug)]
+struct SiteContext<'a> {
+    name: String,
+    bk: &'a str,
+    url: String,
+    profile: Option<String>,
+    table_prefix: Option<&'a str>,
+    tables: &'a [String],
+    tables_available: bool,
+    token: Option<&'a str>,
+    available: bool,
+}
+
+impl<'a> ScriptContext<'a> {
+    fn new(credentials: &'a ScriptCredentials) -> Self {
+        let available = credentials.sites.iter().map(|site| SiteContext {
+            name: site.site_name.clone(),
+            bk: &site.bk,
+            url: site.url.clone(),
+            profile: site.profile.clone(),
+            table_prefix: Some(&site.project_name),
+            tables: &site.tables,
+            tables_available: site.tables_available,
+            token: Some(&site.token),
+            available: true,
+        });
+        let missing = credentials.missing.iter().map(|bridgehead| {
+            let site = sites::registry().site(bridgehead);
+            SiteContext {
+                name: site.name,
+                bk: bridgehead,
+                url: site.opal_url,
+                profile: site.profile,
+                table_prefix: None,
+                tables: &[],
+                tables_available: false,
+                token: None,
+                available: false,
+            }
+        });
+
+        Self {
+            user_id: &credentials.user_id,
+            project_id: &credentials.project_id,
+            generated_at: &credentials.generated_at,
+            sites: available.chain(missing).collect(),
+            credentials_csv: credentials_csv(credentials, true),
+        }
+    }
+}
+
+/// Escapes a value for use inside a single or double quoted R or Python string literal.
+fn escape_string_literal(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\'' => escaped.push_str("\\'"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn environment() -> Environment<'static> {
+    let mut env = Environment::new();
+    // Fail on typos in variable names instead of silently rendering an empty string
+    env.set_undefined_behavior(UndefinedBehavior::Strict);
+    // Values end up in string literals of the scripts, `| safe` writes them unescaped
+    env.set_formatter(|out, _state, value| {
+        if value.is_safe() {
+            write!(out, "{value}")?;
+        } else {
+            out.write_str(&escape_string_literal(&value.to_string()))?;
+        }
+        Ok(())
+    });
+    env.set_trim_blocks(true);
+    env.set_lstrip_blocks(true);
+    env.set_keep_trailing_newline(true);
+    env
+}
+
+/// Renders a Jinja script template with the user, project and per-site credentials. Interpolated
+/// values are escaped for R and Python string literals.
+pub fn render_script(
+    template: &str,
+    credentials: &ScriptCredentials,
+) -> Result<String, minijinja::Error> {
+    let context = ScriptContext::new(credentials);
+    let rendered = environment().render_str(template, &context)?;
+    Ok(rendered.replace(CSV_PLACEHOLDER, &context.credentials_csv))
+}
+
+/// Checks that the template compiles and renders with a sample of available and unavailable sites.
+pub fn validate_template(template: &str) -> Result<(), minijinja::Error> {
+    let sample = ScriptCredentials {
+        user_id: "user@example.com".into(),
+        project_id: "project".into(),
+        generated_at: "01-01-2025 00:00:00".into(),
+        sites: vec![SiteCredentials {
+            bk: "app.site-a.broker".into(),
+            site_name: "site-a".into(),
+            url: "https://site-a/opal/".into(),
+            profile: None,
+            project_name: "project".into(),
+            token: "token".into(),
+            tables: vec!["project.table".into()],
+            tables_available: true,
+        }],
+        missing: vec!["app.site-b.broker".into()],
+        tables_unavailable: Vec::new(),
+    };
+    render_script(template, &sample).map(|_| ())
+}
+
+/// Returns the cached template of the project or loads and caches it, `None` if the project has
+/// no template assigned. A load that overlaps with an invalidation is returned but not cached.
+pub fn cached_template(
+    project_id: &str,
+    format: ScriptFormat,
+    load: impl FnOnce() -> anyhow::Result<Option<String>>,
+) -> anyhow::Result<Option<Arc<str>>> {
+    let key = (project_id.to_string(), format);
+    if let Some(template) = TEMPLATE_CACHE.read().unwrap().get(&key) {
+        return Ok(template.clone());
+    }
+    let generation = CACHE_GENERATION.load(Ordering::SeqCst);
+    let template: Option<Arc<str>> = load()?.map(Into::into);
+    let mut cache = TEMPLATE_CACHE.write().unwrap();
+    if CACHE_GENERATION.load(Ordering::SeqCst) == generation {
+        cache.insert(key, template.clone());
+    }
+    Ok(template)
+}
+
+/// Drops all cached templates, called whenever templates or their assignment change.
+pub fn invalidate_template_cache() {
+    let mut cache = TEMPLATE_CACHE.write().unwrap();
+    CACHE_GENERATION.fetch_add(1, Ordering::SeqCst);
+    cache.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials(user_id: &str) -> ScriptCredentials {
+        ScriptCredentials {
+            user_id: user_id.into(),
+            project_id: "project".into(),
+            generated_at: "01-01-2025 00:00:00".into(),
+            sites: vec![SiteCredentials {
+                bk: "app.site-a.broker".into(),
+                site_name: "site-a".into(),
+                url: "https://site-a/opal/".into(),
+                profile: None,
+                project_name: "project".into(),
+                token: "token".into(),
+                tables: vec!["project.table".into()],
+                tables_available: true,
+            }],
+            missing: vec!["app.site-b.broker".into()],
+            tables_unavailable: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn string_literals_are_escaped() {
+        assert_eq!(escape_string_literal("plain"), "plain");
+        assert_eq!(
+            escape_string_literal(r#"say "hi" and 'bye'"#),
+            r#"say \"hi\" and \'bye\'"#
+        );
+        assert_eq!(escape_string_literal(r"C:\dir\"), r"C:\\dir\\");
+        assert_eq!(escape_string_literal("a\nb\r\tc"), r"a\nb\r\tc");
+    }
+
+    #[test]
+    fn interpolated_values_are_escaped() {
+        let script = render_script(
+            r#"user <- "{{ user_id }}""#,
+            &credentials("evil\"); system(\"rm -rf /\n"),
+        )
+        .unwrap();
+
+        assert_eq!(script, r#"user <- "evil\"); system(\"rm -rf /\n""#);
+    }
+
+    #[test]
+    fn safe_values_are_written_unescaped() {
+        let script =
+            render_script("{{ user_id | safe }}", &credentials("a \"quoted\" user")).unwrap();
+
+        assert_eq!(script, "a \"quoted\" user");
+    }
+
+    #[test]
+    fn sites_are_available_to_templates() {
+        let script = render_script(
+            "{% for site in sites %}{{ site.name }}{% if not site.available %} (missing){% endif %};{% endfor %}",
+            &credentials("user"),
+        )
+        .unwrap();
+
+        assert_eq!(script, "site-a;site-b (missing);");
+    }
+
+    #[test]
+    fn undefined_variables_fail_the_rendering() {
+        assert!(render_script("{{ user }}", &credentials("user")).is_err());
+        assert!(render_script("{{ sites[0].nmae }}", &credentials("user")).is_err());
+        assert!(validate_template("{{ project }}").is_err());
+        assert!(validate_template("{{ project_id }}").is_ok());
+    }
+
+    #[test]
+    fn loads_overlapping_an_invalidation_are_not_cached() {
+        let loaded = cached_template("cache-overlap", ScriptFormat::R, || {
+            invalidate_template_cache();
+            Ok(Some("stale".to_string()))
+        })
+        .unwrap();
+        assert_eq!(loaded.as_deref(), Some("stale"));
+
+        let loaded = cached_template("cache-overlap", ScriptFormat::R, || {
+            Ok(Some("fresh".to_string()))
+        })
+        .unwrap();
+        assert_eq!(loaded.as_deref(), Some("fresh"));
+    }
+
+    #[test]
+    fn failed_loads_are_not_cached() {
+        let failed = cached_template("cache-failure", ScriptFormat::Python, || {
+            anyhow::bail!("database unavailable")
+        });
+        assert!(failed.is_err());
+
+        let loaded = cached_template("cache-failure", ScriptFormat::Python, || Ok(None)).unwrap();
+        assert!(loaded.is_none());
+    }
+
+    #[test]
+    fn legacy_placeholder_is_replaced_after_rendering() {
+        let credentials = credentials("user");
+        let script =
+            render_script("login <- \"${CSV_CREDENTIALS_CONFIG}\"\n", &credentials).unwrap();
+
+        assert_eq!(
+            script,
+            format!("login <- \"{}\"\n", credentials_csv(&credentials, true))
+        );
+    }
+}
@@ -1,10 +1,14 @@
-use std::{fs, io};
-use std::collections::{HashMap, HashSet};
 use crate::config::CONFIG;
+use crate::enums::ScriptFormat;
+use crate::models::ScriptCredentials;
 use aes::Aes256;
 use base64::{engine::general_purpose::STANDARD, Engine};
 use cipher::{KeyIvInit, StreamCipher};
 use ctr::Ctr128BE;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
 
 fn adjust_key(key: &str) -> [u8; 32] {
     let bytes = key.as_bytes();
@@ -25,45 +29,122 @@ pub fn encrypt_data(data: &[u8], nonce: &[u8]) -> Vec<u8> {
     encrypted
 }
 
+/// Hex encoded HMAC-SHA256 of the payload.
+pub fn hmac_sha256_hex(secret: &str, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(payload.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Checks a hex encoded HMAC-SHA256 of the payload in constant time.
+pub fn verify_hmac_sha256_hex(secret: &str, payload: &str, signature: &str) -> bool {
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(payload.as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Encrypts the data to an age X25519 public key, armored so it can be sent as text.
+pub fn encrypt_for_public_key(public_key: &str, data: &str) -> anyhow::Result<String> {
+    let recipient = parse_public_key(public_key)?;
+    Ok(age::encrypt_and_armor(&recipient, data.as_bytes())?)
+}
+
+pub fn parse_public_key(public_key: &str) -> anyhow::Result<age::x25519::Recipient> {
+    public_key
+        .trim()
+        .parse::<age::x25519::Recipient>()
+        .map_err(|e| anyhow::anyhow!("Invalid age public key: {e}"))
+}
+
 pub fn decrypt_data(data: String, nonce: &[u8]) -> String {
     let toke_decode = STANDARD.decode(data).unwrap();
     let decrypted_token = encrypt_data(&toke_decode, nonce);
     String::from_utf8(decrypted_token).unwrap()
 }
 
-pub fn generate_r_script(script_config: String) -> Result<String, io::Error> {
-    // Read the auth script template from the file
-    let template_path = &CONFIG.auth_script_template_path;
-    let template_content = fs::read_to_string(template_path)?;
+/// Used if no `AUTH_PYTHON_SCRIPT_TEMPLATE_PATH` is configured
+const DEFAULT_PYTHON_TEMPLATE: &str = r#"# DataSHIELD logins of user {{ user_id }} for project {{ project_id }}, generated at {{ generated_at }}
+from datashield import DSLoginBuilder, DSSession
+
+builder = DSLoginBuilder()
+{% for site in sites %}
+{% if site.available %}
+{% if site.tables_available %}
+# Tables of {{ site.name }}: {{ site.tables | join(", ") }}
+{% else %}
+# WARNING: Tables of bridgehead {{ site.bk }} could not be fetched, assuming the prefix "{{ site.table_prefix }}"
+{% endif %}
+builder.add("{{ site.name }}", "{{ site.url }}", token="{{ site.token }}"{% if site.profile %}, profile="{{ site.profile }}"{% endif %})
+{% else %}
+# WARNING: No token available for bridgehead {{ site.bk }}
+{% endif %}
+{% endfor %}
 
-    // Replace the placeholder with the credentials CSV
-    let processed_content = template_content.replace("${CSV_CREDENTIALS_CONFIG}", script_config.as_str());
+session = DSSession(builder.build())
+session.open()
+"#;
 
-    // Return the processed content
-    Ok(processed_content)
+/// Credentials as CSV, optionally with a comment line for every bridgehead without a token.
+pub fn credentials_csv(credentials: &ScriptCredentials, with_missing: bool) -> String {
+    let mut lines = vec!["\"SiteName\",\"URL\",\"ProjectName\",\"Token\"".to_string()];
+    lines.extend(credentials.sites.iter().map(|site| {
+        format!(
+            "\"{}\",\"{}\",\"{}\",\"{}\"",
+            site.site_name, site.url, site.project_name, site.token
+        )
+    }));
+    if with_missing {
+        lines.extend(
+            credentials.missing.iter().map(|bridgehead| {
+                format!("\n # Token not available for bridgehead '{}'", bridgehead)
+            }),
+        );
+        lines.extend(credentials.tables_unavailable.iter().map(|bridgehead| {
+            format!("\n # Tables not available for bridgehead '{}', the project id is used as table prefix", bridgehead)
+        }));
+    }
+    lines.join("\n")
 }
 
-pub fn fetch_tables_prefix(
-    bridgehead_tables: &HashMap<String, HashSet<String>>, bridgehead: &str, default: &str) -> String {
-    if let Some(set) = bridgehead_tables.get(bridgehead) {
-        let mut prefix: Option<String> = None;
-
-        for value in set {
-            if let Some((current_prefix, _)) = value.split_once('.') {
-                match &prefix {
-                    Some(existing_prefix) if existing_prefix != current_prefix => {
-                        return default.to_string();
-                    }
-                    None => {
-                        prefix = Some(current_prefix.to_string());
-                    }
-                    _ => {}
-                }
-            }
+/// Template configured for the format, read from `AUTH_SCRIPT_TEMPLATE_PATH` for R scripts and
+/// `AUTH_PYTHON_SCRIPT_TEMPLATE_PATH` or the built-in template for Python scripts.
+pub fn default_script_template(format: ScriptFormat) -> anyhow::Result<String> {
+    match (format, &CONFIG.auth_python_script_template_path) {
+        (ScriptFormat::R, _) => Ok(fs::read_to_string(&CONFIG.auth_script_template_path)?),
+        (ScriptFormat::Python, Some(template_path)) => Ok(fs::read_to_string(template_path)?),
+        (ScriptFormat::Python, None) => Ok(DEFAULT_PYTHON_TEMPLATE.to_string()),
+        (ScriptFormat::Csv | ScriptFormat::Json, _) => {
+            anyhow::bail!("No template for the {} format", format.as_str())
         }
-        prefix.unwrap_or_else(|| default.to_string())
-    } else {
-        default.to_string()
     }
 }
 
+/// Tables the bridgehead reported for the project grouped by their prefix, or `None` if the
+/// bridgehead did not report any. Tables without prefix are grouped under the default.
+pub fn group_tables_by_prefix(
+    bridgehead_tables: &HashMap<String, HashSet<String>>,
+    bridgehead: &str,
+    default: &str,
+) -> Option<BTreeMap<String, Vec<String>>> {
+    let set = bridgehead_tables.get(bridgehead)?;
+    let mut prefixes: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for value in set {
+        let prefix = value.split_once('.').map_or(default, |(prefix, _)| prefix);
+        prefixes
+            .entry(prefix.to_string())
+            .or_default()
+            .push(value.clone());
+    }
+    for tables in prefixes.values_mut() {
+        tables.sort();
+    }
+    if prefixes.is_empty() {
+        prefixes.insert(default.to_string(), Vec::new());
+    }
+    Some(prefixes)
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use chrono::Local;
+use once_cell::sync::{Lazy, OnceCell};
+use reqwest::header;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tracing::{debug, info, warn};
+
+use crate::config::CONFIG;
+use crate::db::{Db, DbPool};
+use crate::enums::WebhookDeliveryStatus;
+use crate::models::{NewWebhookDelivery, TokenEvent, Webhook};
+use crate::utils::hmac_sha256_hex;
+
+pub const SIGNATURE_HEADER: &str = "X-Token-Manager-Signature";
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+
+static EVENT_SENDER: OnceCell<UnboundedSender<TokenEvent>> = OnceCell::new();
+
+static HTTP_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("Failed to build webhook http client")
+});
+
+/// Queues a token lifecycle event for delivery to all subscribed webhooks.
+pub fn notify(event: TokenEvent) {
+    match EVENT_SENDER.get() {
+        Some(sender) => {
+            if sender.send(event).is_err() {
+                warn!("Webhook dispatcher stopped, dropping event");
+            }
+        }
+        None => debug!("Webhook dispatcher not started, dropping event {event:?}"),
+    }
+}
+
+/// Starts the background task that fans out queued events to the registered webhooks. Deliveries
+/// still pending from before a restart are resumed first.
+pub fn start_dispatcher(pool: DbPool) {
+    let (sender, mut receiver) = mpsc::unbounded_channel::<TokenEvent>();
+    if EVENT_SENDER.set(sender).is_err() {
+        warn!("Webhook dispatcher already started");
+        return;
+    }
+
+    tokio::task::spawn(async move {
+        let requeue_pool = pool.clone();
+        match tokio::task::spawn_blocking(move || pending_deliveries(&requeue_pool)).await {
+            Ok(Ok(deliveries)) => {
+                if !deliveries.is_empty() {
+                    info!("Resuming {} pending webhook deliveries", deliveries.len());
+                }
+                for delivery in deliveries {
+                    tokio::task::spawn(deliver(pool.clone(), delivery));
+                }
+            }
+            Ok(Err(e)) => warn!("Failed to resume pending webhook deliveries: {e}"),
+            Err(e) => warn!("Failed to resume pending webhook deliveries: {e}"),
+        }
+
+        while let Some(event) = receiver.recv().await {
+            let dispatch_pool = pool.clone();
+            let dispatched = event.clone();
+            match tokio::task::spawn_blocking(move || dispatch(&dispatch_pool, &dispatched)).await {
+                Ok(Ok(deliveries)) => {
+                    for delivery in deliveries {
+                        tokio::task::spawn(deliver(pool.clone(), delivery));
+                    }
+                }
+                Ok(Err(e)) => warn!("Failed to dispatch webhook event {event:?}: {e}"),
+                Err(e) => warn!("Failed to dispatch webhook event {event:?}: {e}"),
+            }
+        }
+    });
+}
+
+/// Delivery of a payload to a webhook, `attempts` made so far.
+struct Delivery {
+    webhook: Webhook,
+    id: i32,
+    payload: String,
+    attempts: u32,
+}
+
+fn dispatch(pool: &DbPool, event: &TokenEvent) -> anyhow::Result<Vec<Delivery>> {
+    let mut db = Db::from_pool(pool)?;
+    let payload = serde_json::to_string(event)?;
+    let now = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
+
+    let mut deliveries = Vec::new();
+    for webhook in db.get_webhooks()? {
+        if !webhook.is_subscribed(event.event) {
+            continue;
+        }
+        let id = db.save_webhook_delivery_db(NewWebhookDelivery {
+            webhook_id: webhook.id,
+            event: event.event.as_str(),
+            payload: &payload,
+            status: WebhookDeliveryStatus::PENDING,
+            attempts: 0,
+            created_at: &now,
+            updated_at: &now,
+        })?;
+        deliveries.push(Delivery {
+            webhook,
+            id,
+            payload: payload.clone(),
+            attempts: 0,
+        });
+    }
+    Ok(deliveries)
+}
+
+fn pending_deliveries(pool: &DbPool) -> anyhow::Result<Vec<Delivery>> {
+    let mut db = Db::from_pool(pool)?;
+    let webhooks: HashMap<i32, Webhook> = db
+        .get_webhooks()?
+        .into_iter()
+        .map(|webhook| (webhook.id, webhook))
+        .collect();
+
+    let mut deliveries = Vec::new();
+    for delivery in db.get_pending_webhook_deliveries()? {
+        let Some(webhook) = webhooks.get(&delivery.webhook_id) else {
+            continue;
+        };
+        deliveries.push(Delivery {
+            webhook: webhook.clone(),
+            id: delivery.id,
+            payload: delivery.payload,
+            attempts: delivery.attempts.max(0) as u32,
+        });
+    }
+    Ok(deliveries)
+}
+
+/// Posts the payload to the webhook, retrying with exponential backoff until it is accepted
+/// or `WEBHOOK_MAX_ATTEMPTS` is reached. Every attempt is recorded in the delivery log.
+async fn deliver(pool: DbPool, delivery: Delivery) {
+    let Delivery {
+        webhook,
+        id: delivery_id,
+        payload,
+        attempts,
+    } = delivery;
+    let signature = hmac_sha256_hex(&webhook.secret, &payload);
+    let mut backoff = INITIAL_BACKOFF * 2u32.saturating_pow(attempts.min(16));
+
+    for attempt in (attempts + 1)..=CONFIG.webhook_max_attempts.max(attempts + 1) {
+        let result = HTTP_CLIENT
+            .post(&webhook.url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, format!("sha256={signature}"))
+            .body(payload.clone())
+            .send()
+            .await;
+
+        let (response_code, error) = match result {
+            Ok(res) if res.status().is_success() => (Some(res.status().as_u16() as i32), None),
+            Ok(res) => (
+                Some(res.status().as_u16() as i32),
+                Some(format!("Webhook responded with {}", res.status())),
+            ),
+            Err(e) => (None, Some(e.to_string())),
+        };
+
+        let status = match &error {
+            None => WebhookDeliveryStatus::DELIVERED,
+            Some(_) if attempt >= CONFIG.webhook_max_attempts => WebhookDeliveryStatus::FAILED,
+            Some(_) => WebhookDeliveryStatus::PENDING,
+        };
+
+        let record_pool = pool.clone();
+        let record_error = error.clone();
+        let recorded = tokio::task::spawn_blocking(move || {
+            let now = Local::now().format("%d-%m-%Y %H:%M:%S").to_string();
+            Db::from_pool(&record_pool).map(|mut db| {
+                db.update_webhook_delivery_db(
+                    delivery_id,
+                    status,
+                    attempt as i32,
+                    response_code,
+                    record_error.as_deref(),
+                    &now,
+                )
+            })
+        })
+        .await;
+        match recorded {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Could not record webhook delivery {delivery_id}: {e}"),
+            Err(e) => warn!("Could not record webhook delivery {delivery_id}: {e}"),
+        }
+
+        match error {
+            None => {
+                info!("Delivered webhook {} to {}", delivery_id, webhook.url);
+                return;
+            }
+            Some(e) => {
+                warn!(
+                    "Attempt {attempt} to deliver webhook {delivery_id} to {} failed: {e}",
+                    webhook.url
+                );
+                if attempt < CONFIG.webhook_max_attempts {
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+            }
+        }
+    }
+}